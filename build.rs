@@ -0,0 +1,61 @@
+use std::{
+    env, fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Embeds the git commit and build time for /version. CI passes both through
+// BUILD_COMMIT and BUILD_TIMESTAMP; local builds fall back to git and the clock.
+fn main() {
+    println!("cargo:rerun-if-env-changed=BUILD_COMMIT");
+    println!("cargo:rerun-if-env-changed=BUILD_TIMESTAMP");
+    watch_git_head();
+
+    let commit = non_empty_env("BUILD_COMMIT")
+        .or_else(git_commit)
+        .unwrap_or_else(|| String::from("unknown"));
+    let timestamp = non_empty_env("BUILD_TIMESTAMP").unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs().to_string())
+            .unwrap_or_default()
+    });
+
+    println!("cargo:rustc-env=WIBOT_BUILD_COMMIT={}", commit);
+    println!("cargo:rustc-env=WIBOT_BUILD_TIMESTAMP={}", timestamp);
+}
+
+// HEAD usually only names the branch, so a commit changes the branch's ref
+// file, or packed-refs once git has packed it, not HEAD itself.
+fn watch_git_head() {
+    let git = Path::new(".git");
+    let head = git.join("HEAD");
+    let Ok(contents) = fs::read_to_string(&head) else {
+        return;
+    };
+    let mut watched = vec![head, git.join("packed-refs")];
+    if let Some(reference) = contents.trim().strip_prefix("ref: ") {
+        watched.push(git.join(reference));
+    }
+    // Cargo reruns on every build for a missing path, so only watch what exists
+    for path in watched.iter().filter(|path| path.exists()) {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+}
+
+fn non_empty_env(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let commit = String::from_utf8(output.stdout).ok()?;
+    Some(commit.trim().to_string()).filter(|commit| !commit.is_empty())
+}
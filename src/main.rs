@@ -1,21 +1,41 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, ParseError, Utc};
+use regex::Regex;
 use dotenv::dotenv;
 use reqwest::Client;
+use serde::Deserialize;
 use serde_json::{json, Value};
 use sqlx::{sqlite::SqlitePool, Row};
-use std::{env, fs, path::Path, sync::Arc};
-use teloxide::RequestError;
-use teloxide::{prelude::*, types::ParseMode, utils::command::BotCommands};
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    env, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use teloxide::{ApiError, RequestError};
+use teloxide::{
+    prelude::*,
+    types::{
+        ChatMemberKind, InlineKeyboardButton, InlineKeyboardMarkup, InlineQueryResult, InlineQueryResultArticle,
+        InlineQueryResultsButton, InlineQueryResultsButtonKind, InputFile, InputMessageContent,
+        InputMessageContentText, Me, MessageId, ParseMode, Recipient,
+    },
+    utils::command::BotCommands,
+};
 use thiserror::Error;
+use tokio::sync::Semaphore;
 use tokio::time::{sleep, Duration};
-use teloxide::types::ChatMemberKind;
 
 #[derive(Error, Debug)]
 enum BotError {
     #[error("A task with this name already exists")]
     TaskExists,
 
+    #[error("Task name is held by another chat's deleted task")]
+    TaskNameReserved,
+
     #[error("Task not found")]
     TaskNotFound,
 
@@ -39,6 +59,30 @@ enum BotError {
 
     #[error("Permission denied")]
     PermissionDenied,
+
+    #[error("Chat administrator rights required")]
+    AdminRequired,
+
+    #[error("Rate limit exceeded, retry in {0:?}")]
+    RateLimited(Duration),
+
+    #[error("Statistics are disabled")]
+    StatisticsDisabled,
+
+    #[error("Run index out of range, {0} runs stored")]
+    RunIndexOutOfRange(i64),
+
+    #[error("X.AI did not return valid JSON")]
+    InvalidJsonAnswer,
+
+    #[error("Command cooldown active, retry in {0:?}")]
+    CooldownActive(Duration),
+
+    #[error("Channel is missing or the bot cannot post there")]
+    ChannelUnavailable,
+
+    #[error("Only read-only commands can be run as another chat")]
+    NotReadOnly,
 }
 
 impl BotError {
@@ -47,6 +91,9 @@ impl BotError {
             BotError::TaskExists => {
                 "❌ A task with this name already exists\\. Please choose a different name\\."
             }
+            BotError::TaskNameReserved => {
+                "❌ This name belongs to a recently deleted task in another chat and is reserved until its grace period ends\\. Please choose a different name\\."
+            }
             BotError::TaskNotFound => {
                 "❌ Task not found\\. Use /list to see all available tasks\\."
             }
@@ -54,18 +101,14 @@ impl BotError {
                 "❌ Unable to reach X\\.AI service\\. Please try again later\\."
             }
             BotError::DatabaseError(e) => {
-                if let sqlx::Error::Database(db_err) = e {
-                    if db_err.code() == Some("1555".into())
-                        || db_err.message().contains("UNIQUE constraint failed")
-                    {
-                        return "❌ A task with this name already exists\\. Please choose a different name\\.".to_string();
-                    }
+                if is_unique_violation(e) {
+                    return "❌ A task with this name already exists\\. Please choose a different name\\.".to_string();
                 }
                 "❌ Unable to process your request\\. Please try again later\\."
             }
             BotError::TelegramError(_) => "❌ Unable to send message\\. Please try again later\\.",
             BotError::InvalidParameters => {
-                "❌ Invalid parameters provided\\. Please check the command format and try again\\."
+                "❌ Invalid parameters provided\\. Please check the command format and try again, or see /help for usage\\."
             }
             BotError::DateParseError(_) => {
                 "❌ Error processing date information\\. Please try again later\\."
@@ -74,43 +117,953 @@ impl BotError {
             BotError::PermissionDenied => {
                 "❌ This command is restricted to the bot owner\\."
             },
+            BotError::AdminRequired => {
+                "❌ Only chat administrators can use this command\\."
+            },
+            BotError::StatisticsDisabled => {
+                "📊 Usage statistics are disabled on this bot\\."
+            },
+            BotError::ChannelUnavailable => {
+                "❌ I can't post to that channel\\. Make me an administrator allowed to post messages, then use the channel's @username or id\\."
+            },
+            BotError::NotReadOnly => {
+                "❌ /as only runs read\\-only commands: /list, /settings, /welcome, /cost, /overdue and /chatstats\\."
+            },
+            BotError::InvalidJsonAnswer => {
+                "❌ X\\.AI didn't return valid JSON, even when asked again\\. Try rephrasing the question\\."
+            },
+            BotError::RunIndexOutOfRange(0) => {
+                "ℹ️ This task has no stored runs yet\\."
+            },
+            BotError::RunIndexOutOfRange(available) => {
+                return format!(
+                    "❌ Run index out of range\\. This task has {} stored run\\(s\\), use an index from 1 to {}\\.",
+                    available, available
+                );
+            },
+            BotError::CooldownActive(retry_in) => {
+                return format!(
+                    "🐢 Slow down\\! You can send another command in {} seconds\\.",
+                    retry_in.as_secs_f64().ceil().max(1.0)
+                );
+            },
+            BotError::RateLimited(retry_in) => {
+                return format!(
+                    "⏳ You've reached the request limit\\. Please try again in {} seconds\\.",
+                    retry_in.as_secs().max(1)
+                );
+            },
         };
         message.to_string()
     }
 }
 
+fn is_unique_violation(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(db_err) => {
+            db_err.code() == Some("1555".into())
+                || db_err.message().contains("UNIQUE constraint failed")
+        }
+        _ => false,
+    }
+}
+
 #[derive(BotCommands, Clone, Debug)]
 #[command(rename_rule = "lowercase", description = "Available commands:")]
 enum Command {
+    #[command(description = "Show the chat's welcome message")]
+    Start,
     #[command(description = "Display this help message")]
     Help,
     #[command(description = "Show your Telegram ID")]
     MyId,
+    #[command(description = "Show the bot's version and build")]
+    Version,
     #[command(description = "Create a new X.AI query task: /create <task_name> <interval_minutes> <question>")]
     Create(String),
     #[command(description = "List all tasks")]
     List,
-    #[command(description = "Delete a task")]
+    #[command(description = "Delete one or more tasks: /delete <task_name> [task_name...]")]
     Delete(String),
+    #[command(
+        rename = "settemplate",
+        description = "Set text shown before or after a task's answers: /settemplate <task_name> <prefix|suffix> [text]"
+    )]
+    SetTemplate(String),
+    #[command(description = "Restore a recently deleted task")]
+    Undelete(String),
+    #[command(description = "Run a task less often by multiplying its interval: /slower <name>")]
+    Slower(String),
+    #[command(description = "Run a task more often by dividing its interval: /faster <name>")]
+    Faster(String),
+    #[command(description = "Set when a task runs next, without running it now: /reschedule <name> <now|+30m|+2h|+1d>")]
+    Reschedule(String),
+    #[command(description = "Pin each new answer of a task in the chat: /pin <name> [on|off]")]
+    Pin(String),
+    #[command(description = "Change a task's settings: /edit <name> --maxlen=<tokens, 0 for no limit>")]
+    Edit(String),
+    #[command(description = "Pause a task, or every task with a tag: /pause <name> | --tag=<tag>")]
+    Pause(String),
+    #[command(description = "Resume a paused task, or every task with a tag: /resume <name> | --tag=<tag>")]
+    Resume(String),
+    #[command(description = "Resend a stored answer without asking X.AI again: /replay <task_name> [index]")]
+    Replay(String),
+    #[command(description = "Show what changed between a task's last two answers")]
+    Diff(String),
+    #[command(description = "Run every task of a group now and report them together")]
+    RunGroup(String),
+    #[command(description = "Estimate the monthly X.AI cost of this chat's tasks (owner: /cost all)")]
+    Cost(String),
+    #[command(description = "List this chat's tasks that missed their schedule, most overdue first (owner: /overdue all)")]
+    Overdue(String),
+    #[command(description = "Set this chat's welcome message (chat admins only)")]
+    SetWelcome(String),
+    #[command(description = "Show this chat's welcome message")]
+    Welcome,
+    #[command(description = "Set this chat's default answer temperature (chat admins only): /settemp <0-2|default>")]
+    SetTemp(String),
+    #[command(description = "Set the language this chat's answers are written in (chat admins only): /setlang <code|default>")]
+    SetLang(String),
+    #[command(description = "Show this chat's settings")]
+    Settings,
     #[command(description = "Ask X.AI a one-time question")]
     Ask(String),
     #[command(description = "Get your usage statistics")]
     Stats,
     #[command(description = "Get overall bot usage statistics (bot owner only)")]
     BotStats,
+    #[command(description = "Get this chat's usage statistics (bot owner, or chat admins if allowed)")]
+    ChatStats,
+    #[command(description = "Show the scheduler's view of every task (bot owner only)")]
+    Queue,
+    #[command(description = "Inspect or reset a user's rate limit (bot owner only): /ratelimit <user_id> [reset]")]
+    RateLimit(String),
+    #[command(
+        rename = "purge_inactive",
+        description = "Report or delete tasks of inactive chats (bot owner only): /purge_inactive <days> [confirm]"
+    )]
+    PurgeInactive(String),
+    #[command(description = "Search logged interactions (bot owner only): /search [--from=YYYY-MM-DD] [--to=YYYY-MM-DD] <keyword>")]
+    Search(String),
+    #[command(description = "Download your logged interactions in this chat as a text file; the bot owner can pass a chat id: /export [chat_id]")]
+    Export(String),
+    #[command(description = "Set the minimum seconds between a user's commands in this chat (chat admins only): /setcooldown <seconds|default>")]
+    SetCooldown(String),
+    #[command(description = "Set your own answer defaults in every chat: /setpref <model|temp|style> <value|default>")]
+    SetPref(String),
+    #[command(description = "Strip filler such as \"Sure, here's...\" from this chat's answers (chat admins only): /sanitize <on|off>")]
+    Sanitize(String),
+    #[command(description = "Show the effective configuration (bot owner only)")]
+    Config,
+    #[command(description = "Show the X.AI request an /ask would send, without sending it (bot owner only): /payload [--flags] <question>")]
+    Payload(String),
+    #[command(description = "Show recent X.AI latency (bot owner only)")]
+    Status,
+    #[command(description = "Show answer ratings per command, or per model with /ratings model (bot owner only)")]
+    Ratings(String),
+    #[command(
+        rename = "alltasks",
+        description = "List the tasks of every chat (bot owner only): /alltasks [page]"
+    )]
+    AllTasks(String),
+    #[command(description = "Run a read-only command as another chat (bot owner only): /as <chat_id> <command>")]
+    As(String),
+    #[command(description = "Send a sample answer using every formatting feature, to check rendering (bot owner only)")]
+    RenderTest,
+}
+
+impl Command {
+    /// Variant name without arguments, used to group statistics.
+    fn name(&self) -> String {
+        let debug = format!("{:?}", self);
+        match debug.split_once('(') {
+            Some((name, _)) => name.to_string(),
+            None => debug,
+        }
+    }
+
+    fn args(&self) -> Option<&str> {
+        match self {
+            Command::Create(args)
+            | Command::Delete(args)
+            | Command::Pin(args)
+            | Command::Edit(args)
+            | Command::Slower(args)
+            | Command::Faster(args)
+            | Command::Reschedule(args)
+            | Command::Pause(args)
+            | Command::Resume(args)
+            | Command::Undelete(args)
+            | Command::SetTemplate(args)
+            | Command::Diff(args)
+            | Command::Replay(args)
+            | Command::RunGroup(args)
+            | Command::Cost(args)
+            | Command::Overdue(args)
+            | Command::SetWelcome(args)
+            | Command::SetTemp(args)
+            | Command::SetLang(args)
+            | Command::Sanitize(args)
+            | Command::SetPref(args)
+            | Command::SetCooldown(args)
+            | Command::Payload(args)
+            | Command::Ask(args)
+            | Command::RateLimit(args)
+            | Command::PurgeInactive(args)
+            | Command::AllTasks(args)
+            | Command::Ratings(args)
+            | Command::Export(args)
+            | Command::As(args)
+            | Command::Search(args) => Some(args),
+            Command::Start
+            | Command::Help
+            | Command::MyId
+            | Command::Version
+            | Command::List
+            | Command::Welcome
+            | Command::Settings
+            | Command::Stats
+            | Command::BotStats
+            | Command::ChatStats
+            | Command::Config
+            | Command::RenderTest
+            | Command::Status
+            | Command::Queue => None,
+        }
+    }
 }
 
 struct AppState {
     pool: SqlitePool,
+    /// Read-only pool for statistics and log history, the primary pool unless
+    /// `DATABASE_REPLICA_URL` is set. Nothing is ever written through it.
+    read_pool: SqlitePool,
     http_client: Client,
     xai_token: String,
     owner_id: i64,  // Add this field
+    config: Config,
+    chat_cache: Mutex<ChatCache>,
+    response_cache: Mutex<ResponseCache>,
+    rate_limiter: Mutex<RateLimiter>,
+    xai_latency: Mutex<LatencyTracker>,
+    cooldowns: Mutex<CommandCooldowns>,
+    /// Id of the newest inline query per user, older ones are left unanswered.
+    inline_queries: Mutex<HashMap<i64, String>>,
+    repeated_errors: Mutex<RepeatedErrors>,
 }
 
 type State = Arc<AppState>;
 
-async fn is_bot_creator(bot: &Bot, user_id: i64, _chat_id: i64, owner_id: i64) -> Result<bool, RequestError> {
-    Ok(user_id == owner_id)
+impl AppState {
+    fn new(pool: SqlitePool, xai_token: String, owner_id: i64, config: Config) -> Self {
+        AppState {
+            read_pool: pool.clone(),
+            pool,
+            http_client: Client::new(),
+            xai_token,
+            owner_id,
+            chat_cache: Mutex::new(ChatCache::new(config.chat_cache_ttl)),
+            response_cache: Mutex::new(ResponseCache::new(
+                config.xai_cache_ttl,
+                config.xai_cache_max_entries,
+            )),
+            rate_limiter: Mutex::new(RateLimiter::new(
+                config.rate_limit_max_requests,
+                config.rate_limit_window,
+            )),
+            xai_latency: Mutex::new(LatencyTracker::new(config.xai_latency_alpha)),
+            cooldowns: Mutex::new(CommandCooldowns::default()),
+            inline_queries: Mutex::new(HashMap::new()),
+            repeated_errors: Mutex::new(RepeatedErrors::new(REPEATED_ERROR_WINDOW)),
+            config,
+        }
+    }
+
+    fn with_read_pool(self, read_pool: SqlitePool) -> Self {
+        AppState { read_pool, ..self }
+    }
+}
+
+/// Connects to the read-only replica given by `DATABASE_REPLICA_URL`, if any.
+async fn connect_read_replica() -> Result<Option<SqlitePool>> {
+    let Some(url) = env_opt::<String>("DATABASE_REPLICA_URL")? else {
+        return Ok(None);
+    };
+    let pool = connect_read_only(&url).await?;
+    log::info!("Statistics queries use the read replica");
+    Ok(Some(pool))
+}
+
+/// Opens `url` read-only and makes sure a write is refused, so statistics
+/// queries can never change the replica.
+async fn connect_read_only(url: &str) -> Result<SqlitePool> {
+    let options = sqlx::sqlite::SqliteConnectOptions::from_str(url)
+        .context("DATABASE_REPLICA_URL is not a valid SQLite URL")?
+        .read_only(true);
+    let pool = SqlitePool::connect_with(options)
+        .await
+        .context("Failed to connect to the DATABASE_REPLICA_URL replica")?;
+
+    // Rewriting the current value leaves the file unchanged if it ever succeeds
+    let version: i64 = sqlx::query_scalar("PRAGMA user_version").fetch_one(&pool).await?;
+    if sqlx::query(&format!("PRAGMA user_version = {}", version)).execute(&pool).await.is_ok() {
+        anyhow::bail!("The DATABASE_REPLICA_URL replica accepted a write, it must be opened read-only");
+    }
+    Ok(pool)
+}
+
+/// Non-secret settings read from the environment at startup.
+struct Config {
+    /// Chat notified when a task is paused because its own chat became unreachable.
+    fallback_chat_id: Option<i64>,
+    /// How long a successful chat lookup is trusted before the chat is checked again.
+    chat_cache_ttl: Duration,
+    /// How long an X.AI answer is reused for an identical one-time question.
+    xai_cache_ttl: Duration,
+    /// Upper bound on cached X.AI answers, 0 disables the cache.
+    xai_cache_max_entries: usize,
+    /// X.AI-backed commands a user may run per window, 0 disables the limit.
+    rate_limit_max_requests: usize,
+    rate_limit_window: Duration,
+    /// Minimum time between two commands of a user in a chat, unless the chat sets its own.
+    command_cooldown: Duration,
+    /// Longest task name accepted by /create, in characters.
+    max_task_name_len: usize,
+    /// When false nothing is written to `bot_logs` and statistics are unavailable.
+    logging_enabled: bool,
+    /// Matches masked in logged arguments and responses before they are stored.
+    redaction_patterns: Vec<Regex>,
+    /// Leading or trailing filler removed from answers in chats that enable /sanitize.
+    boilerplate_patterns: Vec<Regex>,
+    /// Lets chat admins view their own chat's statistics, global stats stay owner-only.
+    allow_admin_stats: bool,
+    xai_model: String,
+    /// Temperature used when neither the request nor the chat sets one.
+    default_temperature: f64,
+    /// Price used by /cost, in dollars per 1000 tokens.
+    xai_price_per_1k_tokens: f64,
+    /// Tokens assumed per run for tasks without recorded usage.
+    xai_tokens_per_run: i64,
+    /// How long a deleted task can be restored with /undelete before it is purged.
+    task_delete_grace: Duration,
+    /// Task batches the scheduler runs against X.AI at the same time.
+    max_concurrent_tasks: usize,
+    /// Factor /slower multiplies and /faster divides a task's interval by.
+    interval_step_factor: f64,
+    /// Weight of the newest sample in the X.AI latency average shown by /status.
+    xai_latency_alpha: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            fallback_chat_id: None,
+            chat_cache_ttl: Duration::from_secs(3600),
+            xai_cache_ttl: Duration::from_secs(300),
+            xai_cache_max_entries: 256,
+            rate_limit_max_requests: 20,
+            rate_limit_window: Duration::from_secs(3600),
+            command_cooldown: Duration::ZERO,
+            max_task_name_len: 64,
+            logging_enabled: true,
+            redaction_patterns: default_redaction_patterns(),
+            boilerplate_patterns: default_boilerplate_patterns(),
+            allow_admin_stats: false,
+            xai_model: String::from("grok-beta"),
+            default_temperature: 0.0,
+            xai_price_per_1k_tokens: 0.01,
+            xai_tokens_per_run: 1000,
+            task_delete_grace: Duration::from_secs(24 * 3600),
+            max_concurrent_tasks: 4,
+            interval_step_factor: 2.0,
+            xai_latency_alpha: 0.2,
+        }
+    }
+}
+
+impl Config {
+    fn from_env() -> Result<Self> {
+        let defaults = Config::default();
+        Config {
+            fallback_chat_id: env_opt("FALLBACK_CHAT_ID")?,
+            chat_cache_ttl: Duration::from_secs(env_or(
+                "CHAT_CACHE_TTL_SECS",
+                defaults.chat_cache_ttl.as_secs(),
+            )?),
+            xai_cache_ttl: Duration::from_secs(env_or(
+                "XAI_CACHE_TTL_SECS",
+                defaults.xai_cache_ttl.as_secs(),
+            )?),
+            xai_cache_max_entries: env_or("XAI_CACHE_MAX_ENTRIES", defaults.xai_cache_max_entries)?,
+            rate_limit_max_requests: env_or(
+                "RATE_LIMIT_MAX_REQUESTS",
+                defaults.rate_limit_max_requests,
+            )?,
+            rate_limit_window: Duration::from_secs(env_or(
+                "RATE_LIMIT_WINDOW_SECS",
+                defaults.rate_limit_window.as_secs(),
+            )?),
+            command_cooldown: Duration::from_secs(env_or(
+                "COMMAND_COOLDOWN_SECS",
+                defaults.command_cooldown.as_secs(),
+            )?),
+            max_task_name_len: env_or("MAX_TASK_NAME_LEN", defaults.max_task_name_len)?,
+            logging_enabled: env_or("LOGGING_ENABLED", defaults.logging_enabled)?,
+            redaction_patterns: redaction_patterns_from_env()?,
+            boilerplate_patterns: boilerplate_patterns_from_env()?,
+            allow_admin_stats: env_or("ALLOW_ADMIN_STATS", defaults.allow_admin_stats)?,
+            xai_model: env_or("XAI_MODEL", defaults.xai_model)?,
+            default_temperature: env_or("XAI_TEMPERATURE", defaults.default_temperature)?,
+            xai_price_per_1k_tokens: env_or(
+                "XAI_PRICE_PER_1K_TOKENS",
+                defaults.xai_price_per_1k_tokens,
+            )?,
+            xai_tokens_per_run: env_or("XAI_TOKENS_PER_RUN", defaults.xai_tokens_per_run)?,
+            task_delete_grace: Duration::from_secs(env_or(
+                "TASK_DELETE_GRACE_SECS",
+                defaults.task_delete_grace.as_secs(),
+            )?),
+            max_concurrent_tasks: env_or("TASK_CONCURRENCY", defaults.max_concurrent_tasks)?,
+            interval_step_factor: env_or("INTERVAL_STEP_FACTOR", defaults.interval_step_factor)?,
+            xai_latency_alpha: env_or("XAI_LATENCY_ALPHA", defaults.xai_latency_alpha)?,
+        }
+        .validate()
+    }
+
+    /// Rejects values that parse but would break the bot, so it fails at
+    /// startup instead of misbehaving later.
+    fn validate(self) -> Result<Self> {
+        if let Some(chat_id) = self.fallback_chat_id {
+            anyhow::ensure!(chat_id != 0, "FALLBACK_CHAT_ID must not be 0");
+        }
+        anyhow::ensure!(
+            self.rate_limit_max_requests == 0 || !self.rate_limit_window.is_zero(),
+            "RATE_LIMIT_WINDOW_SECS must be positive while RATE_LIMIT_MAX_REQUESTS is set"
+        );
+        anyhow::ensure!(
+            self.max_task_name_len > 0,
+            "MAX_TASK_NAME_LEN must be positive"
+        );
+        anyhow::ensure!(
+            self.max_concurrent_tasks > 0,
+            "TASK_CONCURRENCY must be positive"
+        );
+        anyhow::ensure!(!self.xai_model.trim().is_empty(), "XAI_MODEL must not be empty");
+        anyhow::ensure!(
+            (0.0..=2.0).contains(&self.default_temperature),
+            "XAI_TEMPERATURE must be between 0 and 2, got {}",
+            self.default_temperature
+        );
+        anyhow::ensure!(
+            self.xai_price_per_1k_tokens.is_finite() && self.xai_price_per_1k_tokens >= 0.0,
+            "XAI_PRICE_PER_1K_TOKENS must not be negative, got {}",
+            self.xai_price_per_1k_tokens
+        );
+        anyhow::ensure!(
+            self.xai_tokens_per_run > 0,
+            "XAI_TOKENS_PER_RUN must be positive, got {}",
+            self.xai_tokens_per_run
+        );
+        anyhow::ensure!(
+            self.interval_step_factor.is_finite() && self.interval_step_factor > 1.0,
+            "INTERVAL_STEP_FACTOR must be greater than 1, got {}",
+            self.interval_step_factor
+        );
+        anyhow::ensure!(
+            self.xai_latency_alpha > 0.0 && self.xai_latency_alpha <= 1.0,
+            "XAI_LATENCY_ALPHA must be in (0, 1], got {}",
+            self.xai_latency_alpha
+        );
+        anyhow::ensure!(
+            self.command_cooldown <= MAX_COMMAND_COOLDOWN,
+            "COMMAND_COOLDOWN_SECS must be at most {}, got {}",
+            MAX_COMMAND_COOLDOWN.as_secs(),
+            self.command_cooldown.as_secs()
+        );
+        Ok(self)
+    }
+}
+
+/// Telegram user ids fit in 52 bits.
+const MAX_TELEGRAM_USER_ID: i64 = (1 << 52) - 1;
+
+fn parse_owner_id(value: &str) -> Result<i64> {
+    let owner_id = value
+        .trim()
+        .parse::<i64>()
+        .with_context(|| format!("BOT_OWNER_ID must be a valid integer, got {:?}", value))?;
+    anyhow::ensure!(
+        (1..=MAX_TELEGRAM_USER_ID).contains(&owner_id),
+        "BOT_OWNER_ID must be a Telegram user id between 1 and {}, got {}",
+        MAX_TELEGRAM_USER_ID,
+        owner_id
+    );
+    Ok(owner_id)
+}
+
+fn mask_secret(secret: &str) -> &'static str {
+    if secret.is_empty() {
+        "<not set>"
+    } else {
+        "********"
+    }
+}
+
+/// Effective configuration for /config. Secrets are only reported as set or not set.
+fn format_config_report(config: &Config, xai_token: &str, telegram_token: &str) -> String {
+    let api_host = reqwest::Url::parse(XAI_API_URL)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let lines = [
+        ("xai_model", config.xai_model.clone()),
+        ("xai_temperature", config.default_temperature.to_string()),
+        ("xai_api_host", api_host),
+        ("xai_api_token", mask_secret(xai_token).to_string()),
+        ("telegram_bot_token", mask_secret(telegram_token).to_string()),
+        ("parse_mode", String::from("MarkdownV2")),
+        ("task_poll_interval", format!("{}s", TASK_POLL_INTERVAL.as_secs())),
+        ("task_delete_grace", format!("{}s", config.task_delete_grace.as_secs())),
+        ("task_concurrency", config.max_concurrent_tasks.to_string()),
+        ("interval_step_factor", config.interval_step_factor.to_string()),
+        ("xai_latency_alpha", config.xai_latency_alpha.to_string()),
+        ("max_task_name_len", config.max_task_name_len.to_string()),
+        ("rate_limit_max_requests", config.rate_limit_max_requests.to_string()),
+        ("rate_limit_window", format!("{}s", config.rate_limit_window.as_secs())),
+        ("command_cooldown", format!("{}s", config.command_cooldown.as_secs())),
+        ("xai_cache_ttl", format!("{}s", config.xai_cache_ttl.as_secs())),
+        ("xai_cache_max_entries", config.xai_cache_max_entries.to_string()),
+        ("chat_cache_ttl", format!("{}s", config.chat_cache_ttl.as_secs())),
+        ("xai_price_per_1k_tokens", config.xai_price_per_1k_tokens.to_string()),
+        ("xai_tokens_per_run", config.xai_tokens_per_run.to_string()),
+        ("logging_enabled", config.logging_enabled.to_string()),
+        ("redaction_patterns", config.redaction_patterns.len().to_string()),
+        ("boilerplate_patterns", config.boilerplate_patterns.len().to_string()),
+        ("allow_admin_stats", config.allow_admin_stats.to_string()),
+        (
+            "fallback_chat_id",
+            config
+                .fallback_chat_id
+                .map_or_else(|| String::from("<not set>"), |id| id.to_string()),
+        ),
+    ];
+
+    let width = lines.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    let body = lines
+        .iter()
+        .map(|(key, value)| format!("{:width$} = {}", key, value, width = width))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("*⚙️ Bot Configuration*\n\n```\n{}\n```", escape_code_block(&body))
+}
+
+const DEFAULT_REDACTION_PATTERNS: &[&str] = &[
+    // Telegram bot tokens
+    r"\b\d{6,12}:[A-Za-z0-9_-]{30,}\b",
+    // Prefixed API keys such as sk-..., xai-..., ghp_..., AKIA...
+    r"\b(?:sk|pk|rk|xai|ghp|gho|ghs|glpat|AKIA)[-_]?[A-Za-z0-9_-]{16,}\b",
+    // Authorization headers
+    r"(?i)\bbearer\s+[A-Za-z0-9._~+/=-]+",
+    // key=value or key: value credentials
+    r"(?i)\b(?:password|passwd|pwd|secret|token|api[_-]?key)\s*[:=]\s*\S+",
+    // E-mail addresses
+    r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}\b",
+    // Long opaque strings that look like keys or hashes
+    r"\b[A-Za-z0-9_-]{40,}\b",
+];
+
+fn default_redaction_patterns() -> Vec<Regex> {
+    DEFAULT_REDACTION_PATTERNS
+        .iter()
+        .map(|pattern| Regex::new(pattern).expect("built-in redaction pattern must compile"))
+        .collect()
+}
+
+/// Built-in patterns plus any `;`-separated extras from `LOG_REDACT_PATTERNS`.
+fn redaction_patterns_from_env() -> Result<Vec<Regex>> {
+    let mut patterns = default_redaction_patterns();
+    if let Ok(extra) = env::var("LOG_REDACT_PATTERNS") {
+        for pattern in extra.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            patterns.push(
+                Regex::new(pattern)
+                    .with_context(|| format!("LOG_REDACT_PATTERNS has an invalid regex: {}", pattern))?,
+            );
+        }
+    }
+    Ok(patterns)
+}
+
+fn redact_sensitive(text: &str, patterns: &[Regex]) -> String {
+    patterns
+        .iter()
+        .fold(text.to_string(), |redacted, pattern| {
+            pattern.replace_all(&redacted, "[REDACTED]").into_owned()
+        })
+}
+
+const DEFAULT_BOILERPLATE_PATTERNS: &[&str] = &[
+    // "Sure! Here's the latest on Bitcoin:" before the answer
+    r"(?i)^(?:sure|certainly|of course|absolutely)[!,.]?\s+here(?:'s| is| are)\b[^\n]*?[:.!](?:\s+|$)",
+    // "Certainly!" on a line of its own
+    r"(?i)^(?:sure|certainly|of course|absolutely)[!.]?[ \t]*\n",
+    // "Let me know if you need anything else!" as the closing line
+    r"(?i)\n[ \t]*(?:let me know if|feel free to (?:ask|reach out)|i hope this helps|hope this helps)[^\n]*$",
+];
+
+fn default_boilerplate_patterns() -> Vec<Regex> {
+    DEFAULT_BOILERPLATE_PATTERNS
+        .iter()
+        .map(|pattern| Regex::new(pattern).expect("built-in boilerplate pattern must compile"))
+        .collect()
+}
+
+/// Built-in patterns plus any `;`-separated extras from `XAI_BOILERPLATE_PATTERNS`.
+fn boilerplate_patterns_from_env() -> Result<Vec<Regex>> {
+    let mut patterns = default_boilerplate_patterns();
+    if let Ok(extra) = env::var("XAI_BOILERPLATE_PATTERNS") {
+        for pattern in extra.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            patterns.push(
+                Regex::new(pattern)
+                    .with_context(|| format!("XAI_BOILERPLATE_PATTERNS has an invalid regex: {}", pattern))?,
+            );
+        }
+    }
+    Ok(patterns)
+}
+
+/// Removes boilerplate matched at the very start or end of an answer. Matches
+/// in the middle are left alone, and an answer that would become empty is
+/// returned unchanged.
+fn strip_boilerplate(text: &str, patterns: &[Regex]) -> String {
+    let mut stripped = text.trim();
+    loop {
+        let before = stripped;
+        for pattern in patterns {
+            if let Some(found) = pattern.find(stripped) {
+                if found.start() == 0 {
+                    stripped = stripped[found.end()..].trim_start();
+                } else if found.end() == stripped.len() {
+                    stripped = stripped[..found.start()].trim_end();
+                }
+            }
+        }
+        if stripped == before {
+            break;
+        }
+    }
+
+    if stripped.is_empty() {
+        text.to_string()
+    } else {
+        stripped.to_string()
+    }
+}
+
+fn env_opt<T: FromStr>(key: &str) -> Result<Option<T>> {
+    match env::var(key) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow::anyhow!("{} has an invalid value: {}", key, value)),
+        _ => Ok(None),
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> Result<T> {
+    Ok(env_opt(key)?.unwrap_or(default))
+}
+
+async fn is_chat_admin(bot: &Bot, msg: &Message) -> Result<bool, BotError> {
+    if msg.chat.is_private() {
+        return Ok(true);
+    }
+    let Some(user) = msg.from.as_ref() else {
+        return Ok(false);
+    };
+    let member = bot.get_chat_member(msg.chat.id, user.id).await?;
+    Ok(matches!(
+        member.kind,
+        ChatMemberKind::Owner(_) | ChatMemberKind::Administrator(_)
+    ))
+}
+
+async fn ensure_chat_admin(bot: &Bot, msg: &Message) -> Result<(), BotError> {
+    if is_chat_admin(bot, msg).await? {
+        Ok(())
+    } else {
+        Err(BotError::AdminRequired)
+    }
+}
+
+/// The owner can always see a chat's statistics, chat admins only when
+/// `ALLOW_ADMIN_STATS` is set.
+fn can_view_chat_stats(is_owner: bool, is_chat_admin: bool, allow_admin_stats: bool) -> bool {
+    is_owner || (allow_admin_stats && is_chat_admin)
+}
+
+/// Sliding-window limit on X.AI-backed commands, tracked per user in memory.
+struct RateLimiter {
+    max_requests: usize,
+    window: Duration,
+    requests: HashMap<i64, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    fn new(max_requests: usize, window: Duration) -> Self {
+        RateLimiter {
+            max_requests,
+            window,
+            requests: HashMap::new(),
+        }
+    }
+
+    fn prune(&mut self, user_id: i64, now: Instant) {
+        let window = self.window;
+        if let Some(requests) = self.requests.get_mut(&user_id) {
+            while requests
+                .front()
+                .is_some_and(|sent| now.duration_since(*sent) >= window)
+            {
+                requests.pop_front();
+            }
+            if requests.is_empty() {
+                self.requests.remove(&user_id);
+            }
+        }
+    }
+
+    /// Records a request, or returns how long the user has to wait.
+    fn check(&mut self, user_id: i64, now: Instant) -> Result<(), Duration> {
+        if self.max_requests == 0 {
+            return Ok(());
+        }
+
+        self.prune(user_id, now);
+        let requests = self.requests.entry(user_id).or_default();
+        if requests.len() >= self.max_requests {
+            let oldest = requests[0];
+            return Err(self.window.saturating_sub(now.duration_since(oldest)));
+        }
+        requests.push_back(now);
+        Ok(())
+    }
+
+    /// Requests counted in the current window and the time until the oldest expires.
+    fn usage(&mut self, user_id: i64, now: Instant) -> (usize, Option<Duration>) {
+        self.prune(user_id, now);
+        match self.requests.get(&user_id) {
+            Some(requests) => (
+                requests.len(),
+                requests
+                    .front()
+                    .map(|oldest| self.window.saturating_sub(now.duration_since(*oldest))),
+            ),
+            None => (0, None),
+        }
+    }
+
+    fn reset(&mut self, user_id: i64) -> bool {
+        self.requests.remove(&user_id).is_some()
+    }
+}
+
+/// Exponential moving average of X.AI response times, kept in memory only.
+struct LatencyTracker {
+    alpha: f64,
+    average_ms: Option<f64>,
+    last_ms: Option<f64>,
+    samples: u64,
+}
+
+impl LatencyTracker {
+    fn new(alpha: f64) -> Self {
+        LatencyTracker {
+            alpha,
+            average_ms: None,
+            last_ms: None,
+            samples: 0,
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let ms = latency.as_secs_f64() * 1000.0;
+        self.average_ms = Some(match self.average_ms {
+            Some(average) => self.alpha * ms + (1.0 - self.alpha) * average,
+            None => ms,
+        });
+        self.last_ms = Some(ms);
+        self.samples += 1;
+    }
+}
+
+fn format_status(latency: &LatencyTracker) -> String {
+    match (latency.average_ms, latency.last_ms) {
+        (Some(average), Some(last)) => format!(
+            "*📡 X\\.AI latency*\n\
+            Average: {} ms\n\
+            Last call: {} ms\n\
+            Calls since start: {}\n\
+            Smoothing: {}",
+            average.round(),
+            last.round(),
+            latency.samples,
+            escape_markdown_v2(&latency.alpha.to_string())
+        ),
+        _ => String::from("📡 No X\\.AI calls since the bot started"),
+    }
+}
+
+/// Longest cooldown a chat can set, which also bounds how long entries are kept.
+const MAX_COMMAND_COOLDOWN: Duration = Duration::from_secs(3600);
+
+/// How long repeats of an error are collapsed into one summary line.
+const REPEATED_ERROR_WINDOW: Duration = Duration::from_secs(300);
+
+/// Collapses identical errors, e.g. one per task while X.AI is down, so each
+/// is logged once per window followed by a "repeated N times" summary.
+struct RepeatedErrors {
+    window: Duration,
+    /// When each error was first logged in its window, and how often it recurred since
+    seen: HashMap<String, (Instant, usize)>,
+}
+
+impl RepeatedErrors {
+    fn new(window: Duration) -> Self {
+        RepeatedErrors {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Lines to log for an occurrence of `message`: the message itself the
+    /// first time in a window, plus summaries of windows that just ended.
+    fn record(&mut self, message: &str, now: Instant) -> Vec<String> {
+        let mut lines = self.flush(now);
+        match self.seen.entry(message.to_string()) {
+            Entry::Occupied(mut seen) => seen.get_mut().1 += 1,
+            Entry::Vacant(seen) => {
+                seen.insert((now, 0));
+                lines.push(message.to_string());
+            }
+        }
+        lines
+    }
+
+    /// Ends the windows that are over, summarizing those with repeats.
+    fn flush(&mut self, now: Instant) -> Vec<String> {
+        let window = self.window;
+        let mut summaries = Vec::new();
+        self.seen.retain(|message, (since, repeats)| {
+            if now.duration_since(*since) < window {
+                return true;
+            }
+            if *repeats > 0 {
+                summaries.push(format!(
+                    "{} (repeated {} times in the last {}s)",
+                    message,
+                    repeats,
+                    window.as_secs()
+                ));
+            }
+            false
+        });
+        summaries.sort();
+        summaries
+    }
+}
+
+fn log_repeated_error(state: &AppState, message: &str) {
+    for line in state.repeated_errors.lock().unwrap().record(message, Instant::now()) {
+        log::error!("{}", line);
+    }
+}
+
+/// Anti-flood cooldown between commands, per user and chat, kept in memory.
+#[derive(Default)]
+struct CommandCooldowns {
+    last_command: HashMap<(i64, i64), Instant>,
+}
+
+impl CommandCooldowns {
+    /// Records a command, or returns how long the user has to wait.
+    fn check(&mut self, chat_id: i64, user_id: i64, cooldown: Duration, now: Instant) -> Result<(), Duration> {
+        self.last_command
+            .retain(|_, last| now.duration_since(*last) < MAX_COMMAND_COOLDOWN);
+        if cooldown.is_zero() {
+            return Ok(());
+        }
+
+        if let Some(last) = self.last_command.get(&(chat_id, user_id)) {
+            let elapsed = now.duration_since(*last);
+            if elapsed < cooldown {
+                return Err(cooldown - elapsed);
+            }
+        }
+        self.last_command.insert((chat_id, user_id), now);
+        Ok(())
+    }
+}
+
+async fn check_command_cooldown(state: &AppState, chat_id: i64, user_id: Option<i64>) -> Result<(), BotError> {
+    let Some(user_id) = user_id.filter(|id| *id != state.owner_id) else {
+        return Ok(());
+    };
+    let cooldown = get_chat_settings(&state.pool, chat_id)
+        .await?
+        .cooldown
+        .unwrap_or(state.config.command_cooldown);
+    state
+        .cooldowns
+        .lock()
+        .unwrap()
+        .check(chat_id, user_id, cooldown, Instant::now())
+        .map_err(BotError::CooldownActive)
+}
+
+/// Parses a cooldown in whole seconds, at most [`MAX_COMMAND_COOLDOWN`].
+fn parse_cooldown(value: &str) -> Result<Duration, BotError> {
+    value
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+        .filter(|cooldown| *cooldown <= MAX_COMMAND_COOLDOWN)
+        .ok_or(BotError::InvalidParameters)
+}
+
+fn check_rate_limit(state: &AppState, user_id: Option<i64>) -> Result<(), BotError> {
+    match user_id {
+        Some(id) if id == state.owner_id => Ok(()),
+        Some(id) => state
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .check(id, Instant::now())
+            .map_err(BotError::RateLimited),
+        None => Ok(()),
+    }
+}
+
+fn parse_rate_limit_command(input: &str) -> Option<(i64, bool)> {
+    let mut parts = input.split_whitespace();
+    let user_id = parts.next()?.parse::<i64>().ok()?;
+    let reset = match parts.next() {
+        None => false,
+        Some("reset") => true,
+        Some(_) => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((user_id, reset))
+}
+
+fn ensure_owner(user_id: Option<i64>, owner_id: i64) -> Result<(), BotError> {
+    match user_id {
+        Some(id) if id == owner_id => Ok(()),
+        _ => Err(BotError::PermissionDenied),
+    }
 }
 
 
@@ -146,26 +1099,76 @@ fn escape_markdown_v2(text: &str) -> String {
     result
 }
 
-async fn initialize_database() -> Result<()> {
-    let data_dir = Path::new("data");
-    let db_path = data_dir.join("tasks.db");
+/// Used unless `DATABASE_PATH` is set.
+const DEFAULT_DATABASE_PATH: &str = "data/tasks.db";
 
-    if !data_dir.exists() {
-        fs::create_dir_all(data_dir).context("Failed to create data directory")?;
-        log::info!("Created data directory");
+/// Where the SQLite database lives, and whether the operator chose it.
+fn database_path() -> (PathBuf, bool) {
+    match env::var("DATABASE_PATH") {
+        Ok(path) if !path.trim().is_empty() => (PathBuf::from(path.trim()), true),
+        _ => (PathBuf::from(DEFAULT_DATABASE_PATH), false),
     }
+}
+
+#[derive(Debug, Error)]
+enum DatabasePathError {
+    #[error(
+        "Cannot write the database at {path}: {source}. The default data directory is not writable \
+         (e.g. a read-only container filesystem); set DATABASE_PATH to a file in a writable location, \
+         such as a mounted volume"
+    )]
+    DefaultNotWritable { path: String, source: std::io::Error },
+
+    #[error("Cannot write the database at {path}, set by DATABASE_PATH: {source}. Point DATABASE_PATH at a writable location")]
+    ConfiguredNotWritable { path: String, source: std::io::Error },
+}
+
+/// Creates the database file and its directory if needed, and checks that
+/// SQLite will be able to write both the file and its journal next to it.
+fn prepare_database_file(path: &Path, configured: bool) -> Result<(), DatabasePathError> {
+    let not_writable = |source| {
+        let path = path.display().to_string();
+        if configured {
+            DatabasePathError::ConfiguredNotWritable { path, source }
+        } else {
+            DatabasePathError::DefaultNotWritable { path, source }
+        }
+    };
 
-    if !db_path.exists() {
-        fs::File::create(&db_path).context("Failed to create database file")?;
-        log::info!("Created empty database file");
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if !dir.exists() {
+            fs::create_dir_all(dir).map_err(not_writable)?;
+            log::info!("Created data directory {}", dir.display());
+        }
     }
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(not_writable)?;
+
+    let mut probe = path.as_os_str().to_owned();
+    probe.push(".write-check");
+    fs::File::create(&probe).map_err(not_writable)?;
+    fs::remove_file(&probe).map_err(not_writable)?;
+    Ok(())
+}
 
-    let database_url = format!("sqlite:{}", db_path.to_string_lossy());
+async fn initialize_database(path: &Path, configured: bool) -> Result<SqlitePool> {
+    prepare_database_file(path, configured)?;
 
+    let database_url = format!("sqlite:{}", path.to_string_lossy());
     let pool = SqlitePool::connect(&database_url)
         .await
-        .context("Failed to connect to SQLite database")?;
+        .with_context(|| format!("Failed to connect to SQLite database at {}", path.display()))?;
+
+    create_schema(&pool).await?;
+
+    log::info!("Database at {} initialized successfully", path.display());
+    Ok(pool)
+}
 
+async fn create_schema(pool: &SqlitePool) -> Result<()> {
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS tasks (
@@ -177,7 +1180,7 @@ async fn initialize_database() -> Result<()> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await
     .context("Failed to create tasks table")?;
 
@@ -197,56 +1200,179 @@ async fn initialize_database() -> Result<()> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await
     .context("Failed to create logs table")?;
 
-    log::info!("Database initialized successfully");
-    Ok(())
-
-}
-
-async fn log_interaction(
-    pool: &SqlitePool,
-    chat_id: i64,
-    user_id: Option<i64>,
-    username: Option<String>,
-    command: &str,
-    args: Option<&str>,
-    response: Option<&str>,
-    error: Option<&str>,
-    execution_time: Duration,
-) -> Result<(), sqlx::Error> {
     sqlx::query(
         r#"
-        INSERT INTO bot_logs 
-        (timestamp, chat_id, user_id, username, command, args, response, error, execution_time_ms)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        CREATE TABLE IF NOT EXISTS chat_settings (
+            chat_id INTEGER PRIMARY KEY,
+            welcome_message TEXT
+        )
         "#,
     )
-    .bind(Utc::now().to_rfc3339())
-    .bind(chat_id)
-    .bind(user_id)
-    .bind(username)
-    .bind(command)
-    .bind(args)
-    .bind(response)
-    .bind(error)
-    .bind(execution_time.as_millis() as i64)
     .execute(pool)
-    .await?;
-
-    Ok(())
-}
+    .await
+    .context("Failed to create chat settings table")?;
 
-async fn get_user_stats(pool: &SqlitePool, user_id: i64) -> Result<Value, sqlx::Error> {
-    let stats = sqlx::query(
+    sqlx::query(
         r#"
-        SELECT 
-            COUNT(*) as total_commands,
-            COUNT(DISTINCT DATE(timestamp)) as active_days,
-            AVG(execution_time_ms) as avg_execution_time,
-            COUNT(CASE WHEN error IS NOT NULL THEN 1 END) as error_count
+        CREATE TABLE IF NOT EXISTS task_runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            task_name TEXT NOT NULL,
+            chat_id INTEGER NOT NULL,
+            run_at TEXT NOT NULL,
+            response TEXT NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create task runs table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS ratings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            target_kind TEXT NOT NULL,
+            target_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            vote INTEGER NOT NULL,
+            command TEXT NOT NULL,
+            model TEXT NOT NULL,
+            rated_at TEXT NOT NULL,
+            UNIQUE(target_kind, target_id, user_id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create ratings table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS user_prefs (
+            user_id INTEGER PRIMARY KEY,
+            model TEXT,
+            temperature REAL,
+            style TEXT
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create user preferences table")?;
+
+    // Columns added after the initial release
+    add_column_if_missing(pool, "tasks", "paused", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "chat_settings", "temperature", "REAL").await?;
+    add_column_if_missing(pool, "chat_settings", "language", "TEXT").await?;
+    add_column_if_missing(pool, "chat_settings", "sanitize", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "chat_settings", "cooldown_secs", "INTEGER").await?;
+    add_column_if_missing(pool, "tasks", "task_group", "TEXT").await?;
+    add_column_if_missing(pool, "tasks", "tags", "TEXT").await?;
+    add_column_if_missing(pool, "task_runs", "total_tokens", "INTEGER").await?;
+    add_column_if_missing(pool, "task_runs", "model", "TEXT").await?;
+    add_column_if_missing(pool, "bot_logs", "model", "TEXT").await?;
+    add_column_if_missing(pool, "tasks", "deleted_at", "TEXT").await?;
+    add_column_if_missing(pool, "tasks", "prefix", "TEXT").await?;
+    add_column_if_missing(pool, "tasks", "suffix", "TEXT").await?;
+    add_column_if_missing(pool, "tasks", "priority", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "tasks", "run_on_startup", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "tasks", "pin", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "tasks", "pinned_message_id", "INTEGER").await?;
+    add_column_if_missing(pool, "tasks", "target_chat_id", "INTEGER").await?;
+    add_column_if_missing(pool, "tasks", "max_tokens", "INTEGER").await?;
+
+    Ok(())
+}
+
+async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists = sqlx::query("SELECT 1 FROM pragma_table_info(?) WHERE name = ?")
+        .bind(table)
+        .bind(column)
+        .fetch_optional(pool)
+        .await?
+        .is_some();
+
+    if !exists {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(pool)
+            .await
+            .with_context(|| format!("Failed to add column {}.{}", table, column))?;
+        log::info!("Added column {}.{}", table, column);
+    }
+
+    Ok(())
+}
+
+fn ensure_logging_enabled(state: &AppState) -> Result<(), BotError> {
+    if state.config.logging_enabled {
+        Ok(())
+    } else {
+        Err(BotError::StatisticsDisabled)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn log_interaction(
+    state: &AppState,
+    chat_id: i64,
+    user_id: Option<i64>,
+    username: Option<String>,
+    command: &str,
+    args: Option<&str>,
+    response: Option<&str>,
+    model: Option<&str>,
+    error: Option<&str>,
+    execution_time: Duration,
+) -> Result<Option<i64>, sqlx::Error> {
+    if !state.config.logging_enabled {
+        return Ok(None);
+    }
+
+    let patterns = &state.config.redaction_patterns;
+    let args = args.map(|args| redact_sensitive(args, patterns));
+    let response = response.map(|response| redact_sensitive(response, patterns));
+
+    let result = sqlx::query(
+        r#"
+        INSERT INTO bot_logs 
+        (timestamp, chat_id, user_id, username, command, args, response, model, error, execution_time_ms)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(Utc::now().to_rfc3339())
+    .bind(chat_id)
+    .bind(user_id)
+    .bind(username)
+    .bind(command)
+    .bind(args)
+    .bind(response)
+    .bind(model)
+    .bind(error)
+    .bind(execution_time.as_millis() as i64)
+    .execute(&state.pool)
+    .await?;
+
+    Ok(Some(result.last_insert_rowid()))
+}
+
+async fn get_user_stats(state: &AppState, user_id: i64) -> Result<Value, sqlx::Error> {
+    let pool = &state.read_pool;
+    let stats = sqlx::query(
+        r#"
+        SELECT 
+            COUNT(*) as total_commands,
+            COUNT(DISTINCT DATE(timestamp)) as active_days,
+            AVG(execution_time_ms) as avg_execution_time,
+            COUNT(CASE WHEN error IS NOT NULL THEN 1 END) as error_count
         FROM bot_logs 
         WHERE user_id = ?
         "#
@@ -263,7 +1389,9 @@ async fn get_user_stats(pool: &SqlitePool, user_id: i64) -> Result<Value, sqlx::
     }))
 }
 
-async fn get_command_stats(pool: &SqlitePool) -> Result<Value, sqlx::Error> {
+/// Per-command statistics, for the whole bot or only for `chat_id`.
+async fn get_command_stats(state: &AppState, chat_id: Option<i64>) -> Result<Value, sqlx::Error> {
+    let pool = &state.read_pool;
     let stats = sqlx::query(
         r#"
         SELECT 
@@ -272,10 +1400,12 @@ async fn get_command_stats(pool: &SqlitePool) -> Result<Value, sqlx::Error> {
             AVG(execution_time_ms) as avg_execution_time,
             COUNT(CASE WHEN error IS NOT NULL THEN 1 END) as error_count
         FROM bot_logs 
+        WHERE ?1 IS NULL OR chat_id = ?1
         GROUP BY command
         ORDER BY usage_count DESC
         "#
     )
+    .bind(chat_id)
     .fetch_all(pool)
     .await?;
 
@@ -291,948 +1421,7896 @@ async fn get_command_stats(pool: &SqlitePool) -> Result<Value, sqlx::Error> {
     }))
 }
 
-async fn parse_create_command(input: String) -> Option<(String, u64, String)> {
-    let parts: Vec<&str> = input.splitn(3, ' ').collect();
-    if parts.len() == 3 {
-        let interval = parts[1].parse::<u64>().ok()?;
-        Some((parts[0].to_string(), interval, parts[2].to_string()))
-    } else {
-        None
-    }
+/// Settings a chat has overridden, `None` means the global default applies.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct ChatSettings {
+    welcome_message: Option<String>,
+    temperature: Option<f64>,
+    language: Option<String>,
+    /// Strip boilerplate from answers before they are sent.
+    sanitize: bool,
+    /// Minimum time between two commands of a user in this chat.
+    cooldown: Option<Duration>,
 }
 
-fn format_xai_response(task_name: Option<&str>, question: &str, response: &str) -> String {
-    match task_name {
-        Some(name) => format!(
-            "🤖 *Task Response*\n\n\
-            📌 *Task:* {}\n\
-            ❓ *Question:* `{}`\n\n\
-            📝 *Answer:*\n\n{}",
-            escape_markdown_v2(name),
-            escape_markdown_v2(question),
-            format_response_content(response)
-        ),
-        None => format!(
-            "🤖 *X\\.AI Response*\n\n\
-            ❓ *Question:* `{}`\n\n\
-            📝 *Answer:*\n\n{}",
-            escape_markdown_v2(question),
-            format_response_content(response)
-        ),
-    }
+async fn get_chat_settings(pool: &SqlitePool, chat_id: i64) -> Result<ChatSettings, sqlx::Error> {
+    let settings = sqlx::query("SELECT welcome_message, temperature, language, sanitize, cooldown_secs FROM chat_settings WHERE chat_id = ?")
+        .bind(chat_id)
+        .fetch_optional(pool)
+        .await?
+        .map(|row| ChatSettings {
+            welcome_message: row.get("welcome_message"),
+            temperature: row.get("temperature"),
+            language: row.get("language"),
+            sanitize: row.get("sanitize"),
+            cooldown: row
+                .get::<Option<i64>, _>("cooldown_secs")
+                .map(|secs| Duration::from_secs(secs.max(0) as u64)),
+        })
+        .unwrap_or_default();
+    Ok(settings)
 }
 
+async fn set_chat_temperature(
+    pool: &SqlitePool,
+    chat_id: i64,
+    temperature: Option<f64>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO chat_settings (chat_id, temperature) VALUES (?, ?)
+        ON CONFLICT(chat_id) DO UPDATE SET temperature = excluded.temperature
+        "#,
+    )
+    .bind(chat_id)
+    .bind(temperature)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
 
-fn format_response_content(content: &str) -> String {
-    content
-        .split("\n\n")
-        .map(|paragraph| {
-            // Handle lists
-            if paragraph
-                .lines()
-                .any(|line| line.trim().starts_with('-') || line.trim().starts_with('*'))
-            {
-                paragraph
-                    .lines()
-                    .map(|line| {
-                        if line.trim().starts_with('-') || line.trim().starts_with('*') {
-                            let content = line
-                                .trim()
-                                .trim_start_matches(|c| c == '-' || c == '*')
-                                .trim();
-                            format!("• {}", process_markdown_formatting(content))
-                        } else {
-                            process_markdown_formatting(line)
-                        }
-                    })
-                    .collect::<Vec<_>>()
-                    .join("\n")
-            } else {
-                process_markdown_formatting(paragraph)
-            }
-        })
-        .collect::<Vec<_>>()
-        .join("\n\n")
+async fn set_chat_language(
+    pool: &SqlitePool,
+    chat_id: i64,
+    language: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO chat_settings (chat_id, language) VALUES (?, ?)
+        ON CONFLICT(chat_id) DO UPDATE SET language = excluded.language
+        "#,
+    )
+    .bind(chat_id)
+    .bind(language)
+    .execute(pool)
+    .await?;
+    Ok(())
 }
 
-fn process_markdown_formatting(text: &str) -> String {
-    let mut result = String::with_capacity(text.len() * 2);
-    let mut chars = text.chars().peekable();
-    let mut in_format = None; // None, Some("bold"), Some("italic"), Some("code")
-    let mut current_text = String::new();
+async fn set_chat_sanitize(pool: &SqlitePool, chat_id: i64, sanitize: bool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO chat_settings (chat_id, sanitize) VALUES (?, ?)
+        ON CONFLICT(chat_id) DO UPDATE SET sanitize = excluded.sanitize
+        "#,
+    )
+    .bind(chat_id)
+    .bind(sanitize)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
 
-    while let Some(c) = chars.next() {
-        match c {
-            '*' | '_' | '`' => {
-                let format_type = match c {
-                    '*' => "bold",
-                    '_' => "italic",
-                    '`' => "code",
-                    _ => unreachable!(),
-                };
+async fn set_chat_cooldown(
+    pool: &SqlitePool,
+    chat_id: i64,
+    cooldown: Option<Duration>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO chat_settings (chat_id, cooldown_secs) VALUES (?, ?)
+        ON CONFLICT(chat_id) DO UPDATE SET cooldown_secs = excluded.cooldown_secs
+        "#,
+    )
+    .bind(chat_id)
+    .bind(cooldown.map(|cooldown| cooldown.as_secs() as i64))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
 
-                // Count consecutive formatting characters
-                let mut count = 1;
-                while chars.peek() == Some(&c) {
-                    count += 1;
-                    chars.next();
-                }
+/// Applies the boilerplate sanitizer to an answer if the chat enabled it.
+async fn sanitize_for_chat(state: &AppState, chat_id: i64, answer: String) -> Result<String, BotError> {
+    if get_chat_settings(&state.pool, chat_id).await?.sanitize {
+        Ok(strip_boilerplate(&answer, &state.config.boilerplate_patterns))
+    } else {
+        Ok(answer)
+    }
+}
 
-                // If we have accumulated text, escape and add it
-                if !current_text.is_empty() {
-                    result.push_str(&escape_non_formatting_chars(&current_text));
-                    current_text.clear();
-                }
+fn format_chat_settings(settings: &ChatSettings, config: &Config) -> String {
+    let temperature = match settings.temperature {
+        Some(temperature) => format!("{}", temperature),
+        None => format!("{} (default)", config.default_temperature),
+    };
+    let language = match settings.language.as_deref() {
+        Some(code) => format!("{} ({})", language_name(code).unwrap_or(code), code),
+        None => String::from("same as the question (default)"),
+    };
+    let cooldown = match settings.cooldown {
+        Some(cooldown) if cooldown.is_zero() => String::from("off"),
+        Some(cooldown) => format!("{}s", cooldown.as_secs()),
+        None if config.command_cooldown.is_zero() => String::from("off (default)"),
+        None => format!("{}s (default)", config.command_cooldown.as_secs()),
+    };
+    format!(
+        "*⚙️ Chat Settings*\n\n\
+        🌡 *Temperature:* {}\n\
+        🌐 *Language:* {}\n\
+        🧹 *Sanitizer:* {}\n\
+        🐢 *Command cooldown:* {}\n\
+        👋 *Welcome message:* {}",
+        escape_markdown_v2(&temperature),
+        escape_markdown_v2(&language),
+        if settings.sanitize { "on" } else { "off" },
+        escape_markdown_v2(&cooldown),
+        if settings.welcome_message.is_some() { "custom" } else { "default" }
+    )
+}
 
-                // Handle formatting markers
-                match (in_format, count) {
-                    (None, _) => {
-                        // Start formatting
-                        in_format = Some(format_type);
-                        // Add the formatting characters without escaping
-                        for _ in 0..count {
-                            result.push(c);
-                        }
-                    }
-                    (Some(current_type), _) if current_type == format_type => {
-                        // End formatting
-                        in_format = None;
-                        // Add the formatting characters without escaping
-                        for _ in 0..count {
-                            result.push(c);
-                        }
-                    }
-                    _ => {
-                        // Mismatched formatting or nested formats - escape the characters
-                        for _ in 0..count {
-                            result.push('\\');
-                            result.push(c);
-                        }
-                    }
-                }
-            }
-            _ => {
-                current_text.push(c);
-            }
-        }
-    }
+async fn set_welcome_message(pool: &SqlitePool, chat_id: i64, message: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO chat_settings (chat_id, welcome_message) VALUES (?, ?)
+        ON CONFLICT(chat_id) DO UPDATE SET welcome_message = excluded.welcome_message
+        "#,
+    )
+    .bind(chat_id)
+    .bind(message)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
 
-    // Handle any remaining text
-    if !current_text.is_empty() {
-        result.push_str(&escape_non_formatting_chars(&current_text));
+fn format_welcome_message(message: Option<&str>) -> String {
+    match message {
+        Some(text) => format!("👋 {}", escape_markdown_v2(text)),
+        None => String::from(
+            "👋 *Welcome to Wibot\\!*\n\n\
+            I can answer questions with X\\.AI and run them on a schedule\\.\n\
+            Use /help to see all commands\\.",
+        ),
     }
+}
 
-    result
+struct InactiveChat {
+    chat_id: i64,
+    task_count: i64,
+    last_activity: Option<String>,
 }
 
-async fn call_xai_api(state: &AppState, question: &str) -> Result<String> {
-    let response = state
-        .http_client
-        .post("https://api.x.ai/v1/chat/completions")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", state.xai_token))
-        .json(&json!({
-            "messages": [
-                {
-                    "role": "system",
-                    "content": "You are a helpful assistant. When formatting responses:
-                    - Use *word* for bold text (surround text with single asterisks)
-                    - Start list items with - or *
-                    - Keep responses clear and structured
-                    - Separate paragraphs with blank lines
-                    
-                    Example format:
-                    Here are the prices:
-                    - *Bitcoin (BTC)*: The price is $50,000
-                    - *Ethereum (ETH)*: The price is $3,000"
-                },
-                {
-                    "role": "user",
-                    "content": question
-                }
-            ],
-            "model": "grok-beta",
-            "stream": false,
-            "temperature": 0
-        }))
-        .send()
-        .await?
-        .json::<Value>()
-        .await?;
+async fn find_inactive_chats(
+    pool: &SqlitePool,
+    cutoff: DateTime<Utc>,
+) -> Result<Vec<InactiveChat>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT
+            t.chat_id,
+            COUNT(*) as task_count,
+            (SELECT MAX(l.timestamp) FROM bot_logs l WHERE l.chat_id = t.chat_id) as last_activity
+        FROM tasks t
+        WHERE t.deleted_at IS NULL
+        GROUP BY t.chat_id
+        HAVING last_activity IS NULL OR last_activity < ?
+        ORDER BY t.chat_id
+        "#
+    )
+    .bind(cutoff.to_rfc3339())
+    .fetch_all(pool)
+    .await?;
 
-    Ok(response["choices"][0]["message"]["content"]
-        .as_str()
-        .unwrap_or("No response received")
-        .to_string())
+    Ok(rows
+        .iter()
+        .map(|row| InactiveChat {
+            chat_id: row.get("chat_id"),
+            task_count: row.get("task_count"),
+            last_activity: row.get("last_activity"),
+        })
+        .collect())
 }
 
-fn format_help_message() -> String {
-    format!(
-        "*Available Commands:*\n\n\
-        📌 */help* \\- Show this help message\n\n\
-        📝 */create* \\<name\\> \\<interval\\_minutes\\> \\<question\\>\n\
-        Creates a recurring X\\.AI query task\n\
-        Example: `/create weather 60 What's the weather in New York?`\n\n\
-        📋 */list* \\- Show all active tasks\n\n\
-        🗑 */delete* \\<name\\> \\- Remove a task\n\n\
-        ❓ */ask* \\<question\\> \\- Ask X\\.AI a one\\-time question"
+const ALL_TASKS_PAGE_SIZE: i64 = 10;
+
+#[derive(Debug, PartialEq)]
+struct ChatTask {
+    chat_id: i64,
+    name: String,
+    question: String,
+    interval: i64,
+    paused: bool,
+}
+
+/// One page of tasks across every chat, plus the total number of tasks.
+async fn fetch_all_tasks_page(
+    pool: &SqlitePool,
+    page: i64,
+) -> Result<(Vec<ChatTask>, i64), sqlx::Error> {
+    let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tasks WHERE deleted_at IS NULL")
+        .fetch_one(pool)
+        .await?;
+    let rows = sqlx::query(
+        "SELECT chat_id, name, description, interval, paused FROM tasks WHERE deleted_at IS NULL ORDER BY chat_id, name LIMIT ? OFFSET ?",
     )
+    .bind(ALL_TASKS_PAGE_SIZE)
+    .bind((page - 1) * ALL_TASKS_PAGE_SIZE)
+    .fetch_all(pool)
+    .await?;
+
+    let tasks = rows
+        .iter()
+        .map(|row| ChatTask {
+            chat_id: row.get("chat_id"),
+            name: row.get("name"),
+            question: row.get("description"),
+            interval: row.get("interval"),
+            paused: row.get("paused"),
+        })
+        .collect();
+    Ok((tasks, total))
 }
 
-fn format_task_list(tasks: &[sqlx::sqlite::SqliteRow]) -> String {
+fn parse_page(args: &str) -> Result<i64, BotError> {
+    match args.trim() {
+        "" => Ok(1),
+        page => page
+            .parse::<i64>()
+            .ok()
+            .filter(|page| *page >= 1)
+            .ok_or(BotError::InvalidParameters),
+    }
+}
+
+fn format_all_tasks(
+    tasks: &[ChatTask],
+    titles: &HashMap<i64, String>,
+    page: i64,
+    total: i64,
+) -> String {
     if tasks.is_empty() {
         return String::from("📭 *No tasks found*");
     }
 
-    let mut formatted = String::from("*📋 Active Tasks:*\n\n");
+    let pages = (total + ALL_TASKS_PAGE_SIZE - 1) / ALL_TASKS_PAGE_SIZE;
+    let mut formatted = format!("*🌐 All Tasks* \\(page {}/{}\\)\n", page, pages);
+    let mut current_chat = None;
 
     for task in tasks {
+        if current_chat != Some(task.chat_id) {
+            current_chat = Some(task.chat_id);
+            let title = titles
+                .get(&task.chat_id)
+                .map(|title| format!("*{}* ", escape_markdown_v2(title)))
+                .unwrap_or_default();
+            formatted.push_str(&format!("\n💬 {}`{}`\n", title, task.chat_id));
+        }
         formatted.push_str(&format!(
-            "🔷 *Task:* {}\n\
-            📝 *Question:* `{}`\n\
-            ⏱ *Interval:* {} minutes\n\
-            🕒 *Last run:* _{}_\n\n",
-            escape_markdown_v2(&task.get::<String, _>("name")),
-            escape_markdown_v2(&task.get::<String, _>("question")),
-            task.get::<i64, _>("interval"),
-            escape_markdown_v2(&task.get::<String, _>("last_run"))
+            "{} *{}* \\- every {} min\n   `{}`\n",
+            if task.paused { "⏸" } else { "🔷" },
+            escape_markdown_v2(&task.name),
+            task.interval,
+            escape_markdown_v2(&task.question)
         ));
     }
 
+    if page < pages {
+        formatted.push_str(&format!("\nNext page: /alltasks {}", page + 1));
+    }
     formatted
 }
 
-async fn create_task(
-    pool: &SqlitePool,
-    name: &str,
-    question: &str,
-    interval: i64,
+/// Best-effort display name of a chat for owner reports.
+async fn fetch_chat_title(bot: &Bot, chat_id: i64) -> Option<String> {
+    let chat = bot.get_chat(ChatId(chat_id)).await.ok()?;
+    chat.title()
+        .or(chat.username())
+        .or(chat.first_name())
+        .map(str::to_string)
+}
+
+const LOG_SEARCH_LIMIT: i64 = 20;
+const SNIPPET_RADIUS: usize = 40;
+
+#[derive(Debug, PartialEq)]
+struct LogSearch {
+    keyword: String,
+    /// First day included in the search
+    from: Option<chrono::NaiveDate>,
+    /// Last day included in the search
+    to: Option<chrono::NaiveDate>,
+}
+
+/// Parses `[--from=YYYY-MM-DD] [--to=YYYY-MM-DD] <keyword>`.
+fn parse_search_command(input: &str) -> Result<LogSearch, BotError> {
+    let mut search = LogSearch {
+        keyword: String::new(),
+        from: None,
+        to: None,
+    };
+    let mut rest = input.trim_start();
+
+    while let Some(flag) = rest.strip_prefix("--") {
+        let end = flag.find(char::is_whitespace).unwrap_or(flag.len());
+        let (key, value) = flag[..end].split_once('=').ok_or(BotError::InvalidParameters)?;
+        let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .map_err(|_| BotError::InvalidParameters)?;
+        match key {
+            "from" => search.from = Some(date),
+            "to" => search.to = Some(date),
+            _ => return Err(BotError::InvalidParameters),
+        }
+        rest = flag[end..].trim_start();
+    }
+
+    search.keyword = rest.trim().to_string();
+    if search.keyword.is_empty() || search.from.zip(search.to).is_some_and(|(from, to)| from > to) {
+        return Err(BotError::InvalidParameters);
+    }
+    Ok(search)
+}
+
+#[derive(Debug, PartialEq)]
+struct LogMatch {
+    timestamp: String,
+    command: String,
+    user_id: Option<i64>,
+    username: Option<String>,
+    snippet: String,
+}
+
+/// Most recent interactions whose arguments or response mention the keyword.
+async fn search_logs(state: &AppState, search: &LogSearch) -> Result<Vec<LogMatch>, sqlx::Error> {
+    let pool = &state.read_pool;
+    let escaped = search
+        .keyword
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let rows = sqlx::query(
+        r#"
+        SELECT timestamp, command, user_id, username, args, response
+        FROM bot_logs
+        WHERE (args LIKE ?1 ESCAPE '\' OR response LIKE ?1 ESCAPE '\')
+          AND (?2 IS NULL OR timestamp >= ?2)
+          AND (?3 IS NULL OR timestamp < ?3)
+        ORDER BY id DESC
+        LIMIT ?4
+        "#,
+    )
+    .bind(format!("%{}%", escaped))
+    .bind(search.from.map(|from| from.to_string()))
+    .bind(search.to.and_then(|to| to.succ_opt()).map(|end| end.to_string()))
+    .bind(LOG_SEARCH_LIMIT)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            let args: Option<String> = row.get("args");
+            let response: Option<String> = row.get("response");
+            let snippet = [args, response]
+                .iter()
+                .flatten()
+                .find_map(|text| extract_snippet(text, &search.keyword, SNIPPET_RADIUS))?;
+            Some(LogMatch {
+                timestamp: row.get("timestamp"),
+                command: row.get("command"),
+                user_id: row.get("user_id"),
+                username: row.get("username"),
+                snippet,
+            })
+        })
+        .collect())
+}
+
+/// Text around the first case-insensitive match of `keyword`, with up to
+/// `radius` characters on each side.
+fn extract_snippet(text: &str, keyword: &str, radius: usize) -> Option<String> {
+    let pattern = Regex::new(&format!("(?i){}", regex::escape(keyword))).ok()?;
+    let found = pattern.find(text)?;
+
+    let start = text[..found.start()]
+        .char_indices()
+        .rev()
+        .nth(radius.saturating_sub(1))
+        .map_or(0, |(index, _)| index);
+    let end = text[found.end()..]
+        .char_indices()
+        .nth(radius)
+        .map_or(text.len(), |(index, _)| found.end() + index);
+
+    let mut snippet = text[start..end].split_whitespace().collect::<Vec<_>>().join(" ");
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if end < text.len() {
+        snippet.push('…');
+    }
+    Some(snippet)
+}
+
+fn format_log_matches(search: &LogSearch, matches: &[LogMatch]) -> String {
+    if matches.is_empty() {
+        return format!(
+            "🔎 No interactions mention *{}*",
+            escape_markdown_v2(&search.keyword)
+        );
+    }
+
+    let mut formatted = format!(
+        "*🔎 Interactions mentioning* `{}`\n\n",
+        escape_code_block(&search.keyword)
+    );
+    for found in matches {
+        let user = match (&found.username, found.user_id) {
+            (Some(username), _) => format!("@{}", username),
+            (None, Some(id)) => id.to_string(),
+            (None, None) => String::from("unknown"),
+        };
+        formatted.push_str(&format!(
+            "🔹 *{}* by {} at _{}_\n{}\n\n",
+            escape_markdown_v2(&found.command),
+            escape_markdown_v2(&user),
+            escape_markdown_v2(&found.timestamp),
+            escape_markdown_v2(&found.snippet)
+        ));
+    }
+    formatted
+}
+
+#[derive(Debug, PartialEq)]
+struct TranscriptEntry {
+    timestamp: String,
+    user_id: Option<i64>,
+    username: Option<String>,
+    command: String,
+    args: Option<String>,
+    response: Option<String>,
+    error: Option<String>,
+}
+
+/// Logged interactions of a chat that carry arguments or a response, oldest
+/// first, optionally limited to one user.
+async fn fetch_transcript(
+    state: &AppState,
     chat_id: i64,
-) -> Result<(), BotError> {
-    sqlx::query(
-        "INSERT INTO tasks (name, description, interval, last_run, chat_id) VALUES (?, ?, ?, ?, ?)",
+    user_id: Option<i64>,
+) -> Result<Vec<TranscriptEntry>, sqlx::Error> {
+    let pool = &state.read_pool;
+    let rows = sqlx::query(
+        r#"
+        SELECT timestamp, user_id, username, command, args, response, error
+        FROM bot_logs
+        WHERE chat_id = ?1
+          AND (?2 IS NULL OR user_id = ?2)
+          AND (args IS NOT NULL OR response IS NOT NULL)
+        ORDER BY timestamp ASC, id ASC
+        "#,
     )
-    .bind(name)
-    .bind(question)
-    .bind(interval)
-    .bind(Utc::now().to_rfc3339())
     .bind(chat_id)
-    .execute(pool)
+    .bind(user_id)
+    .fetch_all(pool)
     .await?;
 
-    Ok(())
+    Ok(rows
+        .iter()
+        .map(|row| TranscriptEntry {
+            timestamp: row.get("timestamp"),
+            user_id: row.get("user_id"),
+            username: row.get("username"),
+            command: row.get("command"),
+            args: row.get("args"),
+            response: row.get("response"),
+            error: row.get("error"),
+        })
+        .collect())
 }
 
-async fn delete_task(pool: &SqlitePool, name: &str, chat_id: i64) -> Result<bool, BotError> {
-    let result = sqlx::query("DELETE FROM tasks WHERE name = ? AND chat_id = ?")
-        .bind(name)
-        .bind(chat_id)
-        .execute(pool)
-        .await?;
+/// Plain-text transcript, one block per interaction.
+fn format_transcript(chat_id: i64, entries: &[TranscriptEntry], exported_at: DateTime<Utc>) -> String {
+    let mut transcript = format!(
+        "Transcript of chat {}\nExported {} with {} interactions\n",
+        chat_id,
+        exported_at.format("%Y-%m-%d %H:%M:%S UTC"),
+        entries.len()
+    );
+    for entry in entries {
+        let time = DateTime::parse_from_rfc3339(&entry.timestamp)
+            .map(|time| time.with_timezone(&Utc).format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_else(|_| entry.timestamp.clone());
+        let user = match (&entry.username, entry.user_id) {
+            (Some(username), _) => format!("@{}", username),
+            (None, Some(id)) => id.to_string(),
+            (None, None) => String::from("unknown"),
+        };
+        transcript.push_str(&format!("\n[{}] {}: /{}", time, user, entry.command.to_lowercase()));
+        match entry.args.as_deref().map(str::trim) {
+            Some(args) if !args.is_empty() => transcript.push_str(&format!(" {}\n", args)),
+            _ => transcript.push('\n'),
+        }
+        if let Some(response) = &entry.response {
+            transcript.push_str(&format!("Bot: {}\n", response.trim()));
+        }
+        if let Some(error) = &entry.error {
+            transcript.push_str(&format!("Error: {}\n", error));
+        }
+    }
+    transcript
+}
 
-    Ok(result.rows_affected() > 0)
+async fn purge_chat_tasks(pool: &SqlitePool, chat_ids: &[i64]) -> Result<u64, sqlx::Error> {
+    let mut deleted = 0;
+    for chat_id in chat_ids {
+        deleted += sqlx::query("DELETE FROM tasks WHERE chat_id = ?")
+            .bind(chat_id)
+            .execute(pool)
+            .await?
+            .rows_affected();
+        sqlx::query("DELETE FROM task_runs WHERE chat_id = ?")
+            .bind(chat_id)
+            .execute(pool)
+            .await?;
+    }
+    Ok(deleted)
 }
 
-async fn try_send_message(bot: &Bot, chat_id: ChatId, message: String) -> Result<(), BotError> {
-    bot.send_message(chat_id, message)
-        .parse_mode(ParseMode::MarkdownV2)
-        .await
-        .map_err(BotError::TelegramError)?;
+/// Longest inactivity /purge_inactive accepts, about a century. Larger values
+/// would overflow the cutoff date.
+const MAX_PURGE_DAYS: i64 = 36500;
+
+fn parse_purge_command(input: &str) -> Option<(i64, bool)> {
+    let mut parts = input.split_whitespace();
+    let days = parts
+        .next()?
+        .parse::<i64>()
+        .ok()
+        .filter(|days| (1..=MAX_PURGE_DAYS).contains(days))?;
+    let confirm = match parts.next() {
+        None => false,
+        Some("confirm") => true,
+        Some(_) => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((days, confirm))
+}
+
+fn format_inactive_chats(chats: &[InactiveChat], days: i64, purged: Option<u64>) -> String {
+    if chats.is_empty() {
+        return format!("✅ *No chats inactive for more than {} days*", days);
+    }
+
+    let mut formatted = format!("*💤 Chats inactive for more than {} days:*\n\n", days);
+    for chat in chats {
+        formatted.push_str(&format!(
+            "🔷 *Chat:* `{}`\n\
+            📋 *Tasks:* {}\n\
+            🕒 *Last activity:* _{}_\n\n",
+            chat.chat_id,
+            chat.task_count,
+            escape_markdown_v2(chat.last_activity.as_deref().unwrap_or("never"))
+        ));
+    }
+
+    match purged {
+        Some(count) => formatted.push_str(&format!("🗑 Deleted *{}* tasks", count)),
+        None => formatted.push_str(&format!(
+            "Run `/purge\\_inactive {} confirm` to delete these tasks\\.",
+            days
+        )),
+    }
+
+    formatted
+}
+
+/// Shortest and longest task intervals, in minutes.
+const MIN_TASK_INTERVAL: i64 = 1;
+const MAX_TASK_INTERVAL: i64 = 30 * 24 * 60;
+
+async fn parse_create_command(input: String) -> Option<(String, u64, String)> {
+    let parts: Vec<&str> = input.trim().splitn(3, ' ').collect();
+    if parts.len() == 3 {
+        let interval = parts[1]
+            .parse::<u64>()
+            .ok()
+            .filter(|interval| (MIN_TASK_INTERVAL as u64..=MAX_TASK_INTERVAL as u64).contains(interval))?;
+        let question = validate_question(parts[2]).ok()?;
+        Some((parts[0].to_string(), interval, question.to_string()))
+    } else {
+        None
+    }
+}
+
+/// Trims a question and rejects it when nothing is left to send to X.AI.
+fn validate_question(question: &str) -> Result<&str, BotError> {
+    let question = question.trim();
+    if question.is_empty() {
+        return Err(BotError::InvalidParameters);
+    }
+    Ok(question)
+}
+
+fn validate_task_name(name: &str, max_len: usize) -> Result<(), BotError> {
+    if name.is_empty() || name.chars().count() > max_len || name.chars().any(char::is_control) {
+        return Err(BotError::InvalidParameters);
+    }
     Ok(())
 }
 
-async fn handle_command(bot: Bot, msg: Message, cmd: Command, state: State) -> ResponseResult<()> {
-    let start_time = std::time::Instant::now();
-    let cmd_str = format!("{:?}", cmd);
-    
-    let user_id = msg.from.as_ref().map(|user| user.id.0.try_into().unwrap());
-    let username = msg.from.as_ref().and_then(|user| user.username.clone());
+fn format_xai_response(task_name: Option<&str>, question: &str, response: &str) -> String {
+    wrap_xai_response(task_name, question, &format_response_content(response))
+}
 
-    let result = async {
-        match cmd {
-            Command::Create(args) => {
-                match parse_create_command(args).await {
-                    Some((name, interval, question)) => {
-                        call_xai_api(&state, &question).await?;
-                        
-                        create_task(&state.pool, &name, &question, interval as i64, msg.chat.id.0).await?;
-                        
-                        let create_message = format!(
-                            "✅ *Task Created Successfully*\n\n\
-                            📌 *Name:* {}\n\
-                            ❓ *Question:* `{}`\n\
-                            ⏱ *Interval:* {} minutes\n\n\
-                            🔄 First response coming shortly\\.\\.\\.",
-                            escape_markdown_v2(&name), 
-                            escape_markdown_v2(&question), 
-                            interval
-                        );
-                        
-                        try_send_message(&bot, msg.chat.id, create_message).await?;
+/// Puts an already formatted answer under the question it answers.
+fn wrap_xai_response(task_name: Option<&str>, question: &str, content: &str) -> String {
+    match task_name {
+        Some(name) => format!(
+            "🤖 *Task Response*\n\n\
+            📌 *Task:* {}\n\
+            ❓ *Question:* `{}`\n\n\
+            📝 *Answer:*\n\n{}",
+            escape_markdown_v2(name),
+            escape_markdown_v2(question),
+            content
+        ),
+        None => format!(
+            "🤖 *X\\.AI Response*\n\n\
+            ❓ *Question:* `{}`\n\n\
+            📝 *Answer:*\n\n{}",
+            escape_markdown_v2(question),
+            content
+        ),
+    }
+}
+
+/// Canned answer for `/rendertest`, written the way models tend to reply so
+/// escaping changes can be checked by eye in a real Telegram client.
+const RENDER_TEST_SAMPLE: &str = "# Render test
+
+Plain text with reserved characters: 1.5 + 2 = 3.5 (really!) {braces} [brackets] #hash ~tilde > quote | pipe.
+
+**Bold**, *italic*, __underlined italic__ and `inline code` with `a_b*c` inside.
+
+## Lists
+- First item with **bold**
+- Second item with `code`
+* Third item, starred
+1. Numbered item
+2. Another numbered item
+
+### Links
+See [the Rust book](https://doc.rust-lang.org/book/) or https://example.com/path_with_underscores?a=1&b=2.
+
+| Column | Value |
+|--------|-------|
+| alpha  | 1.0   |
+| beta   | -2    |
+
+```
+fn main() {
+    println!(\"code block with *stars* and _underscores_\");
+}
+```";
+
+/// Rough number of characters per token, used to turn `--maxlen` into a length.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Formats a task's answer and, as a safety net for models that overshoot
+/// `max_tokens`, cuts it to the length the limit allows.
+fn format_task_content(content: &str, max_tokens: Option<i64>) -> String {
+    let formatted = format_response_content(content);
+    match max_tokens {
+        Some(max_tokens) => truncate_markdown(&formatted, max_tokens as usize * CHARS_PER_TOKEN),
+        None => formatted,
+    }
+}
+
+
+fn format_response_content(content: &str) -> String {
+    // A bullet needs a space after it, so `**bold**` at the start of a line stays bold
+    let is_list_item = |line: &str| {
+        let trimmed = line.trim();
+        trimmed == "-" || trimmed.starts_with("- ") || trimmed.starts_with("* ")
+    };
 
-                        if let Ok(initial_response) = call_xai_api(&state, &question).await {
-                            let formatted_response = format_xai_response(Some(&name), &question, &initial_response);
-                            try_send_message(&bot, msg.chat.id, formatted_response).await?;
+    content
+        .split("\n\n")
+        .map(|paragraph| {
+            // Handle lists and headers line by line
+            if paragraph
+                .lines()
+                .any(|line| is_list_item(line) || parse_markdown_header(line).is_some())
+            {
+                paragraph
+                    .lines()
+                    .map(|line| {
+                        if let Some(title) = parse_markdown_header(line) {
+                            format!("*{}*", escape_markdown_v2(title))
+                        } else if is_list_item(line) {
+                            let content = line.trim()[1..].trim();
+                            format!("• {}", process_markdown_formatting(content))
+                        } else {
+                            process_markdown_formatting(line)
                         }
-                    }
-                    None => return Err(BotError::InvalidParameters),
-                }
-            },
-            Command::List => {
-                let tasks = sqlx::query(
-                    "SELECT name, description as question, interval, last_run FROM tasks WHERE chat_id = ?"
-                )
-                .bind(msg.chat.id.0)
-                .fetch_all(&state.pool)
-                .await?;
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            } else {
+                process_markdown_formatting(paragraph)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
 
-                let message = format_task_list(&tasks);
-                try_send_message(&bot, msg.chat.id, message).await?;
-            },
-            Command::Delete(name) => {
-                if delete_task(&state.pool, &name, msg.chat.id.0).await? {
-                    try_send_message(
-                        &bot, 
-                        msg.chat.id, 
-                        format!("✅ Task *{}* deleted successfully", escape_markdown_v2(&name))
-                    ).await?;
-                } else {
-                    return Err(BotError::TaskNotFound);
-                }
-            },
-            Command::Ask(question) => {
-                let response = call_xai_api(&state, &question).await?;
-                let formatted = format_xai_response(None, &question, &response);
-                try_send_message(&bot, msg.chat.id, formatted).await?;
-            },
-            Command::Help => {
-                try_send_message(&bot, msg.chat.id, format_help_message()).await?;
-            },
-            Command::MyId => {
-                if let Some(user) = &msg.from {
-                    let is_creator = user.id.0 as i64 == state.owner_id;  // Simplified check
-                    let user_info = format!(
-                        "👤 *Your Telegram Info:*\n\n\
-                        🆔 *User ID:* `{}`\n\
-                        📝 *Username:* @{}\n\
-                        👑 *Bot Owner:* {}\n",
-                        user.id,
-                        user.username.as_deref().unwrap_or("none"),
-                        if is_creator { "Yes ✅" } else { "No ❌" }
-                    );
-                    try_send_message(&bot, msg.chat.id, user_info).await?;
+/// Returns the title of a `#` to `######` header line. MarkdownV2 has no
+/// headers, so callers render the title in bold instead.
+fn parse_markdown_header(line: &str) -> Option<&str> {
+    let trimmed = line.trim();
+    let title = trimmed.trim_start_matches('#');
+    let level = trimmed.len() - title.len();
+    if !(1..=6).contains(&level) || !title.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let title = title.trim().trim_end_matches('#').trim();
+    (!title.is_empty()).then_some(title)
+}
+
+/// Parses a `[label](url)` link from the characters following an opening `[`.
+/// Returns the label, the URL and the number of characters the link spans.
+fn parse_markdown_link(mut chars: impl Iterator<Item = char>) -> Option<(String, String, usize)> {
+    let mut consumed = 0;
+
+    let mut label = String::new();
+    loop {
+        let c = chars.next()?;
+        consumed += 1;
+        match c {
+            ']' => break,
+            '[' | '\n' => return None,
+            _ => label.push(c),
+        }
+    }
+    if label.trim().is_empty() || chars.next()? != '(' {
+        return None;
+    }
+    consumed += 1;
+
+    let mut url = String::new();
+    let mut depth = 0;
+    loop {
+        let c = chars.next()?;
+        consumed += 1;
+        match c {
+            ')' if depth == 0 => break,
+            ')' => depth -= 1,
+            '(' => depth += 1,
+            c if c.is_whitespace() => return None,
+            _ => {}
+        }
+        url.push(c);
+    }
+
+    let is_url = ["http://", "https://", "tg://", "mailto:"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme));
+    is_url.then_some((label, url, consumed))
+}
+
+fn escape_link_url(url: &str) -> String {
+    // Inside the (...) part of an inline link only ')' and '\' must be escaped
+    let mut result = String::with_capacity(url.len());
+    for c in url.chars() {
+        if c == ')' || c == '\\' {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+fn process_markdown_formatting(text: &str) -> String {
+    let mut result = String::with_capacity(text.len() * 2);
+    let mut chars = text.chars().peekable();
+    let mut in_format = None; // None, Some("bold"), Some("italic"), Some("code")
+    let mut current_text = String::new();
+
+    while let Some(c) = chars.next() {
+        // Code spans are literal until the closing backtick
+        if in_format == Some("code") && c != '`' {
+            current_text.push(c);
+            continue;
+        }
+
+        match c {
+            '*' | '_' | '`' => {
+                let format_type = match c {
+                    '*' => "bold",
+                    '_' => "italic",
+                    '`' => "code",
+                    _ => unreachable!(),
+                };
+
+                // Count consecutive formatting characters
+                let mut count = 1;
+                while chars.peek() == Some(&c) {
+                    count += 1;
+                    chars.next();
                 }
-            },
-            Command::BotStats => {
-                if let Some(user_id) = user_id {
-                    if user_id == state.owner_id {  // Direct comparison
-                        match get_command_stats(&state.pool).await {
-                            Ok(stats) => {
-                                let formatted_stats = format_bot_stats(&stats);
-                                try_send_message(&bot, msg.chat.id, formatted_stats).await?;
-                            }
-                            Err(e) => {
-                                log::error!("Failed to get bot stats: {}", e);
-                                return Err(BotError::DatabaseError(e));
-                            }
+
+                // If we have accumulated text, escape and add it
+                if !current_text.is_empty() {
+                    result.push_str(&escape_span_text(&current_text, in_format));
+                    current_text.clear();
+                }
+
+                // Handle formatting markers
+                match (in_format, count) {
+                    (None, _) => {
+                        // Start formatting
+                        in_format = Some(format_type);
+                        // Add the formatting characters without escaping
+                        for _ in 0..count {
+                            result.push(c);
                         }
-                    } else {
-                        return Err(BotError::PermissionDenied);
                     }
-                }
-            },
-            Command::Stats => {
-                if let Some(user_id) = user_id {
-                    match get_user_stats(&state.pool, user_id).await {
-                        Ok(stats) => {
-                            let formatted_stats = format_user_stats(&stats);
-                            try_send_message(&bot, msg.chat.id, formatted_stats).await?;
+                    (Some(current_type), _) if current_type == format_type => {
+                        // End formatting
+                        in_format = None;
+                        // Add the formatting characters without escaping
+                        for _ in 0..count {
+                            result.push(c);
                         }
-                        Err(e) => {
-                            log::error!("Failed to get user stats: {}", e);
-                            return Err(BotError::DatabaseError(e));
+                    }
+                    _ => {
+                        // Mismatched formatting or nested formats - escape the characters
+                        for _ in 0..count {
+                            result.push('\\');
+                            result.push(c);
                         }
                     }
                 }
+            }
+            '[' => match parse_markdown_link(chars.clone()) {
+                Some((label, url, consumed)) => {
+                    if !current_text.is_empty() {
+                        result.push_str(&escape_non_formatting_chars(&current_text));
+                        current_text.clear();
+                    }
+                    result.push_str(&format!(
+                        "[{}]({})",
+                        escape_markdown_v2(&label),
+                        escape_link_url(&url)
+                    ));
+                    for _ in 0..consumed {
+                        chars.next();
+                    }
+                }
+                // Stray bracket, escaped along with the surrounding text
+                None => current_text.push(c),
             },
+            _ => {
+                current_text.push(c);
+            }
         }
-        Ok(())
-    }.await;
+    }
 
-    // Log the interaction after command execution
-    if let Some(uid) = user_id {
-        let _ = log_interaction(
-            &state.pool,
-            msg.chat.id.0,
-            Some(uid),
-            username,
-            &cmd_str,
-            None,
-            None,
-            result.as_ref().err().map(|e| e.to_string()).as_deref(),
-            start_time.elapsed(),
+    // Handle any remaining text
+    if !current_text.is_empty() {
+        result.push_str(&escape_span_text(&current_text, in_format));
+    }
+
+    result
+}
+
+fn escape_span_text(text: &str, in_format: Option<&str>) -> String {
+    if in_format == Some("code") {
+        escape_code_block(text)
+    } else {
+        escape_non_formatting_chars(text)
+    }
+}
+
+struct CachedResponse {
+    response: String,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+/// X.AI answers keyed by question. Entries expire after `ttl` and the least
+/// recently used entry is evicted once `max_entries` is reached.
+struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: HashMap<String, CachedResponse>,
+    clock: u64,
+}
+
+impl ResponseCache {
+    fn new(ttl: Duration, max_entries: usize) -> Self {
+        ResponseCache {
+            ttl,
+            max_entries,
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str, now: Instant) -> Option<String> {
+        let expired = now.duration_since(self.entries.get(key)?.inserted_at) >= self.ttl;
+        if expired {
+            self.entries.remove(key);
+            return None;
+        }
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        Some(entry.response.clone())
+    }
+
+    fn insert(&mut self, key: &str, response: &str, now: Instant) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, entry| now.duration_since(entry.inserted_at) < ttl);
+
+        while self.entries.len() >= self.max_entries && !self.entries.contains_key(key) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+
+        self.clock += 1;
+        self.entries.insert(
+            key.to_string(),
+            CachedResponse {
+                response: response.to_string(),
+                inserted_at: now,
+                last_used: self.clock,
+            },
+        );
+    }
+}
+
+async fn call_xai_api_cached(state: &AppState, question: &str, options: &XaiOptions) -> Result<String> {
+    let key = options.cache_key(question);
+    if let Some(response) = state.response_cache.lock().unwrap().get(&key, Instant::now()) {
+        log::debug!("Answering from cache: {}", key);
+        return Ok(response);
+    }
+
+    let response = call_xai_api(state, question, options).await?;
+    state
+        .response_cache
+        .lock()
+        .unwrap()
+        .insert(&key, &response, Instant::now());
+    Ok(response)
+}
+
+/// Per-request overrides given as leading `--key=value` flags.
+#[derive(Debug, Default, PartialEq)]
+struct InlineOptions {
+    /// Model used instead of the user's preference or the configured one.
+    model: Option<String>,
+    temperature: Option<f64>,
+    /// Language code the answer should be written in.
+    language: Option<String>,
+    /// Ask for the answer as a JSON document instead of prose.
+    json: bool,
+    /// Task-only: group the task is run and reported with.
+    group: Option<String>,
+    /// Task-only: tasks with a higher priority are dispatched first.
+    priority: Option<i64>,
+    /// Task-only: run the task once whenever the bot starts.
+    run_on_startup: Option<bool>,
+    /// Task-only: pin each new answer in the chat.
+    pin: Option<bool>,
+    /// Task-only: channel (`@username` or id) the answers are posted to.
+    channel: Option<String>,
+    /// Task-only: labels used to pause or resume related tasks together.
+    tags: Vec<String>,
+    /// Task-only: most tokens an answer may use, 0 removes the limit.
+    max_tokens: Option<i64>,
+}
+
+/// Splits leading `--key=value` flags off a command's arguments, returning
+/// the parsed options and the remaining text.
+fn parse_inline_options(input: &str) -> Result<(InlineOptions, String), BotError> {
+    let mut options = InlineOptions::default();
+    let mut rest = input.trim_start();
+
+    while let Some(flag) = rest.strip_prefix("--") {
+        let end = flag.find(char::is_whitespace).unwrap_or(flag.len());
+        let (key, value) = flag[..end].split_once('=').unwrap_or((&flag[..end], ""));
+        match key.to_lowercase().as_str() {
+            "model" => options.model = Some(parse_model(value)?),
+            "temp" | "temperature" => options.temperature = Some(parse_temperature(value)?),
+            "lang" | "language" => options.language = Some(parse_language(value)?),
+            "json" if value.is_empty() => options.json = true,
+            "group" if !value.is_empty() => options.group = Some(value.to_string()),
+            "tag" => {
+                let tag = parse_tag(value)?;
+                if !options.tags.contains(&tag) {
+                    options.tags.push(tag);
+                }
+            }
+            "priority" => {
+                options.priority = Some(value.parse().map_err(|_| BotError::InvalidParameters)?)
+            }
+            "startup" => options.run_on_startup = Some(parse_switch(value)?),
+            "pin" => options.pin = Some(parse_switch(value)?),
+            "channel" if !value.is_empty() => options.channel = Some(value.to_string()),
+            "maxlen" => options.max_tokens = Some(parse_max_tokens(value)?),
+            _ => return Err(BotError::InvalidParameters),
+        }
+        rest = flag[end..].trim_start();
+    }
+
+    Ok((options, rest.to_string()))
+}
+
+/// Model names are passed to X.AI as given, so only plain identifiers are accepted.
+fn parse_model(value: &str) -> Result<String, BotError> {
+    let model = value.trim();
+    if model.is_empty()
+        || model.len() > 64
+        || !model
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(BotError::InvalidParameters);
+    }
+    Ok(model.to_string())
+}
+
+/// Highest `--maxlen` a task can be given, in tokens.
+const MAX_TASK_MAX_TOKENS: i64 = 4096;
+
+fn parse_max_tokens(value: &str) -> Result<i64, BotError> {
+    value
+        .trim()
+        .parse::<i64>()
+        .ok()
+        .filter(|max_tokens| (0..=MAX_TASK_MAX_TOKENS).contains(max_tokens))
+        .ok_or(BotError::InvalidParameters)
+}
+
+/// Value of an on/off flag, where a bare `--flag` means on.
+fn parse_switch(value: &str) -> Result<bool, BotError> {
+    match value.to_lowercase().as_str() {
+        "" | "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(BotError::InvalidParameters),
+    }
+}
+
+/// Tags are lowercase letters, digits, `-` and `_`, so they can be stored comma-separated.
+fn parse_tag(value: &str) -> Result<String, BotError> {
+    let tag = value.trim().to_lowercase();
+    if tag.is_empty()
+        || !tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(BotError::InvalidParameters);
+    }
+    Ok(tag)
+}
+
+fn parse_temperature(value: &str) -> Result<f64, BotError> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|temperature| (0.0..=2.0).contains(temperature))
+        .ok_or(BotError::InvalidParameters)
+}
+
+/// Languages answers can be requested in, by ISO 639-1 code.
+const SUPPORTED_LANGUAGES: &[(&str, &str)] = &[
+    ("ar", "Arabic"),
+    ("de", "German"),
+    ("en", "English"),
+    ("es", "Spanish"),
+    ("fr", "French"),
+    ("hi", "Hindi"),
+    ("id", "Indonesian"),
+    ("it", "Italian"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+    ("nl", "Dutch"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("ru", "Russian"),
+    ("sw", "Swahili"),
+    ("tr", "Turkish"),
+    ("uk", "Ukrainian"),
+    ("zh", "Chinese"),
+];
+
+fn language_name(code: &str) -> Option<&'static str> {
+    SUPPORTED_LANGUAGES
+        .iter()
+        .find(|(supported, _)| *supported == code)
+        .map(|(_, name)| *name)
+}
+
+fn parse_language(value: &str) -> Result<String, BotError> {
+    let code = value.trim().to_lowercase();
+    language_name(&code).ok_or(BotError::InvalidParameters)?;
+    Ok(code)
+}
+
+/// Inline flags win over the user's preferences, then the chat's settings,
+/// then the global defaults.
+fn resolve_xai_options(
+    inline: &InlineOptions,
+    user: &UserPrefs,
+    chat: &ChatSettings,
+    config: &Config,
+) -> XaiOptions {
+    XaiOptions {
+        model: inline
+            .model
+            .clone()
+            .or_else(|| user.model.clone())
+            .unwrap_or_else(|| config.xai_model.clone()),
+        temperature: inline
+            .temperature
+            .or(user.temperature)
+            .or(chat.temperature)
+            .unwrap_or(config.default_temperature),
+        language: inline.language.clone().or_else(|| chat.language.clone()),
+        style: user.style.clone(),
+        json: inline.json,
+        // Only tasks limit their answers, see `ScheduledTask::xai_options`
+        max_tokens: None,
+    }
+}
+
+/// Options for a request in a chat. Scheduled tasks have no user, so only
+/// commands typed by someone pass a `user_id`.
+async fn xai_options_for_chat(
+    state: &AppState,
+    chat_id: i64,
+    user_id: Option<i64>,
+    inline: &InlineOptions,
+) -> Result<XaiOptions, BotError> {
+    let chat = get_chat_settings(&state.pool, chat_id).await?;
+    let user = match user_id {
+        Some(user_id) => get_user_prefs(&state.pool, user_id).await?,
+        None => UserPrefs::default(),
+    };
+    Ok(resolve_xai_options(inline, &user, &chat, &state.config))
+}
+
+/// A user's own defaults, applied in every chat they ask in.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct UserPrefs {
+    model: Option<String>,
+    temperature: Option<f64>,
+    /// Extra instruction on how answers should be written, e.g. "short bullet points".
+    style: Option<String>,
+}
+
+/// Longest answer style accepted by /setpref, in characters.
+const MAX_STYLE_LEN: usize = 200;
+
+/// A single change made with /setpref, `None` restores the default.
+#[derive(Debug, PartialEq)]
+enum UserPref {
+    Model(Option<String>),
+    Temperature(Option<f64>),
+    Style(Option<String>),
+}
+
+/// Parses `<model|temp|style> <value|default>`.
+fn parse_pref_command(input: &str) -> Result<UserPref, BotError> {
+    let (key, value) = input
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or(BotError::InvalidParameters)?;
+    let value = Some(value.trim()).filter(|value| *value != "default");
+    match key.to_lowercase().as_str() {
+        "model" => Ok(UserPref::Model(value.map(parse_model).transpose()?)),
+        "temp" | "temperature" => Ok(UserPref::Temperature(value.map(parse_temperature).transpose()?)),
+        "style" => match value {
+            Some(style) if style.chars().count() > MAX_STYLE_LEN => Err(BotError::InvalidParameters),
+            style => Ok(UserPref::Style(style.map(str::to_string))),
+        },
+        _ => Err(BotError::InvalidParameters),
+    }
+}
+
+async fn get_user_prefs(pool: &SqlitePool, user_id: i64) -> Result<UserPrefs, sqlx::Error> {
+    let prefs = sqlx::query("SELECT model, temperature, style FROM user_prefs WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .map(|row| UserPrefs {
+            model: row.get("model"),
+            temperature: row.get("temperature"),
+            style: row.get("style"),
+        })
+        .unwrap_or_default();
+    Ok(prefs)
+}
+
+async fn set_user_pref(pool: &SqlitePool, user_id: i64, pref: &UserPref) -> Result<(), sqlx::Error> {
+    let column = match pref {
+        UserPref::Model(_) => "model",
+        UserPref::Temperature(_) => "temperature",
+        UserPref::Style(_) => "style",
+    };
+    let query = format!(
+        "INSERT INTO user_prefs (user_id, {0}) VALUES (?, ?) \
+         ON CONFLICT(user_id) DO UPDATE SET {0} = excluded.{0}",
+        column
+    );
+    let query = sqlx::query(&query).bind(user_id);
+    let query = match pref {
+        UserPref::Model(value) | UserPref::Style(value) => query.bind(value.clone()),
+        UserPref::Temperature(value) => query.bind(*value),
+    };
+    query.execute(pool).await?;
+    Ok(())
+}
+
+fn format_user_prefs(prefs: &UserPrefs) -> String {
+    let or_default = |value: Option<String>| value.unwrap_or_else(|| String::from("chat or bot default"));
+    format!(
+        "*👤 Your Preferences*\n\n\
+        🤖 *Model:* {}\n\
+        🌡 *Temperature:* {}\n\
+        ✍️ *Style:* {}",
+        escape_markdown_v2(&or_default(prefs.model.clone())),
+        escape_markdown_v2(&or_default(prefs.temperature.map(|t| t.to_string()))),
+        escape_markdown_v2(&prefs.style.clone().unwrap_or_else(|| String::from("none")))
+    )
+}
+
+const XAI_API_URL: &str = "https://api.x.ai/v1/chat/completions";
+
+const SYSTEM_PROMPT: &str = "You are a helpful assistant. When formatting responses:
+                    - Use *word* for bold text (surround text with single asterisks)
+                    - Start list items with - or *
+                    - Keep responses clear and structured
+                    - Separate paragraphs with blank lines
+                    
+                    Example format:
+                    Here are the prices:
+                    - *Bitcoin (BTC)*: The price is $50,000
+                    - *Ethereum (ETH)*: The price is $3,000";
+
+/// Model parameters for a single X.AI request, after all defaults are applied.
+#[derive(Debug, Clone, PartialEq)]
+struct XaiOptions {
+    model: String,
+    temperature: f64,
+    language: Option<String>,
+    /// How the user likes answers written, added to the system prompt.
+    style: Option<String>,
+    /// Request a JSON object instead of prose.
+    json: bool,
+    /// Upper bound on the answer's length, in tokens.
+    max_tokens: Option<i64>,
+}
+
+impl XaiOptions {
+    /// Key under which an answer produced with these options can be cached.
+    fn cache_key(&self, question: &str) -> String {
+        format!(
+            "{}|{}|{}|{}|{}|{}|{}",
+            self.model,
+            self.temperature,
+            self.language.as_deref().unwrap_or_default(),
+            self.style.as_deref().unwrap_or_default(),
+            self.json,
+            self.max_tokens.unwrap_or_default(),
+            question.trim()
         )
-        .await
-        .map_err(|e| log::error!("Failed to log interaction: {}", e));
+    }
+}
+
+fn system_prompt(language: Option<&str>) -> String {
+    match language.and_then(language_name) {
+        Some(name) => format!(
+            "{}\n\nAlways respond in {}, regardless of the language of the question.",
+            SYSTEM_PROMPT, name
+        ),
+        None => SYSTEM_PROMPT.to_string(),
+    }
+}
+
+fn build_xai_request_body(question: &str, options: &XaiOptions) -> Value {
+    let mut prompt = system_prompt(options.language.as_deref());
+    if let Some(style) = &options.style {
+        prompt.push_str(&format!("\n\nThe user prefers answers in this style: {}", style));
+    }
+    // Appended last, so language and style still apply but the format is JSON
+    if options.json {
+        prompt.push_str("\n\n");
+        prompt.push_str(JSON_INSTRUCTION);
+    }
+    let mut body = json!({
+        "messages": build_xai_messages(&prompt, &[ChatTurn::user(question)], XAI_CONTEXT_TOKENS),
+        "model": options.model,
+        "stream": false,
+        "temperature": options.temperature
+    });
+    if options.json {
+        body["response_format"] = json!({ "type": "json_object" });
+    }
+    if let Some(max_tokens) = options.max_tokens {
+        body["max_tokens"] = json!(max_tokens);
+    }
+    body
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChatRole {
+    User,
+    Assistant,
+}
+
+/// One message of a conversation sent to X.AI, after the system prompt.
+#[derive(Debug, Clone, PartialEq)]
+struct ChatTurn {
+    role: ChatRole,
+    content: String,
+}
+
+impl ChatTurn {
+    fn user(content: &str) -> Self {
+        ChatTurn {
+            role: ChatRole::User,
+            content: content.to_string(),
+        }
+    }
+
+    fn role_name(&self) -> &'static str {
+        match self.role {
+            ChatRole::User => "user",
+            ChatRole::Assistant => "assistant",
+        }
+    }
+}
+
+/// Rough token count of a text, rounded up.
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Newest turns whose estimated tokens fit in `budget`. The last turn, the
+/// question being asked, is always kept, and the history never starts with an
+/// answer whose question was dropped.
+fn trim_history(turns: &[ChatTurn], budget: usize) -> &[ChatTurn] {
+    let Some(last) = turns.len().checked_sub(1) else {
+        return turns;
+    };
+    let mut used = estimate_tokens(&turns[last].content);
+    let mut start = last;
+    while start > 0 {
+        let tokens = estimate_tokens(&turns[start - 1].content);
+        if used + tokens > budget {
+            break;
+        }
+        used += tokens;
+        start -= 1;
+    }
+    if start < last && turns[start].role == ChatRole::Assistant {
+        start += 1;
+    }
+    if start > 0 {
+        log::debug!("Dropped {} of {} conversation turns to fit {} tokens", start, turns.len(), budget);
+    }
+    &turns[start..]
+}
+
+/// Estimated tokens the system prompt and conversation may take up in a
+/// request. Requests only carry the current question for now, which is always
+/// sent, so this starts to matter once conversation history is kept.
+const XAI_CONTEXT_TOKENS: usize = 8000;
+
+/// The `messages` of a request: the system prompt followed by as much of the
+/// conversation as the token budget leaves room for.
+fn build_xai_messages(system_prompt: &str, turns: &[ChatTurn], budget: usize) -> Vec<Value> {
+    let history_budget = budget.saturating_sub(estimate_tokens(system_prompt));
+    let mut messages = vec![json!({ "role": "system", "content": system_prompt })];
+    messages.extend(
+        trim_history(turns, history_budget)
+            .iter()
+            .map(|turn| json!({ "role": turn.role_name(), "content": turn.content })),
+    );
+    messages
+}
+
+/// Appended to the system prompt when the answer must be JSON, after the language and
+/// style instructions so those still apply.
+const JSON_INSTRUCTION: &str =
+    "Ignore the formatting rules above and answer only with a single valid JSON document. \
+     Do not add explanations, Markdown or code fences around it.";
+
+/// Appended to the question when the first JSON answer didn't parse.
+const STRICT_JSON_INSTRUCTION: &str =
+    "Your previous answer was not valid JSON. Reply with nothing but one valid JSON value: \
+     no prose, no comments, no trailing commas and no code fences.";
+
+#[derive(Debug, PartialEq)]
+enum JsonAnswerCheck {
+    /// The answer parsed, pretty-printed for sending
+    Valid(String),
+    Retry,
+    GiveUp,
+}
+
+/// Validates an answer requested as JSON. Code fences around the document are
+/// tolerated; anything else that doesn't parse is retried once.
+fn check_json_answer(content: &str, attempt: u32) -> JsonAnswerCheck {
+    let trimmed = content.trim();
+    let unfenced = trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|inner| inner.trim_end().strip_suffix("```"))
+        .unwrap_or(trimmed);
+
+    match serde_json::from_str::<Value>(unfenced) {
+        Ok(value) => JsonAnswerCheck::Valid(
+            serde_json::to_string_pretty(&value).unwrap_or_else(|_| unfenced.trim().to_string()),
+        ),
+        Err(_) if attempt == 0 => JsonAnswerCheck::Retry,
+        Err(_) => JsonAnswerCheck::GiveUp,
+    }
+}
+
+/// Asks for a JSON answer, retrying once with a stricter instruction if the
+/// first one doesn't parse.
+async fn call_xai_api_json(state: &AppState, question: &str, options: &XaiOptions) -> Result<String, BotError> {
+    let mut prompt = question.to_string();
+    for attempt in 0.. {
+        let content = call_xai_api(state, &prompt, options).await?;
+        match check_json_answer(&content, attempt) {
+            JsonAnswerCheck::Valid(json) => return Ok(json),
+            JsonAnswerCheck::Retry => {
+                log::warn!("X.AI answer is not valid JSON, retrying: {}", content);
+                prompt = format!("{}\n\n{}", question, STRICT_JSON_INSTRUCTION);
+            }
+            JsonAnswerCheck::GiveUp => break,
+        }
+    }
+    Err(BotError::InvalidJsonAnswer)
+}
+
+fn format_json_answer(json: &str) -> String {
+    format!("```json\n{}\n```", escape_code_block(json))
+}
+
+/// A completion returned by X.AI.
+#[derive(Debug, Clone, PartialEq)]
+struct XaiAnswer {
+    content: String,
+    total_tokens: Option<i64>,
+    /// Model that produced the answer, as reported by X.AI.
+    model: String,
+}
+
+async fn call_xai_api(state: &AppState, question: &str, options: &XaiOptions) -> Result<String> {
+    Ok(call_xai_api_with_usage(state, question, options).await?.content)
+}
+
+async fn call_xai_api_with_usage(
+    state: &AppState,
+    question: &str,
+    options: &XaiOptions,
+) -> Result<XaiAnswer> {
+    let started = Instant::now();
+    let request = build_xai_request(state, question, options)?;
+    let response = state.http_client.execute(request).await?;
+    let status = response.status();
+    let body = response.text().await?;
+    state.xai_latency.lock().unwrap().record(started.elapsed());
+
+    Ok(parse_xai_response(status, &body, &options.model)?)
+}
+
+/// The exact HTTP request sent to X.AI for a question.
+fn build_xai_request(state: &AppState, question: &str, options: &XaiOptions) -> reqwest::Result<reqwest::Request> {
+    state
+        .http_client
+        .post(XAI_API_URL)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", state.xai_token))
+        .json(&build_xai_request_body(question, options))
+        .build()
+}
+
+/// Renders a request for /payload, with the Authorization header masked.
+fn format_xai_payload(request: &reqwest::Request) -> String {
+    let headers: serde_json::Map<String, Value> = request
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = if *name == reqwest::header::AUTHORIZATION {
+                String::from("Bearer <omitted>")
+            } else {
+                value.to_str().unwrap_or_default().to_string()
+            };
+            (name.to_string(), Value::String(value))
+        })
+        .collect();
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .and_then(|bytes| serde_json::from_slice::<Value>(bytes).ok())
+        .unwrap_or(Value::Null);
+    let payload = json!({
+        "method": request.method().as_str(),
+        "url": request.url().as_str(),
+        "headers": headers,
+        "body": body,
+    });
+    format!(
+        "*🔍 X\\.AI request preview*\n```json\n{}\n```",
+        escape_code_block(&serde_json::to_string_pretty(&payload).unwrap_or_default())
+    )
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum XaiResponse {
+    Completion(XaiCompletion),
+    Error { error: XaiErrorBody },
+}
+
+#[derive(Debug, Deserialize)]
+struct XaiCompletion {
+    model: Option<String>,
+    choices: Vec<XaiChoice>,
+    usage: Option<XaiUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct XaiChoice {
+    message: XaiMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct XaiMessage {
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct XaiUsage {
+    total_tokens: Option<i64>,
+}
+
+/// X.AI reports errors either as a plain string or as an OpenAI-style object.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum XaiErrorBody {
+    Message(String),
+    Detailed {
+        message: String,
+        #[serde(rename = "type")]
+        kind: Option<String>,
+    },
+}
+
+#[derive(Debug, Error, PartialEq)]
+enum XaiResponseError {
+    #[error("X.AI returned an error (HTTP {status}): {message}")]
+    Api { status: u16, message: String },
+
+    #[error("Unexpected X.AI response (HTTP {status}): {detail}")]
+    UnexpectedShape { status: u16, detail: String },
+}
+
+/// Pauses between attempts of the X.AI call that checks a new task's question,
+/// so a brief outage doesn't reject the /create.
+const CREATE_VALIDATION_BACKOFF: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(4),
+];
+
+/// Failures worth retrying: network errors, rate limiting and server errors.
+/// Anything else, such as a rejected model, fails the same way again.
+fn is_transient_xai_error(error: &anyhow::Error) -> bool {
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        return e.is_timeout() || e.is_connect() || e.is_request() || e.is_body();
+    }
+    match error.downcast_ref::<XaiResponseError>() {
+        Some(XaiResponseError::Api { status, .. } | XaiResponseError::UnexpectedShape { status, .. }) => {
+            *status == 429 || *status >= 500
+        }
+        None => false,
+    }
+}
+
+/// Runs `call`, retrying transient failures after each of the `backoff`
+/// delays before giving up with the last error.
+async fn retry_transient_xai<T, F, Fut>(backoff: &[Duration], mut call: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut delays = backoff.iter();
+    loop {
+        match call().await {
+            Ok(value) => return Ok(value),
+            Err(e) if is_transient_xai_error(&e) => match delays.next() {
+                Some(delay) => {
+                    log::warn!("X.AI call failed, retrying in {:?}: {}", delay, e);
+                    sleep(*delay).await;
+                }
+                None => return Err(e),
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Longest part of an unexpected response body kept in the error.
+const XAI_BODY_SNIPPET_LEN: usize = 200;
+
+/// Turns an X.AI response body into an answer, or an error describing what
+/// came back instead.
+fn parse_xai_response(
+    status: reqwest::StatusCode,
+    body: &str,
+    requested_model: &str,
+) -> Result<XaiAnswer, XaiResponseError> {
+    let unexpected = |detail: String| XaiResponseError::UnexpectedShape {
+        status: status.as_u16(),
+        detail,
+    };
+    let snippet = || body.chars().take(XAI_BODY_SNIPPET_LEN).collect::<String>();
+
+    match serde_json::from_str::<XaiResponse>(body) {
+        Ok(XaiResponse::Completion(completion)) => {
+            let content = completion
+                .choices
+                .into_iter()
+                .next()
+                .and_then(|choice| choice.message.content)
+                .ok_or_else(|| unexpected(format!("completion without content: {}", snippet())))?;
+            Ok(XaiAnswer {
+                content,
+                total_tokens: completion.usage.and_then(|usage| usage.total_tokens),
+                model: completion.model.unwrap_or_else(|| requested_model.to_string()),
+            })
+        }
+        Ok(XaiResponse::Error { error }) => Err(XaiResponseError::Api {
+            status: status.as_u16(),
+            message: match error {
+                XaiErrorBody::Message(message) => message,
+                XaiErrorBody::Detailed { message, kind: Some(kind) } => format!("{} ({})", message, kind),
+                XaiErrorBody::Detailed { message, kind: None } => message,
+            },
+        }),
+        Err(e) if serde_json::from_str::<Value>(body).is_ok() => {
+            Err(unexpected(format!("{}: {}", e, snippet())))
+        }
+        Err(e) => Err(unexpected(format!("invalid JSON ({}): {}", e, snippet()))),
+    }
+}
+
+const MINUTES_PER_MONTH: f64 = 30.0 * 24.0 * 60.0;
+
+fn runs_per_month(interval_minutes: i64) -> f64 {
+    if interval_minutes <= 0 {
+        return 0.0;
+    }
+    MINUTES_PER_MONTH / interval_minutes as f64
+}
+
+#[derive(Debug, PartialEq)]
+struct TaskCost {
+    name: String,
+    chat_id: i64,
+    runs_per_month: f64,
+    tokens_per_run: f64,
+    /// False when no usage has been recorded yet and the configured default was used
+    measured: bool,
+    monthly_cost: f64,
+}
+
+fn estimate_task_cost(
+    name: String,
+    chat_id: i64,
+    interval: i64,
+    avg_tokens: Option<f64>,
+    default_tokens_per_run: f64,
+    price_per_1k_tokens: f64,
+) -> TaskCost {
+    let runs = runs_per_month(interval);
+    let tokens_per_run = avg_tokens.unwrap_or(default_tokens_per_run);
+    TaskCost {
+        name,
+        chat_id,
+        runs_per_month: runs,
+        tokens_per_run,
+        measured: avg_tokens.is_some(),
+        monthly_cost: runs * tokens_per_run / 1000.0 * price_per_1k_tokens,
+    }
+}
+
+/// Monthly cost of every active task, for one chat or for all chats.
+async fn estimate_monthly_costs(
+    pool: &SqlitePool,
+    chat_id: Option<i64>,
+    config: &Config,
+) -> Result<Vec<TaskCost>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT
+            t.name,
+            t.chat_id,
+            t.interval,
+            (SELECT AVG(r.total_tokens) FROM task_runs r
+             WHERE r.task_name = t.name AND r.chat_id = t.chat_id AND r.total_tokens IS NOT NULL) as avg_tokens
+        FROM tasks t
+        WHERE t.paused = 0 AND t.deleted_at IS NULL AND (?1 IS NULL OR t.chat_id = ?1)
+        ORDER BY t.chat_id, t.name
+        "#,
+    )
+    .bind(chat_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            estimate_task_cost(
+                row.get("name"),
+                row.get("chat_id"),
+                row.get("interval"),
+                row.get("avg_tokens"),
+                config.xai_tokens_per_run as f64,
+                config.xai_price_per_1k_tokens,
+            )
+        })
+        .collect())
+}
+
+fn format_cost_estimate(costs: &[TaskCost], global: bool) -> String {
+    if costs.is_empty() {
+        return String::from("📭 *No active tasks to estimate*");
+    }
+
+    let mut formatted = String::from("*💰 Estimated Monthly Cost*\n\n");
+    for cost in costs {
+        let owner = if global {
+            format!(" \\(chat `{}`\\)", cost.chat_id)
+        } else {
+            String::new()
+        };
+        formatted.push_str(&format!(
+            "🔷 *{}*{}\n\
+            ├ Runs/month: {}\n\
+            ├ Tokens/run: {}{}\n\
+            └ Cost: ${}\n\n",
+            escape_markdown_v2(&cost.name),
+            owner,
+            escape_markdown_v2(&format!("{:.0}", cost.runs_per_month)),
+            escape_markdown_v2(&format!("{:.0}", cost.tokens_per_run)),
+            if cost.measured { "" } else { " _\\(estimated\\)_" },
+            escape_markdown_v2(&format!("{:.2}", cost.monthly_cost))
+        ));
+    }
+
+    let total: f64 = costs.iter().map(|cost| cost.monthly_cost).sum();
+    formatted.push_str(&format!(
+        "*Total:* ${}/month",
+        escape_markdown_v2(&format!("{:.2}", total))
+    ));
+    formatted
+}
+
+#[derive(Debug, PartialEq)]
+enum DiffLine<'a> {
+    Same(&'a str),
+    Added(&'a str),
+    Removed(&'a str),
+}
+
+/// Line-based diff using the longest common subsequence of both texts.
+fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    // lcs[i][j] = length of the common subsequence of old_lines[i..] and new_lines[j..]
+    let mut lcs = vec![vec![0usize; new_lines.len() + 1]; old_lines.len() + 1];
+    for i in (0..old_lines.len()).rev() {
+        for j in (0..new_lines.len()).rev() {
+            lcs[i][j] = if old_lines[i] == new_lines[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old_lines.len() && j < new_lines.len() {
+        if old_lines[i] == new_lines[j] {
+            diff.push(DiffLine::Same(old_lines[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            diff.push(DiffLine::Removed(old_lines[i]));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(new_lines[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old_lines[i..].iter().map(|line| DiffLine::Removed(line)));
+    diff.extend(new_lines[j..].iter().map(|line| DiffLine::Added(line)));
+    diff
+}
+
+fn escape_code_block(text: &str) -> String {
+    // Inside pre and code entities only '`' and '\' must be escaped
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '`' || c == '\\' {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+fn format_task_diff(name: &str, previous: &TaskRun, latest: &TaskRun) -> String {
+    let diff = diff_lines(&previous.response, &latest.response);
+    let body = diff
+        .iter()
+        .map(|line| match line {
+            DiffLine::Same(text) => format!("  {}", text),
+            DiffLine::Added(text) => format!("+ {}", text),
+            DiffLine::Removed(text) => format!("- {}", text),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let changed = diff.iter().any(|line| !matches!(line, DiffLine::Same(_)));
+    format!(
+        "🔍 *Changes in {}*\n\
+        🕒 _{}_ → _{}_\n\n{}",
+        escape_markdown_v2(name),
+        escape_markdown_v2(&previous.run_at),
+        escape_markdown_v2(&latest.run_at),
+        if changed {
+            format!("```\n{}\n```", escape_code_block(&body))
+        } else {
+            String::from("No changes between the last two answers\\.")
+        }
+    )
+}
+
+/// Crate version plus the commit and build time embedded by build.rs.
+fn version_info() -> String {
+    let commit = option_env!("WIBOT_BUILD_COMMIT").unwrap_or("unknown");
+    let built = option_env!("WIBOT_BUILD_TIMESTAMP")
+        .filter(|timestamp| !timestamp.is_empty())
+        .map(|timestamp| match timestamp.parse::<i64>() {
+            Ok(secs) => DateTime::from_timestamp(secs, 0)
+                .map(|time| time.to_rfc3339())
+                .unwrap_or_else(|| timestamp.to_string()),
+            Err(_) => timestamp.to_string(),
+        })
+        .unwrap_or_else(|| String::from("unknown"));
+    format!(
+        "{} {} (commit {}, built {})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        commit,
+        built
+    )
+}
+
+fn format_version_message(version: &str) -> String {
+    format!("🏷 *Version:* `{}`", escape_code_block(version))
+}
+
+fn format_help_message() -> String {
+    String::from(
+        "*Available Commands:*\n\n\
+        📌 */help* \\- Show this help message\n\n\
+        🏷 */version* \\- Show the bot's version and build\n\n\
+        📝 */create* \\[\\-\\-group\\=name\\] \\[\\-\\-priority\\=n\\] \\[\\-\\-startup\\] \\[\\-\\-pin\\] \\[\\-\\-tag\\=tag\\] \\[\\-\\-channel\\=@name\\] \\[\\-\\-maxlen\\=tokens\\] \\<name\\> \\<interval\\_minutes\\> \\<question\\>\n\
+        Creates a recurring X\\.AI query task\n\
+        Example: `/create weather 60 What's the weather in New York?`\n\n\
+        📋 */list* \\- Show all active tasks\n\n\
+        🗑 */delete* \\<name\\> \\[name\\.\\.\\.\\] \\- Remove one or more tasks\n\n\
+        ♻️ */undelete* \\<name\\> \\- Restore a recently deleted task\n\n\
+        🧾 */settemplate* \\<name\\> \\<prefix\\|suffix\\> \\[text\\] \\- Wrap a task's answers, supports `{task}` and `{date}`\n\n\
+        🐢 */slower* \\<name\\> / 🐇 */faster* \\<name\\> \\- Run a task less or more often\n\n\
+        ⏱ */reschedule* \\<name\\> \\<now\\|\\+2h\\> \\- Set when a task runs next\n\n\
+        ✏️ */edit* \\<name\\> \\-\\-maxlen\\=tokens \\- Limit a task's answer length, 0 removes the limit\n\n\
+        📌 */pin* \\<name\\> \\[on\\|off\\] \\- Pin each new answer of a task\n\n\
+        ⏸ */pause* \\<name\\> \\| \\-\\-tag\\=tag \\- Pause a task, or every task with a tag\n\n\
+        ▶️ */resume* \\<name\\> \\| \\-\\-tag\\=tag \\- Resume a paused task, or every task with a tag\n\n\
+        🔍 */diff* \\<name\\> \\- Compare a task's last two answers\n\n\
+        🔁 */replay* \\<name\\> \\[index\\] \\- Resend a stored answer, 1 is the latest\n\n\
+        🗂 */rungroup* \\<group\\> \\- Run a group of tasks now\n\n\
+        💰 */cost* \\- Estimate the monthly cost of this chat's tasks\n\n\
+        ⏰ */overdue* \\- List this chat's tasks that missed their schedule\n\n\
+        📤 */export* \\- Download your logged interactions in this chat as a text file\n\n\
+        👋 */welcome* \\- Show this chat's welcome message\n\n\
+        ✏️ */setwelcome* \\<text\\> \\- Set this chat's welcome message \\(admins only\\)\n\n\
+        ❓ */ask* \\[\\-\\-model\\=name\\] \\[\\-\\-temp\\=0\\.7\\] \\[\\-\\-lang\\=es\\] \\[\\-\\-json\\] \\<question\\> \\- Ask X\\.AI a one\\-time question\n\n\
+        👤 */setpref* \\<model\\|temp\\|style\\> \\<value\\|default\\> \\- Set your own answer defaults\n\n\
+        ⚙️ */settings* \\- Show this chat's settings\n\n\
+        🌡 */settemp* \\<0\\-2\\|default\\> \\- Set this chat's answer temperature \\(admins only\\)\n\n\
+        🌐 */setlang* \\<code\\|default\\> \\- Set this chat's answer language \\(admins only\\)\n\n\
+        🧹 */sanitize* \\<on\\|off\\> \\- Strip filler from this chat's answers \\(admins only\\)\n\n\
+        🐢 */setcooldown* \\<seconds\\|default\\> \\- Set the minimum time between a user's commands \\(admins only\\)"
+    )
+}
+
+async fn fetch_task_list(pool: &SqlitePool, chat_id: i64) -> Result<Vec<sqlx::sqlite::SqliteRow>, sqlx::Error> {
+    sqlx::query(
+        "SELECT name, description as question, interval, last_run, paused, task_group, tags, target_chat_id FROM tasks WHERE chat_id = ? AND deleted_at IS NULL"
+    )
+    .bind(chat_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Parses `<chat_id> <command>` for /as. The leading `/` of the command is optional.
+fn parse_as_command(input: &str, bot_name: &str) -> Result<(i64, Command), BotError> {
+    let (chat_id, command) = input
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or(BotError::InvalidParameters)?;
+    let chat_id = chat_id
+        .parse::<i64>()
+        .ok()
+        .filter(|chat_id| *chat_id != 0)
+        .ok_or(BotError::InvalidParameters)?;
+    let command = command.trim();
+    let command = if command.starts_with('/') {
+        command.to_string()
+    } else {
+        format!("/{}", command)
+    };
+    let command = Command::parse(&command, bot_name).map_err(|_| BotError::InvalidParameters)?;
+    Ok((chat_id, command))
+}
+
+/// Reply `cmd` would give in `chat_id`. Only commands that read the chat's
+/// state are allowed, so /as can't change anything or post into the chat.
+async fn run_as_chat(state: &AppState, chat_id: i64, cmd: &Command) -> Result<String, BotError> {
+    let reply = match cmd {
+        Command::List => format_task_list(&fetch_task_list(&state.pool, chat_id).await?),
+        Command::Settings => format_chat_settings(&get_chat_settings(&state.pool, chat_id).await?, &state.config),
+        Command::Start | Command::Welcome => {
+            let settings = get_chat_settings(&state.pool, chat_id).await?;
+            format_welcome_message(settings.welcome_message.as_deref())
+        }
+        Command::Cost(scope) if scope.trim().is_empty() => {
+            let costs = estimate_monthly_costs(&state.pool, Some(chat_id), &state.config).await?;
+            format_cost_estimate(&costs, false)
+        }
+        Command::Overdue(scope) if scope.trim().is_empty() => {
+            format_overdue_tasks(&fetch_overdue_tasks(&state.pool, Some(chat_id), Utc::now()).await?, false)
+        }
+        Command::ChatStats => {
+            ensure_logging_enabled(state)?;
+            format_chat_stats(&get_command_stats(state, Some(chat_id)).await?)
+        }
+        _ => return Err(BotError::NotReadOnly),
+    };
+    Ok(format!("👤 *As chat* `{}`\n\n{}", chat_id, reply))
+}
+
+fn format_task_list(tasks: &[sqlx::sqlite::SqliteRow]) -> String {
+    if tasks.is_empty() {
+        return String::from("📭 *No tasks found*");
+    }
+
+    let mut formatted = String::from("*📋 Active Tasks:*\n\n");
+
+    for task in tasks {
+        if task.try_get::<bool, _>("paused").unwrap_or(false) {
+            formatted.push_str("⏸ _Paused_\n");
+        }
+        formatted.push_str(&format!(
+            "🔷 *Task:* {}\n\
+            📝 *Question:* `{}`\n\
+            ⏱ *Interval:* {} minutes\n\
+            🕒 *Last run:* _{}_\n\n",
+            escape_markdown_v2(&task.get::<String, _>("name")),
+            escape_markdown_v2(&task.get::<String, _>("question")),
+            task.get::<i64, _>("interval"),
+            escape_markdown_v2(&task.get::<String, _>("last_run"))
+        ));
+        if let Ok(Some(group)) = task.try_get::<Option<String>, _>("task_group") {
+            formatted.push_str(&format!("🗂 *Group:* {}\n\n", escape_markdown_v2(&group)));
+        }
+        if let Ok(Some(target)) = task.try_get::<Option<i64>, _>("target_chat_id") {
+            formatted.push_str(&format!("📣 *Posts to channel:* `{}`\n\n", target));
+        }
+        if let Ok(Some(tags)) = task.try_get::<Option<String>, _>("tags") {
+            formatted.push_str(&format!("🏷 *Tags:* {}\n\n", escape_markdown_v2(&tags.replace(',', ", "))));
+        }
+    }
+
+    formatted
+}
+
+/// Optional per-task settings given as flags to /create.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct TaskOptions {
+    group: Option<String>,
+    priority: i64,
+    run_on_startup: bool,
+    pin: bool,
+    tags: Vec<String>,
+    /// Channel the answers are posted to instead of the chat the task belongs to.
+    target_chat_id: Option<i64>,
+    max_tokens: Option<i64>,
+}
+
+async fn create_task(
+    pool: &SqlitePool,
+    name: &str,
+    question: &str,
+    interval: i64,
+    chat_id: i64,
+    options: &TaskOptions,
+) -> Result<(), BotError> {
+    // A deleted task of this chat awaiting purge gives its name up to the new
+    // one. Another chat's stays untouched so it can still be restored there.
+    sqlx::query(
+        "DELETE FROM task_runs WHERE task_name = ?1 AND chat_id = ?2 AND EXISTS \
+         (SELECT 1 FROM tasks WHERE name = ?1 AND chat_id = ?2 AND deleted_at IS NOT NULL)",
+    )
+    .bind(name)
+    .bind(chat_id)
+    .execute(pool)
+    .await?;
+    sqlx::query("DELETE FROM tasks WHERE name = ? AND chat_id = ? AND deleted_at IS NOT NULL")
+        .bind(name)
+        .bind(chat_id)
+        .execute(pool)
+        .await?;
+
+    let result = sqlx::query(
+        "INSERT INTO tasks (name, description, interval, last_run, chat_id, task_group, priority, run_on_startup, pin, tags, target_chat_id, max_tokens) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(name)
+    .bind(question)
+    .bind(interval)
+    .bind(Utc::now().to_rfc3339())
+    .bind(chat_id)
+    .bind(&options.group)
+    .bind(options.priority)
+    .bind(options.run_on_startup)
+    .bind(options.pin)
+    .bind((!options.tags.is_empty()).then(|| options.tags.join(",")))
+    .bind(options.target_chat_id)
+    .bind(options.max_tokens)
+    .execute(pool)
+    .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if is_unique_violation(&e) => {
+            let deleted: Option<bool> =
+                sqlx::query_scalar("SELECT deleted_at IS NOT NULL FROM tasks WHERE name = ?")
+                    .bind(name)
+                    .fetch_optional(pool)
+                    .await?;
+            Err(if deleted == Some(true) {
+                BotError::TaskNameReserved
+            } else {
+                BotError::TaskExists
+            })
+        }
+        Err(e) => Err(BotError::DatabaseError(e)),
+    }
+}
+
+/// Marks a task as deleted. It stays restorable with /undelete until
+/// `purge_deleted_tasks` removes it after the grace period.
+async fn delete_task(pool: &SqlitePool, name: &str, chat_id: i64) -> Result<bool, BotError> {
+    let result = sqlx::query(
+        "UPDATE tasks SET deleted_at = ? WHERE name = ? AND chat_id = ? AND deleted_at IS NULL",
+    )
+    .bind(Utc::now().to_rfc3339())
+    .bind(name)
+    .bind(chat_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Splits whitespace-separated task names, dropping repeats.
+fn parse_task_names(input: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for name in input.split_whitespace() {
+        if !names.iter().any(|seen| seen == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+/// Deletes each task in turn, reporting per name whether it existed.
+async fn delete_tasks(
+    pool: &SqlitePool,
+    names: &[String],
+    chat_id: i64,
+) -> Result<Vec<(String, bool)>, BotError> {
+    let mut results = Vec::with_capacity(names.len());
+    for name in names {
+        let deleted = delete_task(pool, name, chat_id).await?;
+        results.push((name.clone(), deleted));
+    }
+    Ok(results)
+}
+
+fn format_delete_summary(results: &[(String, bool)], grace: &str) -> String {
+    let mut formatted = String::from("*🗑 Delete Summary*\n\n");
+    for (name, deleted) in results {
+        let status = if *deleted { "✅ deleted" } else { "❌ not found" };
+        formatted.push_str(&format!("{} *{}*\n", status, escape_markdown_v2(name)));
+    }
+    if results.iter().any(|(_, deleted)| *deleted) {
+        formatted.push_str(&format!(
+            "\nUse /undelete within {} to restore a task\\.",
+            escape_markdown_v2(grace)
+        ));
+    }
+    formatted
+}
+
+/// Restores a task deleted after `cutoff`.
+async fn undelete_task(
+    pool: &SqlitePool,
+    name: &str,
+    chat_id: i64,
+    cutoff: DateTime<Utc>,
+) -> Result<bool, BotError> {
+    let result = sqlx::query(
+        "UPDATE tasks SET deleted_at = NULL WHERE name = ? AND chat_id = ? AND deleted_at >= ?",
+    )
+    .bind(name)
+    .bind(chat_id)
+    .bind(cutoff.to_rfc3339())
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+fn format_grace_period(grace: Duration) -> String {
+    match grace.as_secs() {
+        secs if secs >= 86400 && secs % 86400 == 0 => format!("{} day(s)", secs / 86400),
+        secs if secs >= 3600 && secs % 3600 == 0 => format!("{} hour(s)", secs / 3600),
+        secs if secs >= 60 && secs % 60 == 0 => format!("{} minute(s)", secs / 60),
+        secs => format!("{} second(s)", secs),
+    }
+}
+
+/// Tasks deleted before this instant are past the grace period.
+fn deletion_cutoff(config: &Config) -> DateTime<Utc> {
+    Utc::now()
+        - chrono::Duration::from_std(config.task_delete_grace).unwrap_or(chrono::Duration::zero())
+}
+
+/// Permanently removes tasks deleted before `cutoff`, together with their run history.
+async fn purge_deleted_tasks(pool: &SqlitePool, cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    sqlx::query(
+        r#"
+        DELETE FROM task_runs WHERE EXISTS (
+            SELECT 1 FROM tasks t
+            WHERE t.name = task_runs.task_name AND t.chat_id = task_runs.chat_id
+              AND t.deleted_at < ?
+        )
+        "#,
+    )
+    .bind(cutoff.to_rfc3339())
+    .execute(pool)
+    .await?;
+
+    let result = sqlx::query("DELETE FROM tasks WHERE deleted_at < ?")
+        .bind(cutoff.to_rfc3339())
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+async fn record_task_run(
+    pool: &SqlitePool,
+    name: &str,
+    chat_id: i64,
+    answer: &XaiAnswer,
+) -> Result<i64, sqlx::Error> {
+    let result = sqlx::query(
+        "INSERT INTO task_runs (task_name, chat_id, run_at, response, total_tokens, model) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(name)
+    .bind(chat_id)
+    .bind(Utc::now().to_rfc3339())
+    .bind(&answer.content)
+    .bind(answer.total_tokens)
+    .bind(&answer.model)
+    .execute(pool)
+    .await?;
+
+    Ok(result.last_insert_rowid())
+}
+
+struct TaskRun {
+    run_at: String,
+    response: String,
+}
+
+/// Most recent runs of a task, newest first.
+async fn get_recent_runs(
+    pool: &SqlitePool,
+    name: &str,
+    chat_id: i64,
+    limit: i64,
+) -> Result<Vec<TaskRun>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT run_at, response FROM task_runs WHERE task_name = ? AND chat_id = ? ORDER BY id DESC LIMIT ?",
+    )
+    .bind(name)
+    .bind(chat_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| TaskRun {
+            run_at: row.get("run_at"),
+            response: row.get("response"),
+        })
+        .collect())
+}
+
+/// Parses `<task> [index]`, where index 1 (the default) is the latest run.
+fn parse_replay_command(input: &str) -> Option<(String, i64)> {
+    let mut parts = input.split_whitespace();
+    let name = parts.next()?.to_string();
+    let index = match parts.next() {
+        Some(index) => index.parse().ok()?,
+        None => 1,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((name, index))
+}
+
+/// The run `index` places back in a task's history, 1 being the latest.
+async fn get_task_run(
+    pool: &SqlitePool,
+    name: &str,
+    chat_id: i64,
+    index: i64,
+) -> Result<TaskRun, BotError> {
+    let available: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM task_runs WHERE task_name = ? AND chat_id = ?")
+            .bind(name)
+            .bind(chat_id)
+            .fetch_one(pool)
+            .await?;
+    if index < 1 || index > available {
+        return Err(BotError::RunIndexOutOfRange(available));
+    }
+
+    let row = sqlx::query(
+        "SELECT run_at, response FROM task_runs WHERE task_name = ? AND chat_id = ? ORDER BY id DESC LIMIT 1 OFFSET ?",
+    )
+    .bind(name)
+    .bind(chat_id)
+    .bind(index - 1)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(TaskRun {
+        run_at: row.get("run_at"),
+        response: row.get("response"),
+    })
+}
+
+async fn get_task_question(
+    pool: &SqlitePool,
+    name: &str,
+    chat_id: i64,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT description FROM tasks WHERE name = ? AND chat_id = ? AND deleted_at IS NULL",
+    )
+    .bind(name)
+    .bind(chat_id)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn set_task_paused(
+    pool: &SqlitePool,
+    name: &str,
+    chat_id: i64,
+    paused: bool,
+) -> Result<bool, BotError> {
+    let result = sqlx::query(
+        "UPDATE tasks SET paused = ? WHERE name = ? AND chat_id = ? AND deleted_at IS NULL",
+    )
+        .bind(paused)
+        .bind(name)
+        .bind(chat_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// New interval after /slower or /faster. It always moves by at least a
+/// minute and stops at [`MIN_TASK_INTERVAL`] or [`MAX_TASK_INTERVAL`]. Older
+/// tasks may lie outside that range; they are only ever moved towards it.
+fn scale_interval(interval: i64, factor: f64, slower: bool) -> i64 {
+    if slower {
+        let scaled = ((interval as f64 * factor).ceil() as i64).max(interval + 1);
+        scaled.min(MAX_TASK_INTERVAL.max(interval))
+    } else {
+        let scaled = ((interval as f64 / factor).floor() as i64).min(interval - 1);
+        scaled.max(MIN_TASK_INTERVAL.min(interval))
+    }
+}
+
+/// Scales a task's interval, returning the old and new interval in minutes.
+async fn adjust_task_interval(
+    pool: &SqlitePool,
+    name: &str,
+    chat_id: i64,
+    factor: f64,
+    slower: bool,
+) -> Result<Option<(i64, i64)>, BotError> {
+    let Some(interval) = sqlx::query_scalar::<_, i64>(
+        "SELECT interval FROM tasks WHERE name = ? AND chat_id = ? AND deleted_at IS NULL",
+    )
+    .bind(name)
+    .bind(chat_id)
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let scaled = scale_interval(interval, factor, slower);
+    sqlx::query("UPDATE tasks SET interval = ? WHERE name = ? AND chat_id = ? AND deleted_at IS NULL")
+        .bind(scaled)
+        .bind(name)
+        .bind(chat_id)
+        .execute(pool)
+        .await?;
+    Ok(Some((interval, scaled)))
+}
+
+fn format_interval_change(name: &str, old: i64, new: i64) -> String {
+    let name = escape_markdown_v2(name);
+    if old == new {
+        let limit = match new {
+            MIN_TASK_INTERVAL => String::from("the shortest allowed interval"),
+            MAX_TASK_INTERVAL => String::from("the longest allowed interval"),
+            _ => format!("outside the allowed {} to {} minutes", MIN_TASK_INTERVAL, MAX_TASK_INTERVAL),
+        };
+        return format!("⏱ Task *{}* already runs every {} minutes, {}\\.", name, new, limit);
+    }
+    format!(
+        "{} Task *{}* now runs every {} minutes \\(was {}\\)",
+        if new > old { "🐢" } else { "🐇" },
+        name,
+        new,
+        old
+    )
+}
+
+/// Handles /slower and /faster.
+async fn handle_interval_command(
+    bot: &Bot,
+    msg: &Message,
+    state: &AppState,
+    name: &str,
+    slower: bool,
+) -> Result<(), BotError> {
+    let name = name.trim();
+    let (old, new) = adjust_task_interval(
+        &state.pool,
+        name,
+        msg.chat.id.0,
+        state.config.interval_step_factor,
+        slower,
+    )
+    .await?
+    .ok_or(BotError::TaskNotFound)?;
+    try_send_message(bot, msg.chat.id, format_interval_change(name, old, new)).await?;
+    Ok(())
+}
+
+async fn get_task_interval(pool: &SqlitePool, name: &str, chat_id: i64) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar("SELECT interval FROM tasks WHERE name = ? AND chat_id = ? AND deleted_at IS NULL")
+        .bind(name)
+        .bind(chat_id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Parses the `<when>` of /reschedule: `now`, or a delay such as `+45m`,
+/// `+2h` or `+1d` (the `+` is optional), at most `MAX_TASK_INTERVAL` minutes.
+fn parse_reschedule_delay(input: &str) -> Result<chrono::Duration, BotError> {
+    let input = input.trim().to_lowercase();
+    if input == "now" {
+        return Ok(chrono::Duration::zero());
+    }
+    let value = input.strip_prefix('+').unwrap_or(&input);
+    let (amount, unit_minutes) = [('m', 1), ('h', 60), ('d', 1440)]
+        .into_iter()
+        .find_map(|(unit, minutes)| Some((value.strip_suffix(unit)?, minutes)))
+        .ok_or(BotError::InvalidParameters)?;
+    let amount: i64 = amount.parse().map_err(|_| BotError::InvalidParameters)?;
+    let minutes = amount
+        .checked_mul(unit_minutes)
+        .filter(|minutes| (0..=MAX_TASK_INTERVAL).contains(minutes))
+        .ok_or(BotError::InvalidParameters)?;
+    Ok(chrono::Duration::minutes(minutes))
+}
+
+/// `last_run` that makes a task due `delay` from now. The delay is clamped to
+/// the task's interval so `last_run` never lies in the future; the flag tells
+/// whether it was.
+fn rescheduled_last_run(now: DateTime<Utc>, interval: i64, delay: chrono::Duration) -> (DateTime<Utc>, bool) {
+    let interval = chrono::Duration::minutes(interval);
+    let clamped = delay > interval;
+    (now + delay.min(interval) - interval, clamped)
+}
+
+fn format_reschedule(name: &str, next_due: DateTime<Utc>, now: DateTime<Utc>, clamped: bool) -> String {
+    let when = if next_due <= now {
+        String::from("at the next check")
+    } else {
+        format!(
+            "at {} \\(in {}\\)",
+            escape_markdown_v2(&next_due.format("%Y-%m-%d %H:%M UTC").to_string()),
+            escape_markdown_v2(&format_lateness(next_due - now))
+        )
+    };
+    let mut formatted = format!("⏱ Task *{}* will run next {}", escape_markdown_v2(name), when);
+    if clamped {
+        formatted.push_str("\n_Delays are capped at one interval, use /pause to hold a task longer_");
+    }
+    formatted
+}
+
+#[derive(Debug, PartialEq)]
+enum PauseTarget {
+    Task(String),
+    Tag(String),
+}
+
+/// Parses the arguments of /pause and /resume: a task name or `--tag=<tag>`.
+fn parse_pause_target(input: &str) -> Result<PauseTarget, BotError> {
+    let input = input.trim();
+    if let Some(tag) = input.strip_prefix("--tag=") {
+        return parse_tag(tag).map(PauseTarget::Tag);
+    }
+    if input.is_empty() || input.starts_with("--") || input.contains(char::is_whitespace) {
+        return Err(BotError::InvalidParameters);
+    }
+    Ok(PauseTarget::Task(input.to_string()))
+}
+
+/// Pauses or resumes every task in the chat carrying `tag`, returning how
+/// many tasks changed state.
+async fn set_tagged_tasks_paused(
+    pool: &SqlitePool,
+    tag: &str,
+    chat_id: i64,
+    paused: bool,
+) -> Result<u64, BotError> {
+    let result = sqlx::query(
+        "UPDATE tasks SET paused = ?1 \
+         WHERE chat_id = ?2 AND deleted_at IS NULL AND paused != ?1 \
+         AND instr(',' || tags || ',', ',' || ?3 || ',') > 0",
+    )
+    .bind(paused)
+    .bind(chat_id)
+    .bind(tag)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Handles /pause and /resume for a single task or a whole tag.
+async fn handle_pause_command(
+    bot: &Bot,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    paused: bool,
+) -> Result<(), BotError> {
+    let (icon, verb) = if paused { ("⏸", "paused") } else { ("▶️", "resumed") };
+    let message = match parse_pause_target(args)? {
+        PauseTarget::Task(name) => {
+            if !set_task_paused(&state.pool, &name, msg.chat.id.0, paused).await? {
+                return Err(BotError::TaskNotFound);
+            }
+            format!("{} Task *{}* {}", icon, escape_markdown_v2(&name), verb)
+        }
+        PauseTarget::Tag(tag) => {
+            ensure_chat_admin(bot, msg).await?;
+            let count = set_tagged_tasks_paused(&state.pool, &tag, msg.chat.id.0, paused).await?;
+            format!(
+                "{} {} task{} tagged *{}* {}",
+                icon,
+                count,
+                if count == 1 { "" } else { "s" },
+                escape_markdown_v2(&tag),
+                verb
+            )
+        }
+    };
+    try_send_message(bot, msg.chat.id, message).await?;
+    Ok(())
+}
+
+/// Longest text Telegram accepts in a single message.
+const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+
+const CODE_FENCE: &str = "```";
+
+/// Splits MarkdownV2 text into messages of at most `limit` characters. Cuts
+/// fall between lines where possible; a code block cut in two is closed and
+/// reopened, and so is a span cut inside an overlong line.
+fn split_markdown_message(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    let mut open_fence: Option<String> = None;
+
+    for text_line in text.split('\n') {
+        // Inside a code block `*` and `_` are literal, not spans
+        let spans = open_fence.is_none() && !text_line.contains(CODE_FENCE);
+        for line in split_long_line(text_line, limit / 2, spans) {
+            push_message_line(&mut chunks, &mut current, &mut current_len, &mut open_fence, &line, limit);
+        }
+    }
+    chunks.push(current);
+
+    chunks
+        .into_iter()
+        .map(|chunk| chunk.trim_matches('\n').to_string())
+        .filter(|chunk| !chunk.is_empty())
+        .collect()
+}
+
+/// Appends `line` to the message being built, starting a new one first if it
+/// wouldn't fit.
+fn push_message_line(
+    chunks: &mut Vec<String>,
+    current: &mut String,
+    current_len: &mut usize,
+    open_fence: &mut Option<String>,
+    line: &str,
+    limit: usize,
+) {
+        let line_len = line.chars().count();
+    let closing_len = if open_fence.is_some() { CODE_FENCE.len() + 1 } else { 0 };
+    if *current_len > 0 && *current_len + 1 + line_len + closing_len > limit {
+        if open_fence.is_some() {
+            current.push('\n');
+            current.push_str(CODE_FENCE);
+        }
+        chunks.push(std::mem::take(current));
+        if let Some(fence) = open_fence {
+            current.push_str(fence);
+        }
+        *current_len = current.chars().count();
+    }
+
+    if *current_len > 0 {
+        current.push('\n');
+        *current_len += 1;
+    }
+    current.push_str(line);
+    *current_len += line_len;
+    if line.matches(CODE_FENCE).count() % 2 == 1 {
+        *open_fence = match open_fence {
+            Some(_) => None,
+            None => Some(line.to_string()),
+        };
+    }
+}
+
+/// Characters kept free in each piece of a split line for the markers that
+/// close and reopen the spans open at the cut.
+const SPAN_MARKER_RESERVE: usize = 12;
+
+/// Breaks a line longer than `max` characters at spaces where possible, never
+/// inside an escape sequence, a marker or a link. With `spans`, formatting
+/// spans open at a cut are closed before it and reopened after it, the way
+/// [`truncate_markdown`] closes them.
+fn split_long_line(line: &str, max: usize, spans: bool) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut open: Vec<&str> = Vec::new();
+    let mut rest = line;
+    loop {
+        let reopen = open.concat();
+        let budget = max.saturating_sub(if spans { SPAN_MARKER_RESERVE } else { 0 }).max(1);
+        if rest.chars().count() <= budget {
+            pieces.push(reopen + rest);
+            return pieces;
+        }
+
+        // Candidate cuts with the spans open there: the last space outside a
+        // link, else the last token boundary outside one, else any boundary
+        let mut state = open.clone();
+        let mut in_link = false;
+        let mut at_space = None;
+        let mut at_boundary = None;
+        let mut at_any = None;
+        let mut count = 0;
+        let mut index = 0;
+        while let Some(c) = rest[index..].chars().next() {
+            if count > budget {
+                break;
+            }
+            if index > 0 {
+                if !in_link && c == ' ' {
+                    at_space = Some((index, state.clone()));
+                }
+                if !in_link {
+                    at_boundary = Some((index, state.clone()));
+                }
+                at_any = Some((index, state.clone()));
+            }
+            let token = &rest[index..];
+            let in_code = state.last() == Some(&"`");
+            let marker = if !spans {
+                None
+            } else if in_code {
+                Some("`").filter(|marker| token.starts_with(marker))
+            } else {
+                ["`", "__", "_", "*", "~", "||"].into_iter().find(|marker| token.starts_with(marker))
+            };
+            let (len, chars) = match (c, marker) {
+                ('\\', _) => {
+                    let escaped = token[1..].chars().next().map_or(0, char::len_utf8);
+                    (1 + escaped, 2)
+                }
+                (_, Some(marker)) => {
+                    match state.iter().rposition(|open| *open == marker) {
+                        Some(position) => {
+                            state.remove(position);
+                        }
+                        None => state.push(marker),
+                    }
+                    (marker.len(), marker.len())
+                }
+                ('[', None) if spans && !in_code => {
+                    in_link = true;
+                    (1, 1)
+                }
+                (')', None) if in_link => {
+                    in_link = false;
+                    (1, 1)
+                }
+                _ => (c.len_utf8(), 1),
+            };
+            count += chars;
+            index += len;
+        }
+
+        // The line break replaces the space a span is cut at
+        let skip_space = spans && at_space.is_some();
+        // A link longer than a piece can't be kept whole
+        let (cut, open_at_cut) = match at_space.or(at_boundary).or(at_any) {
+            Some(candidate) => candidate,
+            None => {
+                let hard = rest.char_indices().nth(budget).map_or(rest.len(), |(index, _)| index);
+                (hard, open.clone())
+            }
+        };
+        let mut piece = reopen + &rest[..cut];
+        for marker in open_at_cut.iter().rev() {
+            piece.push_str(marker);
+        }
+        pieces.push(piece);
+        open = open_at_cut;
+        rest = &rest[cut + usize::from(skip_space)..];
+    }
+}
+
+/// Cuts MarkdownV2 text to at most `max_chars` characters, ending in `…`. The
+/// cut never falls inside an escape sequence or a link, and spans left open
+/// (bold, italic, code and the like) are closed after it.
+fn truncate_markdown(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+
+    // Room needed for the ellipsis and the markers closing `open`
+    let closing_len = |open: &[&str]| {
+        1 + open
+            .iter()
+            .map(|marker| if *marker == CODE_FENCE { marker.len() + 1 } else { marker.len() })
+            .sum::<usize>()
+    };
+    let mut open: Vec<&str> = Vec::new();
+    let mut cut = (0, Vec::new());
+    let mut in_link = false;
+    let mut count = 0;
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if !in_link {
+            if count + closing_len(&open) > max_chars {
+                break;
+            }
+            cut = (text.len() - rest.len(), open.clone());
+        }
+
+        let in_code = matches!(open.last(), Some(&"`") | Some(&CODE_FENCE));
+        let marker = if in_code {
+            open.last().copied().filter(|marker| rest.starts_with(marker))
+        } else {
+            [CODE_FENCE, "`", "__", "_", "*", "~", "||"]
+                .into_iter()
+                .find(|marker| rest.starts_with(marker))
+        };
+        let (len, chars) = match (c, marker) {
+            ('\\', _) => {
+                let escaped = rest[1..].chars().next().map_or(0, char::len_utf8);
+                (1 + escaped, 2)
+            }
+            (_, Some(marker)) => {
+                match open.iter().rposition(|open| *open == marker) {
+                    Some(index) => {
+                        open.remove(index);
+                    }
+                    None => open.push(marker),
+                }
+                (marker.len(), marker.len())
+            }
+            ('[', None) if !in_code => {
+                in_link = true;
+                (1, 1)
+            }
+            (')', None) if in_link && !in_code => {
+                in_link = false;
+                (1, 1)
+            }
+            _ => (c.len_utf8(), 1),
+        };
+        count += chars;
+        rest = &rest[len..];
+    }
+
+    let (index, open) = cut;
+    let mut truncated = text[..index].to_string();
+    truncated.push('…');
+    for marker in open.iter().rev() {
+        if *marker == CODE_FENCE {
+            truncated.push('\n');
+        }
+        truncated.push_str(marker);
+    }
+    truncated
+}
+
+/// Sends text of any length, split into as many messages as needed.
+async fn send_long_message(bot: &Bot, chat_id: ChatId, text: &str) -> Result<Vec<Message>, BotError> {
+    let mut sent = Vec::new();
+    for chunk in split_markdown_message(text, TELEGRAM_MESSAGE_LIMIT) {
+        sent.push(try_send_message(bot, chat_id, chunk).await?);
+    }
+    Ok(sent)
+}
+
+/// Times a send is retried after Telegram's flood control asks us to wait.
+const MAX_SEND_RETRIES: u32 = 3;
+/// Longest `retry_after` we are willing to sleep through before giving up.
+const MAX_SEND_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// How long to wait before retrying a failed send, or `None` to give up.
+fn send_retry_delay(error: &RequestError, attempt: u32) -> Option<Duration> {
+    match error {
+        RequestError::RetryAfter(wait) if attempt < MAX_SEND_RETRIES => {
+            Some(wait.duration()).filter(|wait| *wait <= MAX_SEND_RETRY_WAIT)
+        }
+        _ => None,
+    }
+}
+
+async fn try_send_message(bot: &Bot, chat_id: ChatId, message: String) -> Result<Message, BotError> {
+    let mut attempt = 0;
+    loop {
+        match bot
+            .send_message(chat_id, message.clone())
+            .parse_mode(ParseMode::MarkdownV2)
+            .await
+        {
+            Ok(sent) => return Ok(sent),
+            Err(e) => match send_retry_delay(&e, attempt) {
+                Some(wait) => {
+                    attempt += 1;
+                    log::warn!(
+                        "Flood control on chat {}, retrying in {:?} (attempt {}/{})",
+                        chat_id,
+                        wait,
+                        attempt,
+                        MAX_SEND_RETRIES
+                    );
+                    sleep(wait).await;
+                }
+                None => return Err(BotError::TelegramError(e)),
+            },
+        }
+    }
+}
+
+/// Messages from bots, including this bot's own, are never handled so two
+/// bots in one group can't trigger each other in a loop.
+fn is_from_bot(msg: &Message) -> bool {
+    msg.from.as_ref().is_some_and(|user| user.is_bot)
+}
+
+async fn handle_command(bot: Bot, msg: Message, cmd: Command, state: State) -> ResponseResult<()> {
+    if is_from_bot(&msg) {
+        log::warn!(
+            "Ignoring /{} from bot {:?} in chat {}",
+            cmd.name(),
+            msg.from.as_ref().map(|user| user.id),
+            msg.chat.id
+        );
+        return Ok(());
+    }
+
+    let start_time = std::time::Instant::now();
+    let cmd_str = cmd.name();
+    let cmd_args = cmd.args().map(str::to_string);
+
+    let user_id = msg.from.as_ref().map(|user| user.id.0.try_into().unwrap());
+    let username = msg.from.as_ref().and_then(|user| user.username.clone());
+
+    let result = async {
+        let mut logged_response = None;
+        // Model that wrote `logged_response`, kept for per-model ratings
+        let mut logged_model = None;
+        // Answer message that gets rating buttons once the log id is known
+        let mut rated_message = None;
+        check_command_cooldown(&state, msg.chat.id.0, user_id).await?;
+        match cmd {
+            Command::Create(args) => {
+                let (inline, args) = parse_inline_options(&args)?;
+                if inline.model.is_some()
+                    || inline.temperature.is_some()
+                    || inline.language.is_some()
+                    || inline.json
+                {
+                    return Err(BotError::InvalidParameters);
+                }
+                let target_chat_id = match &inline.channel {
+                    Some(channel) => Some(validate_channel_target(&bot, channel, msg.from.as_ref().map(|user| user.id)).await?),
+                    None => None,
+                };
+                let task_options = TaskOptions {
+                    group: inline.group,
+                    priority: inline.priority.unwrap_or_default(),
+                    run_on_startup: inline.run_on_startup.unwrap_or_default(),
+                    pin: inline.pin.unwrap_or_default(),
+                    tags: inline.tags,
+                    target_chat_id,
+                    max_tokens: inline.max_tokens.filter(|max_tokens| *max_tokens > 0),
+                };
+                match parse_create_command(args).await {
+                    Some((name, interval, question)) => {
+                        validate_task_name(&name, state.config.max_task_name_len)?;
+                        if let Some(group) = &task_options.group {
+                            validate_task_name(group, state.config.max_task_name_len)?;
+                        }
+                        check_rate_limit(&state, user_id)?;
+                        let options = XaiOptions {
+                            max_tokens: task_options.max_tokens,
+                            ..xai_options_for_chat(&state, msg.chat.id.0, None, &InlineOptions::default()).await?
+                        };
+                        retry_transient_xai(&CREATE_VALIDATION_BACKOFF, || {
+                            call_xai_api(&state, &question, &options)
+                        })
+                        .await?;
+                        
+                        create_task(&state.pool, &name, &question, interval as i64, msg.chat.id.0, &task_options).await?;
+                        
+                        let create_message = format!(
+                            "✅ *Task Created Successfully*\n\n\
+                            📌 *Name:* {}\n\
+                            ❓ *Question:* `{}`\n\
+                            ⏱ *Interval:* {} minutes\n{}\n\
+                            🔄 First response coming shortly\\.\\.\\.",
+                            escape_markdown_v2(&name), 
+                            escape_markdown_v2(&question), 
+                            interval,
+                            target_chat_id
+                                .map(|target| format!("📣 *Posts to channel:* `{}`\n", target))
+                                .unwrap_or_default()
+                        );
+                        
+                        try_send_message(&bot, msg.chat.id, create_message).await?;
+
+                        if let Ok(mut initial_answer) = call_xai_api_with_usage(&state, &question, &options).await {
+                            initial_answer.content =
+                                sanitize_for_chat(&state, msg.chat.id.0, initial_answer.content).await?;
+                            let formatted_response = wrap_xai_response(
+                                Some(&name),
+                                &question,
+                                &format_task_content(&initial_answer.content, task_options.max_tokens),
+                            );
+                            let sent = try_send_message(&bot, msg.chat.id, formatted_response).await?;
+                            logged_response = Some(initial_answer.content.clone());
+                            logged_model = Some(initial_answer.model.clone());
+                            match record_task_run(&state.pool, &name, msg.chat.id.0, &initial_answer).await {
+                                Ok(run_id) => {
+                                    attach_rating_buttons(&bot, msg.chat.id, sent.id, RatingTarget::Run(run_id)).await;
+                                }
+                                Err(e) => log::error!("Failed to record run of task {}: {}", name, e),
+                            }
+                        }
+                    }
+                    None => return Err(BotError::InvalidParameters),
+                }
+            },
+            Command::List => {
+                let tasks = fetch_task_list(&state.pool, msg.chat.id.0).await?;
+                try_send_message(&bot, msg.chat.id, format_task_list(&tasks)).await?;
+            },
+            Command::Delete(args) => {
+                let names = parse_task_names(&args);
+                let grace = format_grace_period(state.config.task_delete_grace);
+                match names.as_slice() {
+                    [] => return Err(BotError::InvalidParameters),
+                    [name] => {
+                        if delete_task(&state.pool, name, msg.chat.id.0).await? {
+                            try_send_message(
+                                &bot, 
+                                msg.chat.id, 
+                                format!(
+                                    "✅ Task *{}* deleted successfully\\. Use /undelete within {} to restore it\\.",
+                                    escape_markdown_v2(name),
+                                    escape_markdown_v2(&grace)
+                                )
+                            ).await?;
+                        } else {
+                            return Err(BotError::TaskNotFound);
+                        }
+                    },
+                    names => {
+                        let results = delete_tasks(&state.pool, names, msg.chat.id.0).await?;
+                        try_send_message(&bot, msg.chat.id, format_delete_summary(&results, &grace)).await?;
+                    },
+                }
+            },
+            Command::SetTemplate(args) => {
+                let (name, part, text) =
+                    parse_template_command(&args).ok_or(BotError::InvalidParameters)?;
+                if !set_task_template(&state.pool, &name, msg.chat.id.0, part, text.as_deref()).await? {
+                    return Err(BotError::TaskNotFound);
+                }
+                let action = if text.is_some() { "updated" } else { "cleared" };
+                try_send_message(
+                    &bot,
+                    msg.chat.id,
+                    format!("✅ {} of task *{}* {}", part.column(), escape_markdown_v2(&name), action)
+                ).await?;
+            },
+            Command::Undelete(name) => {
+                let name = name.trim();
+                if undelete_task(&state.pool, name, msg.chat.id.0, deletion_cutoff(&state.config)).await? {
+                    try_send_message(
+                        &bot,
+                        msg.chat.id,
+                        format!("♻️ Task *{}* restored", escape_markdown_v2(name))
+                    ).await?;
+                } else {
+                    return Err(BotError::TaskNotFound);
+                }
+            },
+            Command::Slower(name) => {
+                handle_interval_command(&bot, &msg, &state, &name, true).await?;
+            },
+            Command::Faster(name) => {
+                handle_interval_command(&bot, &msg, &state, &name, false).await?;
+            },
+            Command::Reschedule(args) => {
+                let mut parts = args.split_whitespace();
+                let (Some(name), Some(when), None) = (parts.next(), parts.next(), parts.next()) else {
+                    return Err(BotError::InvalidParameters);
+                };
+                let delay = parse_reschedule_delay(when)?;
+                let interval = get_task_interval(&state.pool, name, msg.chat.id.0)
+                    .await?
+                    .ok_or(BotError::TaskNotFound)?;
+                let now = Utc::now();
+                let (last_run, clamped) = rescheduled_last_run(now, interval, delay);
+                update_last_run(&state.pool, name, last_run).await?;
+                try_send_message(&bot, msg.chat.id, format_reschedule(name, task_next_due(last_run, interval), now, clamped)).await?;
+            },
+            Command::Pin(args) => {
+                let mut parts = args.split_whitespace();
+                let (Some(name), switch, None) = (parts.next(), parts.next(), parts.next()) else {
+                    return Err(BotError::InvalidParameters);
+                };
+                let pin = parse_switch(switch.unwrap_or_default())?;
+                if !set_task_pin(&state.pool, name, msg.chat.id.0, pin).await? {
+                    return Err(BotError::TaskNotFound);
+                }
+                let reply = if pin {
+                    format!("📌 New answers of task *{}* will be pinned", escape_markdown_v2(name))
+                } else {
+                    unpin_task_answer(&state, &bot, name, msg.chat.id.0).await?;
+                    format!("📌 Answers of task *{}* are no longer pinned", escape_markdown_v2(name))
+                };
+                try_send_message(&bot, msg.chat.id, reply).await?;
+            },
+            Command::Edit(args) => {
+                let (name, flags) = args.trim().split_once(char::is_whitespace).ok_or(BotError::InvalidParameters)?;
+                let (inline, rest) = parse_inline_options(flags)?;
+                let Some(max_tokens) = inline.max_tokens else {
+                    return Err(BotError::InvalidParameters);
+                };
+                if !rest.is_empty() || (InlineOptions { max_tokens: None, ..inline }) != InlineOptions::default() {
+                    return Err(BotError::InvalidParameters);
+                }
+                let max_tokens = (max_tokens > 0).then_some(max_tokens);
+                if !set_task_max_tokens(&state.pool, name, msg.chat.id.0, max_tokens).await? {
+                    return Err(BotError::TaskNotFound);
+                }
+                let reply = match max_tokens {
+                    Some(max_tokens) => format!(
+                        "✏️ Answers of task *{}* are now limited to {} tokens",
+                        escape_markdown_v2(name),
+                        max_tokens
+                    ),
+                    None => format!("✏️ Answers of task *{}* are no longer limited", escape_markdown_v2(name)),
+                };
+                try_send_message(&bot, msg.chat.id, reply).await?;
+            },
+            Command::Pause(args) => {
+                handle_pause_command(&bot, &msg, &state, &args, true).await?;
+            },
+            Command::Resume(args) => {
+                handle_pause_command(&bot, &msg, &state, &args, false).await?;
+            },
+            Command::Replay(args) => {
+                let (name, index) =
+                    parse_replay_command(&args).ok_or(BotError::InvalidParameters)?;
+                let question = get_task_question(&state.pool, &name, msg.chat.id.0)
+                    .await?
+                    .ok_or(BotError::TaskNotFound)?;
+                let run = get_task_run(&state.pool, &name, msg.chat.id.0, index).await?;
+                let message = format!(
+                    "{}\n\n🔁 _Replayed answer from {}_",
+                    format_xai_response(Some(&name), &question, &run.response),
+                    escape_markdown_v2(&run.run_at)
+                );
+                try_send_message(&bot, msg.chat.id, message).await?;
+            },
+            Command::Diff(name) => {
+                let name = name.trim();
+                let runs = get_recent_runs(&state.pool, name, msg.chat.id.0, 2).await?;
+                let message = match runs.as_slice() {
+                    [latest, previous] => format_task_diff(name, previous, latest),
+                    _ => format!(
+                        "ℹ️ Task *{}* needs at least two runs to compare\\.",
+                        escape_markdown_v2(name)
+                    ),
+                };
+                // Diffs of long answers don't fit one message
+                send_long_message(&bot, msg.chat.id, &message).await?;
+            },
+            Command::RunGroup(group) => {
+                let group = group.trim();
+                let tasks = fetch_group_tasks(&state.pool, msg.chat.id.0, group).await?;
+                if tasks.is_empty() {
+                    return Err(BotError::TaskNotFound);
+                }
+                check_rate_limit(&state, user_id)?;
+                if run_task_batch(&state, &bot, &tasks, Utc::now()).await? == 0 {
+                    return Err(BotError::Other(anyhow::anyhow!("No task in group {} produced an answer", group)));
+                }
+            },
+            Command::Cost(scope) => {
+                let global = match scope.trim() {
+                    "" => false,
+                    "all" => {
+                        ensure_owner(user_id, state.owner_id)?;
+                        true
+                    }
+                    _ => return Err(BotError::InvalidParameters),
+                };
+                let chat_filter = if global { None } else { Some(msg.chat.id.0) };
+                let costs = estimate_monthly_costs(&state.pool, chat_filter, &state.config).await?;
+                try_send_message(&bot, msg.chat.id, format_cost_estimate(&costs, global)).await?;
+            },
+            Command::Overdue(scope) => {
+                let global = match scope.trim() {
+                    "" => false,
+                    "all" => {
+                        ensure_owner(user_id, state.owner_id)?;
+                        true
+                    }
+                    _ => return Err(BotError::InvalidParameters),
+                };
+                let chat_filter = if global { None } else { Some(msg.chat.id.0) };
+                let overdue = fetch_overdue_tasks(&state.pool, chat_filter, Utc::now()).await?;
+                // Many stuck tasks don't fit one message
+                send_long_message(&bot, msg.chat.id, &format_overdue_tasks(&overdue, global)).await?;
+            },
+            Command::Ask(args) => {
+                let (inline, question) = parse_inline_options(&args)?;
+                if inline.group.is_some()
+                    || inline.priority.is_some()
+                    || inline.run_on_startup.is_some()
+                    || inline.pin.is_some()
+                    || inline.channel.is_some()
+                    || !inline.tags.is_empty()
+                    || inline.max_tokens.is_some()
+                {
+                    return Err(BotError::InvalidParameters);
+                }
+                let question = validate_question(&question)?;
+                check_rate_limit(&state, user_id)?;
+                let options = xai_options_for_chat(&state, msg.chat.id.0, user_id, &inline).await?;
+                let (response, formatted) = if options.json {
+                    let json = call_xai_api_json(&state, question, &options).await?;
+                    let formatted = format_json_answer(&json);
+                    (json, formatted)
+                } else {
+                    let response = call_xai_api_cached(&state, question, &options).await?;
+                    let response = sanitize_for_chat(&state, msg.chat.id.0, response).await?;
+                    let formatted = format_xai_response(None, question, &response);
+                    (response, formatted)
+                };
+                rated_message = Some(try_send_message(&bot, msg.chat.id, formatted).await?.id);
+                logged_response = Some(response);
+                logged_model = Some(options.model);
+            },
+            Command::Start | Command::Welcome => {
+                let settings = get_chat_settings(&state.pool, msg.chat.id.0).await?;
+                try_send_message(&bot, msg.chat.id, format_welcome_message(settings.welcome_message.as_deref())).await?;
+            },
+            Command::SetTemp(value) => {
+                ensure_chat_admin(&bot, &msg).await?;
+                let temperature = match value.trim() {
+                    "default" => None,
+                    value => Some(parse_temperature(value)?),
+                };
+                set_chat_temperature(&state.pool, msg.chat.id.0, temperature).await?;
+                let settings = get_chat_settings(&state.pool, msg.chat.id.0).await?;
+                try_send_message(&bot, msg.chat.id, format_chat_settings(&settings, &state.config)).await?;
+            },
+            Command::SetLang(value) => {
+                ensure_chat_admin(&bot, &msg).await?;
+                let language = match value.trim() {
+                    "default" => None,
+                    value => Some(parse_language(value)?),
+                };
+                set_chat_language(&state.pool, msg.chat.id.0, language.as_deref()).await?;
+                let settings = get_chat_settings(&state.pool, msg.chat.id.0).await?;
+                try_send_message(&bot, msg.chat.id, format_chat_settings(&settings, &state.config)).await?;
+            },
+            Command::SetCooldown(value) => {
+                ensure_chat_admin(&bot, &msg).await?;
+                let cooldown = match value.trim() {
+                    "default" => None,
+                    value => Some(parse_cooldown(value)?),
+                };
+                set_chat_cooldown(&state.pool, msg.chat.id.0, cooldown).await?;
+                let settings = get_chat_settings(&state.pool, msg.chat.id.0).await?;
+                try_send_message(&bot, msg.chat.id, format_chat_settings(&settings, &state.config)).await?;
+            },
+            Command::Sanitize(value) => {
+                ensure_chat_admin(&bot, &msg).await?;
+                let sanitize = match value.trim().to_lowercase().as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(BotError::InvalidParameters),
+                };
+                set_chat_sanitize(&state.pool, msg.chat.id.0, sanitize).await?;
+                let settings = get_chat_settings(&state.pool, msg.chat.id.0).await?;
+                try_send_message(&bot, msg.chat.id, format_chat_settings(&settings, &state.config)).await?;
+            },
+            Command::SetPref(args) => {
+                let user_id = user_id.ok_or(BotError::InvalidParameters)?;
+                if !args.trim().is_empty() {
+                    set_user_pref(&state.pool, user_id, &parse_pref_command(&args)?).await?;
+                }
+                let prefs = get_user_prefs(&state.pool, user_id).await?;
+                try_send_message(&bot, msg.chat.id, format_user_prefs(&prefs)).await?;
+            },
+            Command::Settings => {
+                let settings = get_chat_settings(&state.pool, msg.chat.id.0).await?;
+                try_send_message(&bot, msg.chat.id, format_chat_settings(&settings, &state.config)).await?;
+            },
+            Command::SetWelcome(text) => {
+                ensure_chat_admin(&bot, &msg).await?;
+                let text = text.trim();
+                if text.is_empty() {
+                    return Err(BotError::InvalidParameters);
+                }
+                set_welcome_message(&state.pool, msg.chat.id.0, text).await?;
+                try_send_message(&bot, msg.chat.id, String::from("✅ Welcome message updated")).await?;
+            },
+            Command::Help => {
+                send_long_message(&bot, msg.chat.id, &format_help_message()).await?;
+            },
+            Command::Version => {
+                try_send_message(&bot, msg.chat.id, format_version_message(&version_info())).await?;
+            },
+            Command::MyId => {
+                if let Some(user) = &msg.from {
+                    let is_creator = user.id.0 as i64 == state.owner_id;  // Simplified check
+                    let user_info = format!(
+                        "👤 *Your Telegram Info:*\n\n\
+                        🆔 *User ID:* `{}`\n\
+                        📝 *Username:* @{}\n\
+                        👑 *Bot Owner:* {}\n",
+                        user.id,
+                        user.username.as_deref().unwrap_or("none"),
+                        if is_creator { "Yes ✅" } else { "No ❌" }
+                    );
+                    try_send_message(&bot, msg.chat.id, user_info).await?;
+                }
+            },
+            Command::BotStats => {
+                ensure_logging_enabled(&state)?;
+                if let Some(user_id) = user_id {
+                    if user_id == state.owner_id {  // Direct comparison
+                        match get_command_stats(&state, None).await {
+                            Ok(stats) => {
+                                let formatted_stats = format_bot_stats(&stats);
+                                try_send_message(&bot, msg.chat.id, formatted_stats).await?;
+                            }
+                            Err(e) => {
+                                log::error!("Failed to get bot stats: {}", e);
+                                return Err(BotError::DatabaseError(e));
+                            }
+                        }
+                    } else {
+                        return Err(BotError::PermissionDenied);
+                    }
+                }
+            },
+            Command::Queue => {
+                ensure_owner(user_id, state.owner_id)?;
+                let schedules = fetch_task_schedules(&state.pool).await?;
+                // Every task of every chat rarely fits one message
+                send_long_message(&bot, msg.chat.id, &format_task_queue(&schedules, Utc::now())).await?;
+            },
+            Command::RateLimit(args) => {
+                ensure_owner(user_id, state.owner_id)?;
+                let (target, reset) =
+                    parse_rate_limit_command(&args).ok_or(BotError::InvalidParameters)?;
+
+                let message = if reset {
+                    let cleared = state.rate_limiter.lock().unwrap().reset(target);
+                    if cleared {
+                        format!("✅ Rate limit for user `{}` has been reset", target)
+                    } else {
+                        format!("ℹ️ User `{}` has no recorded requests", target)
+                    }
+                } else {
+                    let (used, resets_in) = state.rate_limiter.lock().unwrap().usage(target, Instant::now());
+                    format!(
+                        "⏳ *Rate limit for user* `{}`\n\n\
+                        📈 *Requests in window:* {}/{}\n\
+                        🕒 *Window:* {} seconds\n\
+                        🔓 *Next slot frees in:* {}",
+                        target,
+                        used,
+                        state.config.rate_limit_max_requests,
+                        state.config.rate_limit_window.as_secs(),
+                        resets_in.map_or(String::from("now"), |wait| format!("{} seconds", wait.as_secs()))
+                    )
+                };
+                try_send_message(&bot, msg.chat.id, message).await?;
+            },
+            Command::PurgeInactive(args) => {
+                ensure_owner(user_id, state.owner_id)?;
+                // Without logs every chat would look inactive
+                ensure_logging_enabled(&state)?;
+                let (days, confirm) =
+                    parse_purge_command(&args).ok_or(BotError::InvalidParameters)?;
+                let cutoff = Utc::now() - chrono::Duration::days(days);
+                let chats = find_inactive_chats(&state.pool, cutoff).await?;
+
+                let purged = if confirm && !chats.is_empty() {
+                    let chat_ids: Vec<i64> = chats.iter().map(|chat| chat.chat_id).collect();
+                    let deleted = purge_chat_tasks(&state.pool, &chat_ids).await?;
+                    log::info!("Purged {} tasks from {} inactive chats", deleted, chat_ids.len());
+                    Some(deleted)
+                } else {
+                    None
+                };
+
+                try_send_message(&bot, msg.chat.id, format_inactive_chats(&chats, days, purged)).await?;
+            },
+            Command::Search(args) => {
+                ensure_owner(user_id, state.owner_id)?;
+                ensure_logging_enabled(&state)?;
+                let search = parse_search_command(&args)?;
+                let matches = search_logs(&state, &search).await?;
+                try_send_message(&bot, msg.chat.id, format_log_matches(&search, &matches)).await?;
+            },
+            Command::Export(args) => {
+                ensure_logging_enabled(&state)?;
+                let (chat_id, exported_user) = match args.trim() {
+                    "" => (msg.chat.id.0, Some(user_id.ok_or(BotError::PermissionDenied)?)),
+                    chat_id => {
+                        ensure_owner(user_id, state.owner_id)?;
+                        (chat_id.parse::<i64>().map_err(|_| BotError::InvalidParameters)?, None)
+                    }
+                };
+                let entries = fetch_transcript(&state, chat_id, exported_user).await?;
+                if entries.is_empty() {
+                    try_send_message(&bot, msg.chat.id, String::from("📭 No logged interactions to export\\.")).await?;
+                } else {
+                    let transcript = format_transcript(chat_id, &entries, Utc::now());
+                    let file = InputFile::memory(transcript.into_bytes())
+                        .file_name(format!("transcript-{}.txt", chat_id));
+                    bot.send_document(msg.chat.id, file).await?;
+                }
+            },
+            Command::As(args) => {
+                ensure_owner(user_id, state.owner_id)?;
+                // Another chat's data is only ever shown to the owner
+                if !msg.chat.is_private() {
+                    return Err(BotError::PermissionDenied);
+                }
+                let me = bot.get_me().await?;
+                let (chat_id, cmd) = parse_as_command(&args, me.username())?;
+                log::info!("Owner runs /{} as chat {}", cmd.name().to_lowercase(), chat_id);
+                let reply = run_as_chat(&state, chat_id, &cmd).await?;
+                try_send_message(&bot, msg.chat.id, reply).await?;
+            },
+            Command::Ratings(args) => {
+                ensure_owner(user_id, state.owner_id)?;
+                let by_model = match args.trim() {
+                    "" | "command" => false,
+                    "model" => true,
+                    _ => return Err(BotError::InvalidParameters),
+                };
+                let stats = get_rating_stats(&state, by_model).await?;
+                try_send_message(&bot, msg.chat.id, format_rating_stats(&stats, by_model)).await?;
+            },
+            Command::Payload(args) => {
+                ensure_owner(user_id, state.owner_id)?;
+                let (inline, question) = parse_inline_options(&args)?;
+                let question = validate_question(&question)?;
+                let options = xai_options_for_chat(&state, msg.chat.id.0, user_id, &inline).await?;
+                let request = build_xai_request(&state, question, &options).map_err(BotError::XaiServiceError)?;
+                try_send_message(&bot, msg.chat.id, format_xai_payload(&request)).await?;
+            },
+            Command::RenderTest => {
+                ensure_owner(user_id, state.owner_id)?;
+                let sample = format_xai_response(None, "Render test", RENDER_TEST_SAMPLE);
+                try_send_message(&bot, msg.chat.id, sample).await?;
+            },
+            Command::Status => {
+                ensure_owner(user_id, state.owner_id)?;
+                let status = format_status(&state.xai_latency.lock().unwrap());
+                try_send_message(&bot, msg.chat.id, status).await?;
+            },
+            Command::Config => {
+                ensure_owner(user_id, state.owner_id)?;
+                let telegram_token = env::var("TELEGRAM_BOT_TOKEN").unwrap_or_default();
+                try_send_message(
+                    &bot,
+                    msg.chat.id,
+                    format_config_report(&state.config, &state.xai_token, &telegram_token)
+                ).await?;
+            },
+            Command::AllTasks(args) => {
+                ensure_owner(user_id, state.owner_id)?;
+                let page = parse_page(&args)?;
+                let (tasks, total) = fetch_all_tasks_page(&state.pool, page).await?;
+
+                let mut chat_ids: Vec<i64> = tasks.iter().map(|task| task.chat_id).collect();
+                chat_ids.dedup();
+                let mut titles = HashMap::new();
+                for chat_id in chat_ids {
+                    if let Some(title) = fetch_chat_title(&bot, chat_id).await {
+                        titles.insert(chat_id, title);
+                    }
+                }
+
+                try_send_message(&bot, msg.chat.id, format_all_tasks(&tasks, &titles, page, total)).await?;
+            },
+            Command::ChatStats => {
+                ensure_logging_enabled(&state)?;
+                let is_owner = user_id == Some(state.owner_id);
+                let is_admin = !is_owner
+                    && state.config.allow_admin_stats
+                    && is_chat_admin(&bot, &msg).await?;
+                if !can_view_chat_stats(is_owner, is_admin, state.config.allow_admin_stats) {
+                    return Err(if state.config.allow_admin_stats {
+                        BotError::AdminRequired
+                    } else {
+                        BotError::PermissionDenied
+                    });
+                }
+                let stats = get_command_stats(&state, Some(msg.chat.id.0)).await?;
+                try_send_message(&bot, msg.chat.id, format_chat_stats(&stats)).await?;
+            },
+            Command::Stats => {
+                ensure_logging_enabled(&state)?;
+                if let Some(user_id) = user_id {
+                    match get_user_stats(&state, user_id).await {
+                        Ok(stats) => {
+                            let formatted_stats = format_user_stats(&stats);
+                            try_send_message(&bot, msg.chat.id, formatted_stats).await?;
+                        }
+                        Err(e) => {
+                            log::error!("Failed to get user stats: {}", e);
+                            return Err(BotError::DatabaseError(e));
+                        }
+                    }
+                }
+            },
+        }
+        Ok((logged_response, logged_model, rated_message))
+    }.await;
+
+    // Log the interaction after command execution
+    if let Some(uid) = user_id {
+        let log_id = log_interaction(
+            &state,
+            msg.chat.id.0,
+            Some(uid),
+            username,
+            &cmd_str,
+            cmd_args.as_deref(),
+            result.as_ref().ok().and_then(|(response, _, _)| response.as_deref()),
+            result.as_ref().ok().and_then(|(_, model, _)| model.as_deref()),
+            result.as_ref().err().map(|e| e.to_string()).as_deref(),
+            start_time.elapsed(),
+        )
+        .await
+        .unwrap_or_else(|e| {
+            log::error!("Failed to log interaction: {}", e);
+            None
+        });
+
+        if let (Ok((_, _, Some(message_id))), Some(log_id)) = (&result, log_id) {
+            attach_rating_buttons(&bot, msg.chat.id, *message_id, RatingTarget::Log(log_id)).await;
+        }
+    }
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            let _ = try_send_message(&bot, msg.chat.id, err.user_message()).await;
+            log::error!("Command error: {:?}", err);
+            Ok(())
+        }
+    }
+}
+
+
+/// An answer users can vote on: a logged /ask or a recorded task run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RatingTarget {
+    Log(i64),
+    Run(i64),
+}
+
+impl RatingTarget {
+    fn kind(self) -> &'static str {
+        match self {
+            RatingTarget::Log(_) => "log",
+            RatingTarget::Run(_) => "run",
+        }
+    }
+
+    fn id(self) -> i64 {
+        match self {
+            RatingTarget::Log(id) | RatingTarget::Run(id) => id,
+        }
+    }
+
+    fn callback_data(self, vote: i64) -> String {
+        let vote = if vote > 0 { "up" } else { "down" };
+        format!("rate:{}:{}:{}", self.kind(), self.id(), vote)
+    }
+}
+
+/// Parses `rate:<log|run>:<id>:<up|down>` callback data into a target and a vote of 1 or -1.
+fn parse_rating_callback(data: &str) -> Option<(RatingTarget, i64)> {
+    let mut parts = data.strip_prefix("rate:")?.split(':');
+    let kind = parts.next()?;
+    let id = parts.next()?.parse().ok()?;
+    let vote = match parts.next()? {
+        "up" => 1,
+        "down" => -1,
+        _ => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    let target = match kind {
+        "log" => RatingTarget::Log(id),
+        "run" => RatingTarget::Run(id),
+        _ => return None,
+    };
+    Some((target, vote))
+}
+
+fn rating_keyboard(target: RatingTarget) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback("👍", target.callback_data(1)),
+        InlineKeyboardButton::callback("👎", target.callback_data(-1)),
+    ]])
+}
+
+async fn attach_rating_buttons(bot: &Bot, chat_id: ChatId, message_id: MessageId, target: RatingTarget) {
+    if let Err(e) = bot
+        .edit_message_reply_markup(chat_id, message_id)
+        .reply_markup(rating_keyboard(target))
+        .await
+    {
+        log::warn!("Failed to attach rating buttons: {}", e);
+    }
+}
+
+/// Command and model a vote is attributed to, or `None` when the answer no longer exists.
+async fn rating_target_info(
+    pool: &SqlitePool,
+    target: RatingTarget,
+    config: &Config,
+) -> Result<Option<(String, String)>, sqlx::Error> {
+    match target {
+        // Logs written before the model was recorded fall back to the configured one
+        RatingTarget::Log(id) => Ok(sqlx::query_as::<_, (String, Option<String>)>(
+            "SELECT command, model FROM bot_logs WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+        .map(|(command, model)| (command, model.unwrap_or_else(|| config.xai_model.clone())))),
+        RatingTarget::Run(id) => Ok(sqlx::query_scalar::<_, Option<String>>("SELECT model FROM task_runs WHERE id = ?")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?
+            .map(|model| {
+                (
+                    String::from("Task"),
+                    model.unwrap_or_else(|| config.xai_model.clone()),
+                )
+            })),
+    }
+}
+
+/// Stores a user's vote; voting again on the same answer replaces the earlier vote.
+async fn record_rating(
+    pool: &SqlitePool,
+    target: RatingTarget,
+    user_id: i64,
+    vote: i64,
+    command: &str,
+    model: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO ratings (target_kind, target_id, user_id, vote, command, model, rated_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT(target_kind, target_id, user_id)
+        DO UPDATE SET vote = excluded.vote, rated_at = excluded.rated_at
+        "#,
+    )
+    .bind(target.kind())
+    .bind(target.id())
+    .bind(user_id)
+    .bind(vote)
+    .bind(command)
+    .bind(model)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+struct RatingStat {
+    key: String,
+    up: i64,
+    down: i64,
+}
+
+impl RatingStat {
+    fn up_rate(&self) -> f64 {
+        let total = self.up + self.down;
+        if total == 0 {
+            0.0
+        } else {
+            self.up as f64 * 100.0 / total as f64
+        }
+    }
+}
+
+/// Vote totals grouped by command, or by model when `by_model` is set.
+async fn get_rating_stats(state: &AppState, by_model: bool) -> Result<Vec<RatingStat>, sqlx::Error> {
+    let pool = &state.read_pool;
+    let column = if by_model { "model" } else { "command" };
+    let rows = sqlx::query(&format!(
+        r#"
+        SELECT
+            {0} as key,
+            COUNT(CASE WHEN vote > 0 THEN 1 END) as up,
+            COUNT(CASE WHEN vote < 0 THEN 1 END) as down
+        FROM ratings
+        GROUP BY {0}
+        ORDER BY COUNT(*) DESC, {0}
+        "#,
+        column
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| RatingStat {
+            key: row.get("key"),
+            up: row.get("up"),
+            down: row.get("down"),
+        })
+        .collect())
+}
+
+fn format_rating_stats(stats: &[RatingStat], by_model: bool) -> String {
+    if stats.is_empty() {
+        return String::from("📭 *No ratings yet*");
+    }
+
+    let mut formatted = format!(
+        "*👍 Answer Ratings by {}*\n\n",
+        if by_model { "Model" } else { "Command" }
+    );
+    for stat in stats {
+        formatted.push_str(&format!(
+            "🔹 *{}*: {} 👍 / {} 👎 \\({}\\)\n",
+            escape_markdown_v2(&stat.key),
+            stat.up,
+            stat.down,
+            escape_markdown_v2(&format!("{:.1}%", stat.up_rate()))
+        ));
+    }
+    formatted
+}
+
+async fn handle_callback(bot: Bot, query: CallbackQuery, state: State) -> ResponseResult<()> {
+    let Some((target, vote)) = query.data.as_deref().and_then(parse_rating_callback) else {
+        bot.answer_callback_query(query.id).await?;
+        return Ok(());
+    };
+    let user_id = query.from.id.0 as i64;
+
+    let reply = match rating_target_info(&state.pool, target, &state.config).await {
+        Ok(Some((command, model))) => {
+            match record_rating(&state.pool, target, user_id, vote, &command, &model).await {
+                Ok(()) => "Thanks for your feedback!",
+                Err(e) => {
+                    log::error!("Failed to record rating: {}", e);
+                    "Couldn't save your vote, please try again later."
+                }
+            }
+        }
+        Ok(None) => "This answer can no longer be rated.",
+        Err(e) => {
+            log::error!("Failed to look up rated answer: {}", e);
+            "Couldn't save your vote, please try again later."
+        }
+    };
+
+    bot.answer_callback_query(query.id).text(reply).await?;
+    Ok(())
+}
+
+/// Reply to a `/command` the bot couldn't parse, if it should answer at all.
+/// Private chats always get a pointer to /help. In groups other bots' commands
+/// are common, so the bot only speaks up when it is mentioned.
+fn unknown_command_reply(text: &str, is_private: bool, bot_name: &str) -> Option<String> {
+    let token = text.split_whitespace().next()?.strip_prefix('/')?;
+    let (name, target) = match token.split_once('@') {
+        Some((name, target)) => (name, Some(target)),
+        None => (token, None),
+    };
+    if name.is_empty() || target.is_some_and(|target| !target.eq_ignore_ascii_case(bot_name)) {
+        return None;
+    }
+    // Whole usernames only, `@wibot_fan` doesn't mention `wibot`
+    let mention = format!("@{}", bot_name);
+    let mentioned = target.is_some()
+        || text
+            .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '@'))
+            .any(|word| word.eq_ignore_ascii_case(&mention));
+    if !is_private && !mentioned {
+        return None;
+    }
+
+    Some(format!(
+        "❓ Unknown command /{}\\. Try /help for the list of commands\\.",
+        escape_markdown_v2(name)
+    ))
+}
+
+/// Catches `/commands` that didn't parse as a [`Command`]; plain messages are ignored.
+async fn handle_unknown_command(bot: Bot, msg: Message, me: Me) -> ResponseResult<()> {
+    let Some(text) = msg.text() else {
+        return Ok(());
+    };
+    if let Some(reply) = unknown_command_reply(text, msg.chat.is_private(), me.username()) {
+        if let Err(e) = try_send_message(&bot, msg.chat.id, reply).await {
+            log::error!("Failed to answer unknown command: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Telegram sends an inline query for every keystroke. A query is only
+/// answered once the user has stopped typing for this long.
+const INLINE_QUERY_DEBOUNCE: Duration = Duration::from_millis(800);
+/// Longest answer preview shown under an inline result.
+const INLINE_DESCRIPTION_LEN: usize = 100;
+
+/// Answers `@bot <question>` with a single article holding the X.AI answer.
+async fn handle_inline_query(bot: Bot, query: InlineQuery, state: State) -> ResponseResult<()> {
+    let Ok(question) = validate_question(&query.query) else {
+        return Ok(());
+    };
+    let user_id = query.from.id.0 as i64;
+
+    state.inline_queries.lock().unwrap().insert(user_id, query.id.clone());
+    sleep(INLINE_QUERY_DEBOUNCE).await;
+    {
+        let mut latest = state.inline_queries.lock().unwrap();
+        if latest.get(&user_id) != Some(&query.id) {
+            // The user kept typing, a newer query takes over
+            return Ok(());
+        }
+        latest.remove(&user_id);
+    }
+
+    let start_time = Instant::now();
+    let result = async {
+        check_rate_limit(&state, Some(user_id))?;
+        // Inline queries carry no chat, so the settings of the user's private chat apply
+        let options = xai_options_for_chat(&state, user_id, Some(user_id), &InlineOptions::default()).await?;
+        let answer = call_xai_api_cached(&state, question, &options).await?;
+        Ok::<_, BotError>((answer, options.model))
+    }
+    .await;
+
+    let error = result.as_ref().err().map(|e| format!("{:?}", e));
+    if let Err(e) = log_interaction(
+        &state,
+        user_id,
+        Some(user_id),
+        query.from.username.clone(),
+        "Inline",
+        Some(question),
+        result.as_ref().ok().map(|(answer, _)| answer.as_str()),
+        result.as_ref().ok().map(|(_, model)| model.as_str()),
+        error.as_deref(),
+        start_time.elapsed(),
+    )
+    .await
+    {
+        log::error!("Failed to log inline query: {}", e);
+    }
+
+    let answer = match &result {
+        Ok((answer, _)) => bot.answer_inline_query(
+            query.id,
+            [InlineQueryResult::Article(inline_answer_result(question, answer))],
+        ),
+        Err(e) => {
+            log::warn!("Inline query from user {} failed: {:?}", user_id, e);
+            bot.answer_inline_query(query.id, []).button(inline_error_button(e))
+        }
+    };
+    answer.cache_time(0).is_personal(true).await?;
+    Ok(())
+}
+
+/// The article offered for an inline question. Sending it posts the answer the
+/// way /ask formats it, cut to a single message.
+fn inline_answer_result(question: &str, answer: &str) -> InlineQueryResultArticle {
+    let text = split_markdown_message(&format_xai_response(None, question, answer), TELEGRAM_MESSAGE_LIMIT)
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    let preview = answer.split_whitespace().collect::<Vec<_>>().join(" ");
+    let description = if preview.chars().count() > INLINE_DESCRIPTION_LEN {
+        let cut: String = preview.chars().take(INLINE_DESCRIPTION_LEN - 1).collect();
+        format!("{}…", cut.trim_end())
+    } else {
+        preview
+    };
+
+    InlineQueryResultArticle::new(
+        "answer",
+        question,
+        InputMessageContent::Text(InputMessageContentText::new(text).parse_mode(ParseMode::MarkdownV2)),
+    )
+    .description(description)
+}
+
+/// Shown above the (empty) results when a question can't be answered; it
+/// opens a private chat with the bot.
+fn inline_error_button(error: &BotError) -> InlineQueryResultsButton {
+    let text = match error {
+        BotError::RateLimited(wait) => format!("⏳ Rate limit reached, try again in {}s", wait.as_secs().max(1)),
+        _ => String::from("⚠️ Couldn't get an answer, try again later"),
+    };
+    InlineQueryResultsButton {
+        text,
+        kind: InlineQueryResultsButtonKind::StartParameter(String::from("inline")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChatReachability {
+    Reachable,
+    /// The bot can never deliver to this chat again (blocked, kicked, deleted chat)
+    Unreachable,
+    /// The lookup failed for a reason that may go away (network, flood control)
+    Unknown,
+}
+
+/// Remembers chats that recently resolved successfully so the scheduler does
+/// not call `get_chat` before every single task run.
+struct ChatCache {
+    ttl: Duration,
+    verified_at: HashMap<i64, Instant>,
+}
+
+impl ChatCache {
+    fn new(ttl: Duration) -> Self {
+        ChatCache {
+            ttl,
+            verified_at: HashMap::new(),
+        }
+    }
+
+    fn is_fresh(&self, chat_id: i64, now: Instant) -> bool {
+        self.verified_at
+            .get(&chat_id)
+            .is_some_and(|verified| now.duration_since(*verified) < self.ttl)
+    }
+
+    fn mark_reachable(&mut self, chat_id: i64, now: Instant) {
+        self.verified_at.insert(chat_id, now);
+    }
+
+    fn forget(&mut self, chat_id: i64) {
+        self.verified_at.remove(&chat_id);
+    }
+}
+
+fn classify_chat_lookup<T>(result: &Result<T, RequestError>) -> ChatReachability {
+    match result {
+        Ok(_) => ChatReachability::Reachable,
+        Err(RequestError::Api(
+            ApiError::ChatNotFound
+            | ApiError::BotBlocked
+            | ApiError::BotKicked
+            | ApiError::BotKickedFromSupergroup
+            | ApiError::UserDeactivated
+            | ApiError::CantInitiateConversation
+            | ApiError::CantTalkWithBots
+            | ApiError::GroupDeactivated,
+        )) => ChatReachability::Unreachable,
+        // A migrated group lives on under a new id, the old one never comes back
+        Err(RequestError::MigrateToChatId(_)) => ChatReachability::Unreachable,
+        Err(_) => ChatReachability::Unknown,
+    }
+}
+
+async fn check_chat_reachability(state: &AppState, bot: &Bot, chat_id: i64) -> ChatReachability {
+    if state.chat_cache.lock().unwrap().is_fresh(chat_id, Instant::now()) {
+        return ChatReachability::Reachable;
+    }
+
+    let lookup = bot.get_chat(ChatId(chat_id)).await;
+    let reachability = classify_chat_lookup(&lookup);
+    match &lookup {
+        Ok(_) => state
+            .chat_cache
+            .lock()
+            .unwrap()
+            .mark_reachable(chat_id, Instant::now()),
+        Err(e) => log::warn!("Chat {} lookup failed: {:?}", chat_id, e),
+    }
+    reachability
+}
+
+/// Records the message now pinned for a task, returning the previously pinned one.
+async fn swap_pinned_message(
+    pool: &SqlitePool,
+    name: &str,
+    chat_id: i64,
+    message_id: Option<MessageId>,
+) -> Result<Option<MessageId>, sqlx::Error> {
+    let previous: Option<i32> = sqlx::query_scalar(
+        "SELECT pinned_message_id FROM tasks WHERE name = ? AND chat_id = ? AND deleted_at IS NULL",
+    )
+    .bind(name)
+    .bind(chat_id)
+    .fetch_optional(pool)
+    .await?
+    .flatten();
+
+    sqlx::query("UPDATE tasks SET pinned_message_id = ? WHERE name = ? AND chat_id = ? AND deleted_at IS NULL")
+        .bind(message_id.map(|id| id.0))
+        .bind(name)
+        .bind(chat_id)
+        .execute(pool)
+        .await?;
+
+    Ok(previous.map(MessageId).filter(|previous| Some(*previous) != message_id))
+}
+
+async fn set_task_pin(pool: &SqlitePool, name: &str, chat_id: i64, pin: bool) -> Result<bool, BotError> {
+    let result = sqlx::query("UPDATE tasks SET pin = ? WHERE name = ? AND chat_id = ? AND deleted_at IS NULL")
+        .bind(pin)
+        .bind(name)
+        .bind(chat_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+async fn set_task_max_tokens(
+    pool: &SqlitePool,
+    name: &str,
+    chat_id: i64,
+    max_tokens: Option<i64>,
+) -> Result<bool, BotError> {
+    let result = sqlx::query("UPDATE tasks SET max_tokens = ? WHERE name = ? AND chat_id = ? AND deleted_at IS NULL")
+        .bind(max_tokens)
+        .bind(name)
+        .bind(chat_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Unpins the message a task last pinned, if any.
+async fn unpin_task_answer(state: &AppState, bot: &Bot, name: &str, chat_id: i64) -> Result<(), BotError> {
+    let target: Option<i64> = sqlx::query_scalar(
+        "SELECT COALESCE(target_chat_id, chat_id) FROM tasks WHERE name = ? AND chat_id = ? AND deleted_at IS NULL",
+    )
+    .bind(name)
+    .bind(chat_id)
+    .fetch_optional(&state.pool)
+    .await?;
+    let Some(target) = target else {
+        return Ok(());
+    };
+    if let Some(previous) = swap_pinned_message(&state.pool, name, chat_id, None).await? {
+        if let Err(e) = bot.unpin_chat_message(ChatId(target)).message_id(previous).await {
+            log::warn!("Failed to unpin previous answer of task '{}': {:?}", name, e);
+        }
+    }
+    Ok(())
+}
+
+/// Pins a task's new answer and unpins the one it replaces. Without the right
+/// to pin, pinning is switched off for the task and the chat is told once.
+async fn pin_task_answer(state: &AppState, bot: &Bot, task: &ScheduledTask, message_id: MessageId) {
+    let (name, chat_id) = (task.name.as_str(), task.chat_id);
+    match bot
+        .pin_chat_message(ChatId(task.target()), message_id)
+        .disable_notification(true)
+        .await
+    {
+        Ok(_) => {}
+        Err(RequestError::Api(ApiError::NotEnoughRightsToPinMessage | ApiError::NotEnoughRightsToManagePins)) => {
+            log::warn!("Cannot pin answers of task '{}' in chat {}: missing rights", name, chat_id);
+            if let Err(e) = set_task_pin(&state.pool, name, chat_id, false).await {
+                log::error!("Failed to turn off pinning for task '{}': {}", name, e);
+            }
+            let notice = format!(
+                "📌 I can't pin answers of task *{}* without the permission to pin messages\\. \
+                Pinning is turned off, use /pin {} once I'm allowed to\\.",
+                escape_markdown_v2(name),
+                escape_markdown_v2(name)
+            );
+            if let Err(e) = try_send_message(bot, ChatId(chat_id), notice).await {
+                log::error!("Failed to report pin failure to chat {}: {:?}", chat_id, e);
+            }
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to pin answer of task '{}': {:?}", name, e);
+            return;
+        }
+    }
+
+    match swap_pinned_message(&state.pool, name, chat_id, Some(message_id)).await {
+        Ok(Some(previous)) => {
+            if let Err(e) = bot.unpin_chat_message(ChatId(task.target())).message_id(previous).await {
+                log::warn!("Failed to unpin previous answer of task '{}': {:?}", name, e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => log::error!("Failed to record pinned answer of task '{}': {}", name, e),
+    }
+}
+
+/// Whether a failed post means the bot can no longer write to a channel.
+fn is_lost_channel_rights(error: &BotError) -> bool {
+    match error {
+        BotError::TelegramError(RequestError::Api(
+            ApiError::NotEnoughRightsToPostMessages | ApiError::BotKicked | ApiError::ChatNotFound,
+        )) => true,
+        BotError::TelegramError(RequestError::Api(ApiError::Unknown(description))) => {
+            ["not a member of the channel", "CHAT_WRITE_FORBIDDEN", "CHAT_ADMIN_REQUIRED"]
+                .iter()
+                .any(|known| description.contains(known))
+        }
+        _ => false,
+    }
+}
+
+/// Telegram refused the answer itself, so sending it again can't succeed.
+fn is_rejected_answer(error: &BotError) -> bool {
+    matches!(
+        error,
+        BotError::TelegramError(RequestError::Api(
+            ApiError::MessageIsTooLong
+                | ApiError::MessageTextIsEmpty
+                | ApiError::CantParseEntities(_)
+                | ApiError::CantParseUrl,
+        ))
+    )
+}
+
+/// Pauses a task whose channel no longer takes the bot's posts and tells the
+/// chat the task was created in.
+async fn pause_lost_channel_task(state: &AppState, bot: &Bot, task: &ScheduledTask) -> Result<(), BotError> {
+    set_task_paused(&state.pool, &task.name, task.chat_id, true).await?;
+    state.chat_cache.lock().unwrap().forget(task.target());
+    log::warn!("Paused task '{}': cannot post to channel {}", task.name, task.target());
+
+    let notice = format!(
+        "⏸ Task *{}* was paused because I can no longer post to channel `{}`\\. \
+        Make me an administrator allowed to post messages again, then use /resume {}\\.",
+        escape_markdown_v2(&task.name),
+        task.target(),
+        escape_markdown_v2(&task.name)
+    );
+    if let Err(e) = try_send_message(bot, ChatId(task.chat_id), notice).await {
+        log::error!("Failed to notify chat {} about task '{}': {:?}", task.chat_id, task.name, e);
+    }
+    Ok(())
+}
+
+/// Parses a channel given as `@username` or numeric id.
+fn parse_channel_ref(value: &str) -> Result<Recipient, BotError> {
+    let value = value.trim();
+    if let Some(username) = value.strip_prefix('@') {
+        if username.len() >= 5 && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Ok(Recipient::ChannelUsername(value.to_string()));
+        }
+        return Err(BotError::InvalidParameters);
+    }
+    value
+        .parse::<i64>()
+        .ok()
+        .filter(|id| *id < 0)
+        .map(|id| Recipient::Id(ChatId(id)))
+        .ok_or(BotError::InvalidParameters)
+}
+
+/// A channel target needs a bot that may post there and a requester who
+/// administers the channel, so nobody can post through the bot into channels
+/// they don't control.
+fn check_channel_rights(bot: &ChatMemberKind, requester: Option<&ChatMemberKind>) -> Result<(), BotError> {
+    if !bot.can_post_messages() {
+        return Err(BotError::ChannelUnavailable);
+    }
+    if !requester.is_some_and(ChatMemberKind::is_privileged) {
+        return Err(BotError::AdminRequired);
+    }
+    Ok(())
+}
+
+/// Resolves `--channel` at task creation, returning the channel's id.
+async fn validate_channel_target(bot: &Bot, value: &str, requester: Option<UserId>) -> Result<i64, BotError> {
+    let channel = bot
+        .get_chat(parse_channel_ref(value)?)
+        .await
+        .map_err(|_| BotError::ChannelUnavailable)?;
+    if !channel.is_channel() {
+        return Err(BotError::ChannelUnavailable);
+    }
+
+    let me = bot.get_me().await?;
+    let bot_member = bot
+        .get_chat_member(channel.id, me.id)
+        .await
+        .map_err(|_| BotError::ChannelUnavailable)?;
+    let requester_member = match requester {
+        Some(user_id) => bot.get_chat_member(channel.id, user_id).await.ok(),
+        None => None,
+    };
+    check_channel_rights(&bot_member.kind, requester_member.as_ref().map(|member| &member.kind))?;
+    Ok(channel.id.0)
+}
+
+async fn pause_unreachable_task(state: &AppState, bot: &Bot, name: &str, chat_id: i64) -> Result<(), BotError> {
+    set_task_paused(&state.pool, name, chat_id, true).await?;
+    state.chat_cache.lock().unwrap().forget(chat_id);
+    log::warn!("Paused task '{}': chat {} is unreachable", name, chat_id);
+
+    if let Some(fallback_chat_id) = state.config.fallback_chat_id {
+        let notice = format!(
+            "⏸ Task *{}* was paused because chat `{}` is no longer reachable\\.",
+            escape_markdown_v2(name),
+            chat_id
+        );
+        if let Err(e) = try_send_message(bot, ChatId(fallback_chat_id), notice).await {
+            log::error!("Failed to notify fallback chat {}: {:?}", fallback_chat_id, e);
+        }
+    }
+    Ok(())
+}
+
+fn task_next_due(last_run: DateTime<Utc>, interval: i64) -> DateTime<Utc> {
+    last_run + chrono::Duration::minutes(interval)
+}
+
+fn is_task_due(last_run: DateTime<Utc>, interval: i64, now: DateTime<Utc>) -> bool {
+    now.signed_duration_since(last_run).num_minutes() >= interval
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TaskQueueStatus {
+    Due,
+    Waiting,
+    Paused,
+    /// `last_run` can't be parsed, the scheduler fails on this task
+    Invalid,
+}
+
+struct TaskSchedule {
+    name: String,
+    chat_id: i64,
+    interval: i64,
+    last_run: String,
+    paused: bool,
+}
+
+impl TaskSchedule {
+    fn next_due(&self) -> Option<DateTime<Utc>> {
+        let last_run: DateTime<Utc> = self.last_run.parse().ok()?;
+        Some(task_next_due(last_run, self.interval))
+    }
+
+    fn status(&self, now: DateTime<Utc>) -> TaskQueueStatus {
+        match self.last_run.parse::<DateTime<Utc>>() {
+            Err(_) => TaskQueueStatus::Invalid,
+            Ok(_) if self.paused => TaskQueueStatus::Paused,
+            Ok(last_run) if is_task_due(last_run, self.interval, now) => TaskQueueStatus::Due,
+            Ok(_) => TaskQueueStatus::Waiting,
+        }
+    }
+}
+
+async fn fetch_task_schedules(pool: &SqlitePool) -> Result<Vec<TaskSchedule>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT name, chat_id, interval, last_run, paused FROM tasks WHERE deleted_at IS NULL",
+    )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| TaskSchedule {
+            name: row.get("name"),
+            chat_id: row.get("chat_id"),
+            interval: row.get("interval"),
+            last_run: row.get("last_run"),
+            paused: row.get("paused"),
+        })
+        .collect())
+}
+
+fn format_task_queue(schedules: &[TaskSchedule], now: DateTime<Utc>) -> String {
+    if schedules.is_empty() {
+        return String::from("📭 *No tasks scheduled*");
+    }
+
+    let mut sorted: Vec<&TaskSchedule> = schedules.iter().collect();
+    sorted.sort_by_key(|schedule| schedule.next_due());
+
+    let mut formatted = format!(
+        "*🗓 Scheduler Queue* \\({}\\)\n\n",
+        escape_markdown_v2(&now.to_rfc3339())
+    );
+    for schedule in sorted {
+        let status = match schedule.status(now) {
+            TaskQueueStatus::Due => "🔴 Due",
+            TaskQueueStatus::Waiting => "🟢 Waiting",
+            TaskQueueStatus::Paused => "⏸ Paused",
+            TaskQueueStatus::Invalid => "⚠️ Invalid last run",
+        };
+        formatted.push_str(&format!(
+            "🔷 *{}* \\(chat `{}`\\)\n\
+            ├ Status: {}\n\
+            ├ Interval: {} minutes\n\
+            ├ Last run: {}\n\
+            └ Next due: {}\n\n",
+            escape_markdown_v2(&schedule.name),
+            schedule.chat_id,
+            status,
+            schedule.interval,
+            escape_markdown_v2(&schedule.last_run),
+            escape_markdown_v2(
+                &schedule
+                    .next_due()
+                    .map_or(String::from("unknown"), |due| due.to_rfc3339())
+            )
+        ));
+    }
+
+    formatted
+}
+
+/// Active tasks whose last run is more than one interval ago, paired with how
+/// long past due they are, most overdue first.
+fn find_overdue_tasks(tasks: Vec<ScheduledTask>, now: DateTime<Utc>) -> Vec<(ScheduledTask, chrono::Duration)> {
+    let mut overdue: Vec<_> = tasks
+        .into_iter()
+        .map(|task| {
+            let late = task.overdue_by(now);
+            (task, late)
+        })
+        .filter(|(_, late)| *late > chrono::Duration::zero())
+        .collect();
+    overdue.sort_by(|(a, a_late), (b, b_late)| b_late.cmp(a_late).then_with(|| a.name.cmp(&b.name)));
+    overdue
+}
+
+async fn fetch_overdue_tasks(
+    pool: &SqlitePool,
+    chat_id: Option<i64>,
+    now: DateTime<Utc>,
+) -> Result<Vec<(ScheduledTask, chrono::Duration)>, sqlx::Error> {
+    let tasks = fetch_active_tasks(pool)
+        .await?
+        .into_iter()
+        .filter(|task| chat_id.is_none_or(|chat_id| task.chat_id == chat_id))
+        .collect();
+    Ok(find_overdue_tasks(tasks, now))
+}
+
+/// Compact duration such as `2d 3h 5m`, or `<1m`.
+fn format_lateness(late: chrono::Duration) -> String {
+    let minutes = late.num_minutes();
+    if minutes < 1 {
+        return String::from("<1m");
+    }
+    let parts = [(minutes / 1440, "d"), (minutes / 60 % 24, "h"), (minutes % 60, "m")];
+    parts
+        .iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn format_overdue_tasks(overdue: &[(ScheduledTask, chrono::Duration)], global: bool) -> String {
+    if overdue.is_empty() {
+        return String::from("✅ *No overdue tasks*");
+    }
+
+    let mut formatted = String::from("*⏰ Overdue Tasks*\n\n");
+    for (task, late) in overdue {
+        let chat = if global {
+            format!(" \\(chat `{}`\\)", task.chat_id)
+        } else {
+            String::new()
+        };
+        formatted.push_str(&format!(
+            "🔴 *{}*{}\n\
+            ├ Overdue by: {}\n\
+            ├ Interval: {} minutes\n\
+            └ Last run: {}\n\n",
+            escape_markdown_v2(&task.name),
+            chat,
+            escape_markdown_v2(&format_lateness(*late)),
+            task.interval,
+            escape_markdown_v2(&task.last_run.format("%Y-%m-%d %H:%M UTC").to_string())
+        ));
+    }
+    formatted
+}
+
+struct ScheduledTask {
+    name: String,
+    question: String,
+    interval: i64,
+    last_run: DateTime<Utc>,
+    chat_id: i64,
+    group: Option<String>,
+    /// Template text placed before and after the task's answer
+    prefix: Option<String>,
+    suffix: Option<String>,
+    priority: i64,
+    /// Pin each new answer, replacing the previously pinned one
+    pin: bool,
+    /// Channel the answers are posted to, if not the task's own chat
+    target_chat_id: Option<i64>,
+    /// Limit on the answer's length, in tokens
+    max_tokens: Option<i64>,
+}
+
+impl ScheduledTask {
+    /// The chat's request options with the task's own limits applied.
+    fn xai_options(&self, chat_options: &XaiOptions) -> XaiOptions {
+        XaiOptions {
+            max_tokens: self.max_tokens,
+            ..chat_options.clone()
+        }
+    }
+
+    fn is_due(&self, now: DateTime<Utc>) -> bool {
+        is_task_due(self.last_run, self.interval, now)
+    }
+
+    /// Chat the task's answers are sent to.
+    fn target(&self) -> i64 {
+        self.target_chat_id.unwrap_or(self.chat_id)
+    }
+
+    /// How long the task has been waiting past its due time.
+    fn overdue_by(&self, now: DateTime<Utc>) -> chrono::Duration {
+        now - task_next_due(self.last_run, self.interval)
+    }
+}
+
+fn scheduled_task_from_row(row: &sqlx::sqlite::SqliteRow) -> Option<ScheduledTask> {
+    let name: String = row.get("name");
+    let last_run = match row.get::<String, _>("last_run").parse() {
+        Ok(last_run) => last_run,
+        Err(e) => {
+            log::error!("Task '{}' has an invalid last_run: {}", name, e);
+            return None;
+        }
+    };
+
+    Some(ScheduledTask {
+        name,
+        question: row.get("question"),
+        interval: row.get("interval"),
+        last_run,
+        chat_id: row.get("chat_id"),
+        group: row.get("task_group"),
+        prefix: row.get("prefix"),
+        suffix: row.get("suffix"),
+        priority: row.get("priority"),
+        pin: row.get("pin"),
+        target_chat_id: row.get("target_chat_id"),
+        max_tokens: row.get("max_tokens"),
+    })
+}
+
+/// How often the scheduler looks for due tasks.
+const TASK_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+const SCHEDULED_TASK_COLUMNS: &str =
+    "name, description as question, interval, last_run, chat_id, task_group, prefix, suffix, priority, pin, target_chat_id, max_tokens";
+
+async fn fetch_active_tasks(pool: &SqlitePool) -> Result<Vec<ScheduledTask>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM tasks WHERE paused = 0 AND deleted_at IS NULL ORDER BY name",
+        SCHEDULED_TASK_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().filter_map(scheduled_task_from_row).collect())
+}
+
+async fn fetch_startup_tasks(pool: &SqlitePool) -> Result<Vec<ScheduledTask>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM tasks WHERE run_on_startup = 1 AND paused = 0 AND deleted_at IS NULL ORDER BY name",
+        SCHEDULED_TASK_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().filter_map(scheduled_task_from_row).collect())
+}
+
+async fn fetch_group_tasks(
+    pool: &SqlitePool,
+    chat_id: i64,
+    group: &str,
+) -> Result<Vec<ScheduledTask>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM tasks WHERE paused = 0 AND deleted_at IS NULL AND chat_id = ? AND task_group = ? ORDER BY name",
+        SCHEDULED_TASK_COLUMNS
+    ))
+    .bind(chat_id)
+    .bind(group)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().filter_map(scheduled_task_from_row).collect())
+}
+
+/// Splits the due tasks into batches, each delivered as one message. Tasks of
+/// a group are batched together only when every task in the group is due,
+/// otherwise the due ones run on their own.
+fn plan_task_batches(tasks: Vec<ScheduledTask>, now: DateTime<Utc>) -> Vec<Vec<ScheduledTask>> {
+    let mut batches = Vec::new();
+    let mut groups: std::collections::BTreeMap<(i64, i64, String), Vec<ScheduledTask>> =
+        std::collections::BTreeMap::new();
+
+    for task in tasks {
+        match task.group.clone() {
+            Some(group) => groups.entry((task.chat_id, task.target(), group)).or_default().push(task),
+            None if task.is_due(now) => batches.push(vec![task]),
+            None => {}
+        }
+    }
+
+    for members in groups.into_values() {
+        if members.iter().all(|task| task.is_due(now)) {
+            batches.push(members);
+        } else {
+            batches.extend(
+                members
+                    .into_iter()
+                    .filter(|task| task.is_due(now))
+                    .map(|task| vec![task]),
+            );
+        }
+    }
+
+    batches
+}
+
+/// Dispatch order of due batches: highest priority first, then the most
+/// overdue, so important tasks don't starve when the pool is saturated.
+fn compare_batch_urgency(
+    a: &[ScheduledTask],
+    b: &[ScheduledTask],
+    now: DateTime<Utc>,
+) -> std::cmp::Ordering {
+    let priority = |batch: &[ScheduledTask]| batch.iter().map(|task| task.priority).max();
+    let overdue = |batch: &[ScheduledTask]| batch.iter().map(|task| task.overdue_by(now)).max();
+    priority(b)
+        .cmp(&priority(a))
+        .then_with(|| overdue(b).cmp(&overdue(a)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TemplatePart {
+    Prefix,
+    Suffix,
+}
+
+impl TemplatePart {
+    fn column(self) -> &'static str {
+        match self {
+            TemplatePart::Prefix => "prefix",
+            TemplatePart::Suffix => "suffix",
+        }
+    }
+}
+
+/// Parses `<name> <prefix|suffix> [text]`; a missing text clears the template.
+fn parse_template_command(input: &str) -> Option<(String, TemplatePart, Option<String>)> {
+    let mut parts = input.trim().splitn(3, char::is_whitespace);
+    let name = parts.next().filter(|name| !name.is_empty())?;
+    let part = match parts.next()?.to_lowercase().as_str() {
+        "prefix" => TemplatePart::Prefix,
+        "suffix" => TemplatePart::Suffix,
+        _ => return None,
+    };
+    let text = parts
+        .next()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(str::to_string);
+    Some((name.to_string(), part, text))
+}
+
+async fn set_task_template(
+    pool: &SqlitePool,
+    name: &str,
+    chat_id: i64,
+    part: TemplatePart,
+    text: Option<&str>,
+) -> Result<bool, BotError> {
+    let result = sqlx::query(&format!(
+        "UPDATE tasks SET {} = ? WHERE name = ? AND chat_id = ? AND deleted_at IS NULL",
+        part.column()
+    ))
+    .bind(text)
+    .bind(name)
+    .bind(chat_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Fills in the `{task}` and `{date}` placeholders and escapes the result.
+fn render_template(template: &str, task_name: &str, now: DateTime<Utc>) -> String {
+    let text = template
+        .replace("{task}", task_name)
+        .replace("{date}", &now.format("%Y-%m-%d").to_string());
+    escape_markdown_v2(&text)
+}
+
+/// Wraps a task's formatted answer with its prefix and suffix templates.
+fn apply_task_templates(task: &ScheduledTask, formatted: String, now: DateTime<Utc>) -> String {
+    let mut wrapped = String::new();
+    if let Some(prefix) = &task.prefix {
+        wrapped.push_str(&render_template(prefix, &task.name, now));
+        wrapped.push_str("\n\n");
+    }
+    wrapped.push_str(&formatted);
+    if let Some(suffix) = &task.suffix {
+        wrapped.push_str("\n\n");
+        wrapped.push_str(&render_template(suffix, &task.name, now));
+    }
+    wrapped
+}
+
+fn format_group_response(
+    group: &str,
+    answers: &[(&ScheduledTask, XaiAnswer)],
+    now: DateTime<Utc>,
+) -> String {
+    let mut formatted = format!(
+        "🤖 *Group Response*\n\n\
+        🗂 *Group:* {}\n",
+        escape_markdown_v2(group)
+    );
+    for (task, answer) in answers {
+        let section = format!(
+            "📌 *Task:* {}\n\
+            ❓ *Question:* `{}`\n\n{}",
+            escape_markdown_v2(&task.name),
+            escape_markdown_v2(&task.question),
+            format_task_content(&answer.content, task.max_tokens)
+        );
+        formatted.push_str(&format!("\n{}\n", apply_task_templates(task, section, now)));
+    }
+    formatted
+}
+
+/// Runs a batch of tasks for one chat and sends their answers as a single
+/// message. Returns how many tasks produced an answer.
+async fn run_task_batch(
+    state: &AppState,
+    bot: &Bot,
+    batch: &[ScheduledTask],
+    now: DateTime<Utc>,
+) -> Result<usize, BotError> {
+    let Some(first) = batch.first() else {
+        return Ok(0);
+    };
+    let chat_id = first.chat_id;
+    let target = first.target();
+
+    // Don't spend an X.AI call on an answer nobody can receive
+    match check_chat_reachability(state, bot, target).await {
+        ChatReachability::Reachable => {}
+        ChatReachability::Unknown => {
+            log::warn!("Skipping task '{}' for now: chat {} could not be checked", first.name, target);
+            return Ok(0);
+        }
+        ChatReachability::Unreachable if first.target_chat_id.is_some() => {
+            for task in batch {
+                pause_lost_channel_task(state, bot, task).await?;
+            }
+            return Ok(0);
+        }
+        ChatReachability::Unreachable => {
+            for task in batch {
+                pause_unreachable_task(state, bot, &task.name, chat_id).await?;
+            }
+            return Ok(0);
+        }
+    }
+
+    let options = xai_options_for_chat(state, chat_id, None, &InlineOptions::default()).await?;
+    let patterns = get_chat_settings(&state.pool, chat_id)
+        .await?
+        .sanitize
+        .then_some(state.config.boilerplate_patterns.as_slice());
+    let mut answers = Vec::new();
+    for task in batch {
+        log::info!("Running task '{}' with question: {}", task.name, task.question);
+        match call_xai_api_with_usage(state, &task.question, &task.xai_options(&options)).await {
+            Ok(mut answer) => {
+                if let Some(patterns) = patterns {
+                    answer.content = strip_boilerplate(&answer.content, patterns);
+                }
+                answers.push((task, answer))
+            }
+            Err(e) => {
+                log::debug!("Failed to get X.AI response for task {}: {:?}", task.name, e);
+                log_repeated_error(state, &format!("Failed to get X.AI response for a task: {:?}", e));
+            }
+        }
+    }
+
+    let message = match (answers.as_slice(), &first.group) {
+        ([], _) => return Ok(0),
+        ([(task, answer)], None) => apply_task_templates(
+            task,
+            wrap_xai_response(
+                Some(&task.name),
+                &task.question,
+                &format_task_content(&answer.content, task.max_tokens),
+            ),
+            now,
+        ),
+        (answers, group) => format_group_response(group.as_deref().unwrap_or_default(), answers, now),
+    };
+    // Combined group answers easily pass Telegram's length limit
+    let sent = match send_long_message(bot, ChatId(target), &message).await {
+        Ok(sent) => sent,
+        Err(e) if first.target_chat_id.is_some() && is_lost_channel_rights(&e) => {
+            for (task, _) in &answers {
+                pause_lost_channel_task(state, bot, task).await?;
+            }
+            return Ok(0);
+        }
+        Err(e) if is_rejected_answer(&e) => {
+            // Asking X.AI again every poll would be billed without ever being delivered
+            log::error!("Telegram rejected the answer of task '{}', waiting a full interval: {:?}", first.name, e);
+            for (task, _) in &answers {
+                update_last_run(&state.pool, &task.name, now).await?;
+            }
+            return Ok(0);
+        }
+        Err(e) => {
+            log::error!("Failed to send task response: {:?}", e);
+            state.chat_cache.lock().unwrap().forget(target);
+            return Ok(0);
+        }
+    };
+    // Buttons and pins go on the first part of the answer
+    let Some(sent) = sent.first() else {
+        return Ok(0);
+    };
+
+    for (task, answer) in &answers {
+        match record_task_run(&state.pool, &task.name, chat_id, answer).await {
+            // A combined group message has no single run to rate
+            Ok(run_id) if answers.len() == 1 && first.group.is_none() => {
+                attach_rating_buttons(bot, ChatId(target), sent.id, RatingTarget::Run(run_id)).await;
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Failed to record run of task {}: {}", task.name, e),
+        }
+        if task.pin {
+            pin_task_answer(state, bot, task, sent.id).await;
+        }
+        update_last_run(&state.pool, &task.name, now).await?;
+    }
+
+    Ok(answers.len())
+}
+
+async fn update_last_run(pool: &SqlitePool, name: &str, now: DateTime<Utc>) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE tasks SET last_run = ? WHERE name = ?")
+        .bind(now.to_rfc3339())
+        .bind(name)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// How far in the future a `last_run` may lie before it is blamed on a clock
+/// change rather than small drift.
+const MAX_CLOCK_SKEW_MINUTES: i64 = 5;
+
+fn has_future_last_run(task: &ScheduledTask, now: DateTime<Utc>) -> bool {
+    task.last_run > now + chrono::Duration::minutes(MAX_CLOCK_SKEW_MINUTES)
+}
+
+/// Tasks whose `last_run` lies implausibly far ahead, e.g. after the clock was
+/// set back, would wait until the clock catches up. They are skipped this round
+/// and their `last_run` is reset to now so they run again one interval later.
+async fn reset_future_tasks(
+    pool: &SqlitePool,
+    tasks: Vec<ScheduledTask>,
+    now: DateTime<Utc>,
+) -> Result<Vec<ScheduledTask>, sqlx::Error> {
+    let mut valid = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        if has_future_last_run(&task, now) {
+            log::warn!(
+                "Task '{}' last ran at {} which is in the future, resetting it to {}",
+                task.name,
+                task.last_run,
+                now
+            );
+            update_last_run(pool, &task.name, now).await?;
+        } else {
+            valid.push(task);
+        }
+    }
+    Ok(valid)
+}
+
+/// Difference between wall-clock and monotonic time elapsed since the last
+/// poll, when it is large enough to mean the host slept or the clock moved.
+fn detect_clock_jump(wall_elapsed: chrono::Duration, monotonic_elapsed: Duration) -> Option<chrono::Duration> {
+    let drift = wall_elapsed - chrono::Duration::from_std(monotonic_elapsed).ok()?;
+    (drift.num_minutes().abs() >= MAX_CLOCK_SKEW_MINUTES).then_some(drift)
+}
+
+/// Runs every due task once. However many intervals a task missed, e.g. while
+/// the host slept, it runs a single time and its `last_run` becomes now.
+async fn check_and_run_tasks(state: State) -> Result<(), BotError> {
+    let now = Utc::now();
+    let tasks = reset_future_tasks(&state.pool, fetch_active_tasks(&state.pool).await?, now).await?;
+    let bot = Bot::new(env::var("TELEGRAM_BOT_TOKEN").unwrap());
+
+    let mut batches = plan_task_batches(tasks, now);
+    batches.sort_by(|a, b| compare_batch_urgency(a, b, now));
+    dispatch_task_batches(&state, &bot, batches, now).await
+}
+
+/// Runs every task flagged to run on startup once, regardless of its interval.
+async fn run_startup_tasks(state: State) -> Result<(), BotError> {
+    let tasks = fetch_startup_tasks(&state.pool).await?;
+    if tasks.is_empty() {
+        return Ok(());
+    }
+    log::info!("Running {} startup tasks", tasks.len());
+
+    let now = Utc::now();
+    let bot = Bot::new(env::var("TELEGRAM_BOT_TOKEN").unwrap());
+    let mut batches: Vec<Vec<ScheduledTask>> = tasks.into_iter().map(|task| vec![task]).collect();
+    batches.sort_by(|a, b| compare_batch_urgency(a, b, now));
+    dispatch_task_batches(&state, &bot, batches, now).await
+}
+
+/// Runs batches through a pool of at most `max_concurrent_tasks` at a time.
+async fn dispatch_task_batches(
+    state: &State,
+    bot: &Bot,
+    batches: Vec<Vec<ScheduledTask>>,
+    now: DateTime<Utc>,
+) -> Result<(), BotError> {
+    // Permits are taken in dispatch order, so urgent batches start first
+    let pool = Arc::new(Semaphore::new(state.config.max_concurrent_tasks.max(1)));
+    let mut running = Vec::with_capacity(batches.len());
+    for batch in batches {
+        let permit = Arc::clone(&pool)
+            .acquire_owned()
+            .await
+            .map_err(|e| BotError::Other(e.into()))?;
+        let state = Arc::clone(state);
+        let bot = bot.clone();
+        running.push(tokio::spawn(async move {
+            let _permit = permit;
+            run_task_batch(&state, &bot, &batch, now).await
+        }));
+    }
+
+    for handle in running {
+        match handle.await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => log::error!("Error running task batch: {}", e),
+            Err(e) => log::error!("Task batch panicked: {}", e),
+        }
+    }
+    Ok(())
+}
+
+async fn try_connect_bot(token: &str, retries: u32, delay: Duration) -> Result<Bot, BotError> {
+    let mut attempt = 0;
+    loop {
+        match Bot::new(token).get_me().await {
+            Ok(_) => {
+                log::info!("Successfully connected to Telegram API");
+                return Ok(Bot::new(token));
+            }
+            Err(e) => {
+                attempt += 1;
+                if attempt >= retries {
+                    return Err(BotError::TelegramError(e));
+                }
+                log::warn!(
+                    "Failed to connect to Telegram API (attempt {}/{}): {:?}",
+                    attempt,
+                    retries,
+                    e
+                );
+                sleep(delay).await;
+            }
+        }
+    }
+}
+
+async fn run_bot(bot: Bot, state: State) -> Result<(), BotError> {
+    let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .filter_command::<Command>()
+                .endpoint(handle_command),
+        )
+        .branch(Update::filter_message().endpoint(handle_unknown_command))
+        .branch(Update::filter_callback_query().endpoint(handle_callback))
+        .branch(Update::filter_inline_query().endpoint(handle_inline_query));
+
+    Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![state])
+        // Other updates are of no interest
+        .default_handler(|_| async {})
+        .enable_ctrlc_handler()
+        .build()
+        .dispatch()
+        .await;
+    Ok(())
+}
+
+async fn run_with_retry(state: State, telegram_token: String) {
+    let retry_delay = Duration::from_secs(5);
+    let max_retries = 5;
+
+    loop {
+        log::info!("Attempting to start bot...");
+
+        match try_connect_bot(&telegram_token, max_retries, retry_delay).await {
+            Ok(bot) => match run_bot(bot, Arc::clone(&state)).await {
+                Ok(_) => {
+                    log::info!("Bot stopped gracefully");
+                    break;
+                }
+                Err(e) => {
+                    log::error!(
+                        "Bot crashed: {:?}. Restarting in {} seconds...",
+                        e,
+                        retry_delay.as_secs()
+                    );
+                    sleep(retry_delay).await;
+                }
+            },
+            Err(e) => {
+                log::error!(
+                    "Failed to connect to Telegram API after {} attempts: {:?}",
+                    max_retries,
+                    e
+                );
+                log::info!("Retrying in {} seconds...", retry_delay.as_secs());
+                sleep(retry_delay).await;
+            }
+        }
+    }
+}
+
+fn format_bot_stats(stats: &Value) -> String {
+    format_command_stats("*📊 Bot Usage Statistics*", stats)
+}
+
+fn format_chat_stats(stats: &Value) -> String {
+    format_command_stats("*📊 Chat Usage Statistics*", stats)
+}
+
+fn format_command_stats(title: &str, stats: &Value) -> String {
+    let mut formatted = format!("{}\n\n", title);
+
+    if let Some(commands) = stats["commands"].as_array() {
+        for cmd in commands {
+            formatted.push_str(&format!(
+                "🔷 *{}*\n\
+                  ├ Usage Count: {}\n\
+                  ├ Avg Response: {}ms\n\
+                  └ Error Rate: {}%\n\n",
+                escape_markdown_v2(cmd["command"].as_str().unwrap_or("unknown")),
+                cmd["usage_count"].as_i64().unwrap_or(0),
+                escape_markdown_v2(&format!("{:.2}", cmd["avg_execution_time_ms"].as_f64().unwrap_or(0.0))),
+                escape_markdown_v2(&format!("{:.2}", cmd["error_rate"].as_f64().unwrap_or(0.0)))
+            ));
+        }
+    }
+
+    formatted
+}
+
+fn format_user_stats(stats: &Value) -> String {
+    format!(
+        "*📊 Your Usage Statistics*\n\n\
+        📈 *Total Commands:* {}\n\
+        📅 *Active Days:* {}\n\
+        ⚡ *Average Response Time:* {}\n\
+        ❌ *Error Rate:* {}",
+        stats["total_commands"].as_i64().unwrap_or(0),
+        stats["active_days"].as_i64().unwrap_or(0),
+        escape_markdown_v2(&format!("{:.2}ms", stats["avg_execution_time_ms"].as_f64().unwrap_or(0.0))),
+        escape_markdown_v2(&format!("{:.2}%", stats["error_rate"].as_f64().unwrap_or(0.0)))
+    )
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "info");
+    }
+    pretty_env_logger::init();
+
+    log::info!("Starting task bot...");
+
+    let telegram_token = env::var("TELEGRAM_BOT_TOKEN")
+        .context("TELEGRAM_BOT_TOKEN not found in environment variables or .env file")?;
+    let xai_token = env::var("XAI_API_TOKEN")
+        .context("XAI_API_TOKEN not found in environment variables or .env file")?;
+    
+    // Add owner ID initialization
+    let owner_id = parse_owner_id(
+        &env::var("BOT_OWNER_ID").context("BOT_OWNER_ID not found in environment variables or .env file")?,
+    )?;
+
+    let (db_path, configured) = database_path();
+    let pool = initialize_database(&db_path, configured).await?;
+
+    let config = Config::from_env()?;
+
+    let mut state = AppState::new(pool, xai_token, owner_id, config);
+    if let Some(replica) = connect_read_replica().await? {
+        state = state.with_read_pool(replica);
+    }
+    let state = Arc::new(state);
+
+    let state_clone = Arc::clone(&state);
+
+    tokio::spawn(async move {
+        if let Err(e) = run_startup_tasks(Arc::clone(&state_clone)).await {
+            log::error!("Error running startup tasks: {}", e);
+        }
+        let mut last_poll = (Utc::now(), Instant::now());
+        loop {
+            let poll = (Utc::now(), Instant::now());
+            if let Some(jump) = detect_clock_jump(poll.0 - last_poll.0, poll.1 - last_poll.1) {
+                log::warn!("Clock moved by {} minutes since the last task check", jump.num_minutes());
+            }
+            last_poll = poll;
+            if let Err(e) = check_and_run_tasks(Arc::clone(&state_clone)).await {
+                log_repeated_error(&state_clone, &format!("Error checking tasks: {}", e));
+            }
+            for summary in state_clone.repeated_errors.lock().unwrap().flush(Instant::now()) {
+                log::error!("{}", summary);
+            }
+            match purge_deleted_tasks(&state_clone.pool, deletion_cutoff(&state_clone.config)).await {
+                Ok(0) => {}
+                Ok(purged) => log::info!("Purged {} deleted tasks past the grace period", purged),
+                Err(e) => log::error!("Error purging deleted tasks: {}", e),
+            }
+            sleep(TASK_POLL_INTERVAL).await;
+        }
+    });
+
+    log::info!("Bot started successfully!");
+
+    run_with_retry(state, telegram_token).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Row;
+
+    #[test]
+    fn test_escape_markdown_v2() {
+        let input = "Hello *world* with [link] and (parens)";
+        let escaped = escape_markdown_v2(input);
+        assert_eq!(escaped, r"Hello \*world\* with \[link\] and \(parens\)");
+    }
+
+    #[test]
+    fn test_format_response_content() {
+        // Test list formatting with debug output
+        let list_input = "Items:\n- First item\n- *Second* item";
+        let formatted = format_response_content(list_input);
+        println!("Formatted output: {}", formatted);
+        
+        // Test list items - asterisks are preserved for formatting
+        assert!(formatted.contains("• First item")); 
+        assert!(formatted.contains("• *Second* item")); // Markdown formatting is preserved
+    
+        // Test paragraph formatting
+        let text_with_formatting = "Here is *bold* and `code` text";
+        let formatted_text = format_response_content(text_with_formatting);
+        assert!(formatted_text.contains("Here is *bold* and `code` text")); // Markdown formatting is preserved
+    
+        // Test multiple paragraphs with lists
+        let multi_paragraph = "First paragraph\n\nList:\n- Item 1\n- *Item* 2\n\nLast paragraph";
+        let formatted_multi = format_response_content(multi_paragraph);
+        assert!(formatted_multi.contains("First paragraph"));
+        assert!(formatted_multi.contains("• Item 1"));
+        assert!(formatted_multi.contains("• *Item* 2")); // Markdown formatting is preserved
+        assert!(formatted_multi.contains("Last paragraph"));
+    
+        // Test special characters are escaped but formatting is preserved
+        let mixed_content = "Here's a *bold* statement with some (parentheses)";
+        let formatted_mixed = format_response_content(mixed_content);
+        assert!(formatted_mixed.contains("Here\\'s a *bold* statement with some \\(parentheses\\)")); // Special chars escaped, formatting preserved
+    }
+
+    #[test]
+    fn test_format_xai_response() {
+        let question = "What's the price?";
+        let response = "Bitcoin is at $50,000";
+
+        // Test with task name
+        let with_task = format_xai_response(Some("price_check"), question, response);
+        assert!(with_task.contains("price\\_check"));
+        assert!(with_task.contains("What\\'s the price\\?"));
+        assert!(with_task.contains("Bitcoin is at \\$50\\,000"));
+
+        // Test without task name
+        let without_task = format_xai_response(None, question, response);
+        assert!(!without_task.contains("Task:"));
+        assert!(without_task.contains("Question:"));
+        assert!(without_task.contains("Answer:"));
+    }
+
+    #[test]
+    fn test_help_message() {
+        let help = format_help_message();
+        assert!(help.contains("/help"));
+        assert!(help.contains("/create"));
+        assert!(help.contains("/list"));
+        assert!(help.contains("/delete"));
+        assert!(help.contains("/ask"));
+    }
+
+    /// Unescaped `*`, `_` and `` ` `` must pair up within a MarkdownV2 message.
+    /// Inside code only backticks count, everything else there is literal.
+    fn has_balanced_markdown(text: &str) -> bool {
+        let mut counts = HashMap::new();
+        let mut escaped = false;
+        let mut in_code = false;
+        for c in text.chars() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '`' => {
+                    in_code = !in_code;
+                    *counts.entry(c).or_insert(0) += 1;
+                }
+                '*' | '_' if !in_code => *counts.entry(c).or_insert(0) += 1,
+                _ => {}
+            }
+        }
+        counts.values().all(|count| count % 2 == 0)
+    }
+
+    #[test]
+    fn test_render_test_sample_is_balanced() {
+        let formatted = format_response_content(RENDER_TEST_SAMPLE);
+        assert!(has_balanced_markdown(&formatted), "unbalanced sample: {}", formatted);
+        assert!(!formatted.ends_with('\\'));
+        assert!(formatted.contains("*Render test*"));
+        // Bold at the start of a line is not a bullet
+        assert!(formatted.contains("\n**Bold**\\, *italic*"));
+        assert!(formatted.contains("[the Rust book](https://doc.rust-lang.org/book/)"));
+        assert!(formatted.contains("\\| alpha"));
+
+        let message = format_xai_response(None, "Render test", RENDER_TEST_SAMPLE);
+        assert!(has_balanced_markdown(&message));
+        assert!(message.chars().count() <= TELEGRAM_MESSAGE_LIMIT);
+    }
+
+    #[test]
+    fn test_unknown_command_reply() {
+        // Private chats always get a hint
+        let reply = unknown_command_reply("/frobnicate now", true, "wibot").unwrap();
+        assert!(reply.contains("Unknown command /frobnicate"));
+        assert!(reply.contains("/help"));
+        assert!(unknown_command_reply("/frobnicate@WiBot", true, "wibot").is_some());
+
+        // Groups stay quiet unless the bot is mentioned
+        assert_eq!(unknown_command_reply("/frobnicate", false, "wibot"), None);
+        assert!(unknown_command_reply("/frobnicate@wibot", false, "wibot").is_some());
+        assert!(unknown_command_reply("/frobnicate please @WIBOT", false, "wibot").is_some());
+        assert!(unknown_command_reply("/frobnicate @wibot, thanks", false, "wibot").is_some());
+        assert_eq!(unknown_command_reply("/frobnicate @wibot_fan", false, "wibot"), None);
+        assert_eq!(unknown_command_reply("/frobnicate @wibotnews", false, "wibot"), None);
+
+        // Other bots' commands and plain text are never answered
+        assert_eq!(unknown_command_reply("/frobnicate@otherbot", true, "wibot"), None);
+        assert_eq!(unknown_command_reply("/frobnicate@otherbot", false, "wibot"), None);
+        assert_eq!(unknown_command_reply("hello @wibot", true, "wibot"), None);
+        assert_eq!(unknown_command_reply("/", true, "wibot"), None);
+
+        // Commands are matched case-sensitively, so /List isn't /list
+        assert!(Command::parse("/List", "wibot").is_err());
+        let reply = unknown_command_reply("/List", true, "wibot").unwrap();
+        assert!(reply.contains("Unknown command /List"));
+    }
+
+    #[test]
+    fn test_split_long_help_message() {
+        let help = format_help_message();
+        let long_help = [help.as_str(); 6].join("\n\n");
+        assert!(long_help.chars().count() > TELEGRAM_MESSAGE_LIMIT);
+
+        let chunks = split_markdown_message(&long_help, TELEGRAM_MESSAGE_LIMIT);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= TELEGRAM_MESSAGE_LIMIT);
+            assert!(has_balanced_markdown(chunk), "unbalanced chunk: {}", chunk);
+        }
+        // Nothing is lost, only the line breaks at the cuts
+        let joined: String = chunks.concat().split_whitespace().collect();
+        let original: String = long_help.split_whitespace().collect();
+        assert_eq!(joined, original);
+
+        assert_eq!(split_markdown_message(&help, TELEGRAM_MESSAGE_LIMIT), vec![help.clone()]);
+    }
+
+    #[test]
+    fn test_split_markdown_code_block() {
+        let code: Vec<String> = (0..30).map(|i| format!("line {}", i)).collect();
+        let text = format!("*Output*\n```json\n{}\n```\nDone\\.", code.join("\n"));
+        let chunks = split_markdown_message(&text, 60);
+        assert!(chunks.len() > 2);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 60);
+            assert_eq!(chunk.matches(CODE_FENCE).count() % 2, 0, "unclosed code block: {}", chunk);
+        }
+        assert!(chunks[1].starts_with("```json\n"));
+
+        let long_line = format!("{}\\. end", "word ".repeat(30));
+        for piece in split_long_line(&long_line, 20, true) {
+            assert!(piece.chars().count() <= 20);
+            assert!(!piece.ends_with('\\'));
+        }
+    }
+
+    #[test]
+    fn test_split_long_span() {
+        // One bold phrase and one link crossing every possible cut
+        let words = "word ".repeat(60);
+        let line = format!(
+            "Intro *bold {}end* then _italic `code {}` done_ and [a link](https://example.com/x) {}\\. end",
+            words, words, words
+        );
+        let chunks = split_markdown_message(&line, 200);
+        assert!(chunks.len() > 3);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 200);
+            assert!(has_balanced_markdown(chunk), "unbalanced chunk: {}", chunk);
+            assert!(!chunk.ends_with('\\'));
+        }
+        assert!(chunks[1].starts_with("*word"));
+        assert!(chunks.iter().any(|chunk| chunk.contains("[a link](https://example.com/x)")));
+        let joined: String = chunks.concat().split_whitespace().collect();
+        assert!(joined.starts_with("Intro*boldwordword"));
+
+        // Lines of a code block are cut without adding markers
+        let code = format!("```\nlet s = \"{}*\";\n```", "a_b ".repeat(100));
+        for chunk in split_markdown_message(&code, 200) {
+            assert!(chunk.chars().count() <= 200);
+            assert_eq!(chunk.matches(CODE_FENCE).count(), 2, "unclosed code block: {}", chunk);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_database_operations() -> Result<()> {
+        // Setup in-memory database for testing
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS tasks (
+                name TEXT PRIMARY KEY,
+                description TEXT NOT NULL,
+                interval INTEGER NOT NULL,
+                last_run TEXT NOT NULL,
+                chat_id INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        // Test task creation
+        let result = sqlx::query(
+            "INSERT INTO tasks (name, description, interval, last_run, chat_id) VALUES (?, ?, ?, ?, ?)"
+        )
+        .bind("test_task")
+        .bind("test description")
+        .bind(60)
+        .bind(Utc::now().to_rfc3339())
+        .bind(123456789)
+        .execute(&pool)
+        .await;
+
+        assert!(result.is_ok());
+
+        // Test task retrieval
+        let task = sqlx::query("SELECT * FROM tasks WHERE name = ?")
+            .bind("test_task")
+            .fetch_one(&pool)
+            .await?;
+
+        assert_eq!(task.get::<String, _>("name"), "test_task");
+        assert_eq!(task.get::<i64, _>("interval"), 60);
+
+        // Test task deletion
+        let delete_result = sqlx::query("DELETE FROM tasks WHERE name = ?")
+            .bind("test_task")
+            .execute(&pool)
+            .await?;
+
+        assert_eq!(delete_result.rows_affected(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_task_scheduling() -> Result<()> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS tasks (
+                name TEXT PRIMARY KEY,
+                description TEXT NOT NULL,
+                interval INTEGER NOT NULL,
+                last_run TEXT NOT NULL,
+                chat_id INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        let now = Utc::now();
+
+        // Create a task that should run
+        sqlx::query(
+            "INSERT INTO tasks (name, description, interval, last_run, chat_id) VALUES (?, ?, ?, ?, ?)"
+        )
+        .bind("schedule_test")
+        .bind("test description")
+        .bind(1) // 1 minute interval
+        .bind(now.checked_sub_signed(chrono::Duration::minutes(2)).unwrap().to_rfc3339())
+        .bind(123456789)
+        .execute(&pool)
+        .await?;
+
+        // Check if task should run
+        let task = sqlx::query("SELECT * FROM tasks WHERE name = ?")
+            .bind("schedule_test")
+            .fetch_one(&pool)
+            .await?;
+
+        let last_run: DateTime<Utc> = task.get::<String, _>("last_run").parse()?;
+        let interval: i64 = task.get("interval");
+        let duration_since_last = now.signed_duration_since(last_run);
+
+        assert!(duration_since_last.num_minutes() >= interval);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parse_create_command() {
+        let valid_input = "test_task 30 What is the weather?".to_string();
+        let result = parse_create_command(valid_input).await;
+        assert!(result.is_some());
+
+        if let Some((name, interval, question)) = result {
+            assert_eq!(name, "test_task");
+            assert_eq!(interval, 30);
+            assert_eq!(question, "What is the weather?");
+        }
+
+        let invalid_input = "invalid command".to_string();
+        let result = parse_create_command(invalid_input).await;
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_format_task_list() -> Result<()> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS tasks (
+                name TEXT PRIMARY KEY,
+                description TEXT NOT NULL,
+                interval INTEGER NOT NULL,
+                last_run TEXT NOT NULL,
+                chat_id INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        let timestamp = "2024-02-20T12:00:00Z";
+        
+        sqlx::query(
+            "INSERT INTO tasks (name, description, interval, last_run, chat_id) VALUES (?, ?, ?, ?, ?)"
+        )
+        .bind("test_task")
+        .bind("What is the weather?")
+        .bind(30)
+        .bind(timestamp)
+        .bind(123456789)
+        .execute(&pool)
+        .await?;
+
+        let tasks = sqlx::query("SELECT name, description as question, interval, last_run FROM tasks")
+            .fetch_all(&pool)
+            .await?;
+
+        let formatted = format_task_list(&tasks);
+
+        assert!(formatted.contains("test\\_task"));
+        assert!(formatted.contains("30 minutes"));
+        assert!(formatted.contains("What is the weather\\?"));
+        assert!(formatted.contains(&escape_markdown_v2(timestamp)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_task_list() -> Result<()> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS tasks (
+                name TEXT PRIMARY KEY,
+                description TEXT NOT NULL,
+                interval INTEGER NOT NULL,
+                last_run TEXT NOT NULL,
+                chat_id INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        let tasks =
+            sqlx::query("SELECT name, description as question, interval, last_run FROM tasks")
+                .fetch_all(&pool)
+                .await?;
+
+        let formatted = format_task_list(&tasks);
+        assert!(formatted.contains("No tasks found"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_markdown_escaping() {
+        let special_chars = "._*[]()~`>#+-=|{}.!";
+        let escaped = escape_markdown_v2(special_chars);
+        assert_eq!(escaped, r"\.\_\*\[\]\(\)\~\`\>\#\+\-\=\|\{\}\.\!");
+
+        // Test individual characters
+        assert_eq!(escape_markdown_v2("."), r"\.");
+        assert_eq!(escape_markdown_v2("*"), r"\*");
+        assert_eq!(escape_markdown_v2("_"), r"\_");
+        assert_eq!(escape_markdown_v2("["), r"\[");
+        assert_eq!(escape_markdown_v2("]"), r"\]");
+        assert_eq!(escape_markdown_v2("("), r"\(");
+        assert_eq!(escape_markdown_v2(")"), r"\)");
+        assert_eq!(escape_markdown_v2("~"), r"\~");
+        assert_eq!(escape_markdown_v2("`"), r"\`");
+        assert_eq!(escape_markdown_v2(">"), r"\>");
+        assert_eq!(escape_markdown_v2("#"), r"\#");
+        assert_eq!(escape_markdown_v2("+"), r"\+");
+        assert_eq!(escape_markdown_v2("-"), r"\-");
+        assert_eq!(escape_markdown_v2("="), r"\=");
+        assert_eq!(escape_markdown_v2("|"), r"\|");
+        assert_eq!(escape_markdown_v2("{"), r"\{");
+        assert_eq!(escape_markdown_v2("}"), r"\}");
+        assert_eq!(escape_markdown_v2("!"), r"\!");
+    }
+
+    #[tokio::test]
+    async fn test_create_command_validation() {
+        // Valid command
+        let valid = parse_create_command("weather 60 What's the weather like?".to_string()).await;
+        assert!(valid.is_some());
+        if let Some((name, interval, question)) = valid {
+            assert_eq!(name, "weather");
+            assert_eq!(interval, 60);
+            assert_eq!(question, "What's the weather like?");
+        }
+
+        // Invalid commands
+        let invalid_cases = vec![
+            "weather".to_string(),
+            "weather 60".to_string(),
+            "weather invalid 60".to_string(),
+            "weather 0 What's the weather like?".to_string(),
+            "weather 43201 What's the weather like?".to_string(),
+            "".to_string(),
+            "weather 60 ".to_string(),
+            "weather 60    ".to_string(),
+            "   ".to_string(),
+        ];
+
+        for case in invalid_cases {
+            assert!(parse_create_command(case).await.is_none());
+        }
+    }
+
+    #[test]
+    fn test_xai_response_formatting() {
+        let response = format_xai_response(
+            Some("crypto_check"),
+            "What's the BTC price?",
+            "Bitcoin is at $50,000"
+        );
+
+        assert!(response.contains("crypto\\_check"));
+        assert!(response.contains("What\\'s the BTC price\\?"));
+        assert!(response.contains("Bitcoin is at \\$50\\,000"));
+
+        let without_task = format_xai_response(
+            None, 
+            "What's the BTC price?",
+            "Bitcoin is at $50,000"
+        );
+
+        assert!(!without_task.contains("Task:"));
+        assert!(without_task.contains("Question:"));
+        assert!(without_task.contains("Answer:"));
+    }
+
+    #[test]
+    fn test_special_character_escaping() {
+        let text = "What's this? It's a test!";
+        let escaped = escape_markdown_v2(text);
+        assert_eq!(escaped, r"What\'s this\? It\'s a test\!");
+    }
+
+    #[test]
+    fn test_markdown_links() {
+        let response = "See [the docs](https://example.com/a_b) for details [stray";
+        let formatted = format_response_content(response);
+        assert!(formatted.contains("See [the docs](https://example.com/a_b) for details"));
+        assert!(formatted.contains("\\[stray"));
+
+        // Labels are escaped, URLs only escape ')' and '\'
+        let formatted = process_markdown_formatting("[v1.0 (beta)](https://example.com/wiki/Rust_(language))");
+        assert_eq!(
+            formatted,
+            r"[v1\.0 \(beta\)](https://example.com/wiki/Rust_(language\))"
+        );
+
+        // Brackets that are not followed by a valid URL stay literal
+        assert_eq!(process_markdown_formatting("[a] (b)"), r"\[a\] \(b\)");
+        assert_eq!(process_markdown_formatting("[a](not a url)"), r"\[a\]\(not a url\)");
+    }
+
+    #[test]
+    fn test_inline_code_is_verbatim() {
+        let formatted = process_markdown_formatting("Run `a.b!c` now.");
+        assert_eq!(formatted, r"Run `a.b!c` now\.");
+
+        // Formatting characters and links inside code stay literal
+        assert_eq!(
+            process_markdown_formatting("`*not bold* [x](https://a.b)`"),
+            "`*not bold* [x](https://a.b)`"
+        );
+
+        // Only backslashes need escaping inside code
+        assert_eq!(process_markdown_formatting(r"`C:\path`"), r"`C:\\path`");
+
+        let formatted = format_response_content("Use `std::fs::read(path)?` to load it.");
+        assert!(formatted.contains("`std::fs::read(path)?`"));
+        assert!(formatted.contains(r"to load it\."));
+    }
+
+    async fn setup_test_db() -> Result<SqlitePool> {
+        // A single connection keeps every query on the same in-memory database
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        create_schema(&pool).await?;
+        Ok(pool)
+    }
+
+    fn test_answer(content: &str) -> XaiAnswer {
+        XaiAnswer {
+            content: content.to_string(),
+            total_tokens: None,
+            model: String::from("grok-beta"),
+        }
+    }
+
+    fn test_state(pool: SqlitePool, config: Config) -> AppState {
+        AppState::new(pool, String::from("test-token"), 1, config)
+    }
+
+    async fn insert_log_at(pool: &SqlitePool, chat_id: i64, timestamp: DateTime<Utc>) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO bot_logs (timestamp, chat_id, user_id, command, execution_time_ms) VALUES (?, ?, ?, ?, ?)"
+        )
+        .bind(timestamp.to_rfc3339())
+        .bind(chat_id)
+        .bind(42)
+        .bind("List")
+        .bind(10)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_transcript_order() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let entries = [
+            ("2024-03-02T09:00:00+00:00", 1, 42, Some("second")),
+            ("2024-03-01T09:00:00+00:00", 1, 42, Some("first")),
+            ("2024-03-03T09:00:00+00:00", 1, 7, Some("someone else")),
+            ("2024-03-04T09:00:00+00:00", 2, 42, Some("other chat")),
+            ("2024-03-05T09:00:00+00:00", 1, 42, None),
+            ("2024-03-02T09:00:00+00:00", 1, 42, Some("third")),
+        ];
+        for (timestamp, chat_id, user_id, args) in entries {
+            sqlx::query(
+                "INSERT INTO bot_logs (timestamp, chat_id, user_id, command, args, execution_time_ms) VALUES (?, ?, ?, 'Ask', ?, 10)"
+            )
+            .bind(timestamp)
+            .bind(chat_id)
+            .bind(user_id)
+            .bind(args)
+            .execute(&pool)
+            .await?;
+        }
+
+        let state = test_state(pool, Config::default());
+        let own = fetch_transcript(&state, 1, Some(42)).await?;
+        let args: Vec<_> = own.iter().map(|entry| entry.args.as_deref().unwrap()).collect();
+        assert_eq!(args, vec!["first", "second", "third"]);
+
+        let whole_chat = fetch_transcript(&state, 1, None).await?;
+        assert_eq!(whole_chat.len(), 4);
+        assert_eq!(whole_chat[3].args.as_deref(), Some("someone else"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_transcript() {
+        let entries = vec![
+            TranscriptEntry {
+                timestamp: String::from("2024-03-01T09:00:00+00:00"),
+                user_id: Some(42),
+                username: Some(String::from("alice")),
+                command: String::from("Ask"),
+                args: Some(String::from("What's new?")),
+                response: Some(String::from("Nothing much.\n")),
+                error: None,
+            },
+            TranscriptEntry {
+                timestamp: String::from("2024-03-01T10:30:00+02:00"),
+                user_id: Some(42),
+                username: None,
+                command: String::from("Create"),
+                args: Some(String::from("news 60 headlines")),
+                response: None,
+                error: Some(String::from("A task with this name already exists")),
+            },
+        ];
+        let exported_at = DateTime::parse_from_rfc3339("2024-03-02T00:00:00Z").unwrap().with_timezone(&Utc);
+
+        assert_eq!(
+            format_transcript(-100, &entries, exported_at),
+            "Transcript of chat -100\n\
+             Exported 2024-03-02 00:00:00 UTC with 2 interactions\n\
+             \n\
+             [2024-03-01 09:00:00 UTC] @alice: /ask What's new?\n\
+             Bot: Nothing much.\n\
+             \n\
+             [2024-03-01 08:30:00 UTC] 42: /create news 60 headlines\n\
+             Error: A task with this name already exists\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_logs() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let entries = [
+            ("2024-02-19T10:00:00+00:00", "Ask", "Bitcoin price?", "BTC is at 50k"),
+            ("2024-02-20T10:00:00+00:00", "Ask", "weather", "Sunny, no bitcoin here"),
+            ("2024-02-21T23:59:00+00:00", "Create", "btc 60 bitcoin", "BTC: 51k"),
+            ("2024-02-21T12:00:00+00:00", "Ask", "100% sure?", "Yes"),
+        ];
+        for (timestamp, command, args, response) in entries {
+            sqlx::query(
+                "INSERT INTO bot_logs (timestamp, chat_id, user_id, command, args, response, execution_time_ms) VALUES (?, 1, 42, ?, ?, ?, 10)"
+            )
+            .bind(timestamp)
+            .bind(command)
+            .bind(args)
+            .bind(response)
+            .execute(&pool)
+            .await?;
+        }
+
+        let state = test_state(pool, Config::default());
+        let all = search_logs(&state, &parse_search_command("BITCOIN")?).await?;
+        let commands: Vec<&str> = all.iter().map(|found| found.command.as_str()).collect();
+        assert_eq!(commands, vec!["Create", "Ask", "Ask"]);
+
+        let ranged = search_logs(&state, &parse_search_command("--from=2024-02-20 --to=2024-02-21 bitcoin")?).await?;
+        assert_eq!(ranged.len(), 2);
+        assert_eq!(ranged[1].snippet, "Sunny, no bitcoin here");
+
+        let until = search_logs(&state, &parse_search_command("--to=2024-02-19 bitcoin")?).await?;
+        assert_eq!(until.len(), 1);
+
+        // LIKE wildcards in the keyword match literally
+        assert_eq!(search_logs(&state, &parse_search_command("%")?).await?.len(), 1);
+
+        assert!(parse_search_command("--from=yesterday btc").is_err());
+        assert!(parse_search_command("--from=2024-02-21 --to=2024-02-20 btc").is_err());
+        assert!(parse_search_command("--from=2024-02-21").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_boilerplate() {
+        let patterns = default_boilerplate_patterns();
+        assert_eq!(
+            strip_boilerplate("Sure! Here's the latest on Bitcoin: BTC trades at $50,000.", &patterns),
+            "BTC trades at $50,000."
+        );
+        assert_eq!(
+            strip_boilerplate(
+                "Certainly!\n\n- *BTC*: $50,000\n- *ETH*: $3,000\n\nLet me know if you need anything else!",
+                &patterns
+            ),
+            "- *BTC*: $50,000\n- *ETH*: $3,000"
+        );
+
+        // Phrases inside the answer are content, not boilerplate
+        let answer = "Analysts said: sure, here's the thing: rates stay high.\nLet me know if rates change, the report says.\nFinal line.";
+        assert_eq!(strip_boilerplate(answer, &patterns), answer);
+        assert_eq!(strip_boilerplate("Surely the market will recover.", &patterns), "Surely the market will recover.");
+
+        // Nothing but boilerplate is kept as is
+        assert_eq!(strip_boilerplate("Sure, here it is.", &patterns), "Sure, here it is.");
+
+        let custom = vec![Regex::new(r"(?i)^great question[!.]\s*").unwrap()];
+        assert_eq!(strip_boilerplate("Great question! It's 42.", &custom), "It's 42.");
+    }
+
+    #[tokio::test]
+    async fn test_chat_sanitize_setting() -> Result<()> {
+        let pool = setup_test_db().await?;
+        assert!(!get_chat_settings(&pool, 1).await?.sanitize);
+        set_chat_sanitize(&pool, 1, true).await?;
+        let settings = get_chat_settings(&pool, 1).await?;
+        assert!(settings.sanitize);
+        assert!(format_chat_settings(&settings, &Config::default()).contains("*Sanitizer:* on"));
+        assert!(!get_chat_settings(&pool, 2).await?.sanitize);
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_cooldown() {
+        let mut cooldowns = CommandCooldowns::default();
+        let cooldown = Duration::from_secs(10);
+        let start = Instant::now();
+
+        assert!(cooldowns.check(1, 42, cooldown, start).is_ok());
+        assert_eq!(
+            cooldowns.check(1, 42, cooldown, start + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+        // Other users and the same user in other chats are unaffected
+        assert!(cooldowns.check(1, 43, cooldown, start + Duration::from_secs(4)).is_ok());
+        assert!(cooldowns.check(2, 42, cooldown, start + Duration::from_secs(4)).is_ok());
+        // A blocked command doesn't extend the cooldown
+        assert!(cooldowns.check(1, 42, cooldown, start + Duration::from_secs(10)).is_ok());
+        assert!(cooldowns.check(1, 42, cooldown, start + Duration::from_secs(12)).is_err());
+
+        // No cooldown never blocks
+        assert!(cooldowns.check(3, 42, Duration::ZERO, start).is_ok());
+        assert!(cooldowns.check(3, 42, Duration::ZERO, start).is_ok());
+
+        assert!(parse_cooldown("3601").is_err());
+        assert_eq!(parse_cooldown(" 10 ").ok(), Some(cooldown));
+    }
+
+    #[tokio::test]
+    async fn test_chat_cooldown_setting() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let config = Config {
+            command_cooldown: Duration::from_secs(5),
+            ..Config::default()
+        };
+        let state = test_state(pool.clone(), config);
+
+        assert!(check_command_cooldown(&state, 1, Some(42)).await.is_ok());
+        assert!(matches!(
+            check_command_cooldown(&state, 1, Some(42)).await,
+            Err(BotError::CooldownActive(_))
+        ));
+        // The owner is never slowed down
+        assert!(check_command_cooldown(&state, 1, Some(state.owner_id)).await.is_ok());
+        assert!(check_command_cooldown(&state, 1, Some(state.owner_id)).await.is_ok());
+
+        set_chat_cooldown(&pool, 2, Some(Duration::ZERO)).await?;
+        assert!(check_command_cooldown(&state, 2, Some(42)).await.is_ok());
+        assert!(check_command_cooldown(&state, 2, Some(42)).await.is_ok());
+        assert!(format_chat_settings(&get_chat_settings(&pool, 2).await?, &state.config)
+            .contains("*Command cooldown:* off"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_latency_tracker_ema() {
+        let mut tracker = LatencyTracker::new(0.5);
+        assert!(format_status(&tracker).contains("No X"));
+
+        for ms in [100, 200, 400, 400] {
+            tracker.record(Duration::from_millis(ms));
+        }
+        // 100 -> 150 -> 275 -> 337.5
+        assert!((tracker.average_ms.unwrap() - 337.5).abs() < 1e-9);
+        assert_eq!(tracker.last_ms, Some(400.0));
+        assert_eq!(tracker.samples, 4);
+
+        let mut smooth = LatencyTracker::new(0.1);
+        smooth.record(Duration::from_millis(1000));
+        smooth.record(Duration::from_millis(2000));
+        assert!((smooth.average_ms.unwrap() - 1100.0).abs() < 1e-9);
+        assert!(format_status(&smooth).contains("Average: 1100 ms"));
+    }
+
+    #[test]
+    fn test_extract_snippet() {
+        let text = "The quick brown fox jumps over the lazy dog";
+        assert_eq!(extract_snippet(text, "FOX", 6).as_deref(), Some("…brown fox jumps…"));
+        assert_eq!(extract_snippet(text, "the", 4).as_deref(), Some("The qui…"));
+        assert_eq!(extract_snippet(text, "dog", 100).as_deref(), Some(text));
+        assert_eq!(extract_snippet("Prix: 50 000 €\nhausse", "€", 3).as_deref(), Some("…00 € ha…"));
+        assert_eq!(extract_snippet(text, "cat", 10), None);
+    }
+
+    #[tokio::test]
+    async fn test_find_inactive_chats() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let now = Utc::now();
+
+        create_task(&pool, "active_task", "question", 60, 1, &TaskOptions::default()).await?;
+        create_task(&pool, "stale_task", "question", 60, 2, &TaskOptions::default()).await?;
+        create_task(&pool, "stale_task_2", "question", 60, 2, &TaskOptions::default()).await?;
+        create_task(&pool, "silent_task", "question", 60, 3, &TaskOptions::default()).await?;
+
+        insert_log_at(&pool, 1, now - chrono::Duration::days(1)).await?;
+        insert_log_at(&pool, 2, now - chrono::Duration::days(45)).await?;
+
+        let cutoff = now - chrono::Duration::days(30);
+        let chats = find_inactive_chats(&pool, cutoff).await?;
+        let chat_ids: Vec<i64> = chats.iter().map(|chat| chat.chat_id).collect();
+
+        // Chat 1 was active recently and must not be selected
+        assert_eq!(chat_ids, vec![2, 3]);
+        assert_eq!(chats[0].task_count, 2);
+        assert!(chats[0].last_activity.is_some());
+        assert!(chats[1].last_activity.is_none());
+
+        let deleted = purge_chat_tasks(&pool, &chat_ids).await?;
+        assert_eq!(deleted, 3);
+
+        let remaining: Vec<String> = sqlx::query("SELECT name FROM tasks")
+            .fetch_all(&pool)
+            .await?
+            .iter()
+            .map(|row| row.get("name"))
+            .collect();
+        assert_eq!(remaining, vec!["active_task"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_chat_lookup() {
+        let found: Result<(), RequestError> = Ok(());
+        assert_eq!(classify_chat_lookup(&found), ChatReachability::Reachable);
+
+        for api_error in [ApiError::ChatNotFound, ApiError::BotBlocked, ApiError::BotKicked] {
+            let lookup: Result<(), RequestError> = Err(RequestError::Api(api_error));
+            assert_eq!(classify_chat_lookup(&lookup), ChatReachability::Unreachable);
+        }
+
+        let migrated: Result<(), RequestError> = Err(RequestError::MigrateToChatId(ChatId(-100)));
+        assert_eq!(classify_chat_lookup(&migrated), ChatReachability::Unreachable);
+
+        // Transient failures must not pause anything
+        let flood: Result<(), RequestError> =
+            Err(RequestError::RetryAfter(teloxide::types::Seconds::from_seconds(5)));
+        assert_eq!(classify_chat_lookup(&flood), ChatReachability::Unknown);
+        let io: Result<(), RequestError> = Err(RequestError::Io(std::io::Error::other("timeout")));
+        assert_eq!(classify_chat_lookup(&io), ChatReachability::Unknown);
+    }
+
+    #[test]
+    fn test_chat_cache_expiry() {
+        let mut cache = ChatCache::new(Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(!cache.is_fresh(1, now));
+
+        cache.mark_reachable(1, now);
+        assert!(cache.is_fresh(1, now + Duration::from_secs(59)));
+        assert!(!cache.is_fresh(1, now + Duration::from_secs(60)));
+        assert!(!cache.is_fresh(2, now));
+
+        cache.forget(1);
+        assert!(!cache.is_fresh(1, now));
+    }
+
+    fn channel_admin(can_post_messages: bool) -> ChatMemberKind {
+        ChatMemberKind::Administrator(
+            serde_json::from_value(json!({
+                "is_anonymous": false,
+                "can_be_edited": false,
+                "can_manage_chat": true,
+                "can_change_info": false,
+                "can_post_messages": can_post_messages,
+                "can_delete_messages": false,
+                "can_manage_video_chats": false,
+                "can_invite_users": false,
+                "can_restrict_members": false,
+                "can_promote_members": false
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_channel_target_validation() {
+        assert_eq!(
+            parse_channel_ref(" @wibot_news ").ok(),
+            Some(Recipient::ChannelUsername(String::from("@wibot_news")))
+        );
+        assert_eq!(
+            parse_channel_ref("-1001234567890").ok(),
+            Some(Recipient::Id(ChatId(-1001234567890)))
+        );
+        assert!(parse_channel_ref("@abc").is_err());
+        assert!(parse_channel_ref("@bad-name").is_err());
+        // Positive ids are users, not channels
+        assert!(parse_channel_ref("12345").is_err());
+
+        let owner = ChatMemberKind::Owner(teloxide::types::Owner { custom_title: None, is_anonymous: false });
+        assert!(check_channel_rights(&channel_admin(true), Some(&owner)).is_ok());
+        assert!(check_channel_rights(&channel_admin(true), Some(&channel_admin(false))).is_ok());
+        assert!(matches!(
+            check_channel_rights(&channel_admin(false), Some(&owner)),
+            Err(BotError::ChannelUnavailable)
+        ));
+        assert!(matches!(
+            check_channel_rights(&ChatMemberKind::Member, Some(&owner)),
+            Err(BotError::ChannelUnavailable)
+        ));
+        assert!(matches!(
+            check_channel_rights(&channel_admin(true), Some(&ChatMemberKind::Member)),
+            Err(BotError::AdminRequired)
+        ));
+        assert!(matches!(check_channel_rights(&channel_admin(true), None), Err(BotError::AdminRequired)));
+    }
+
+    #[tokio::test]
+    async fn test_lost_channel_rights() -> Result<()> {
+        let api = |error: ApiError| BotError::TelegramError(RequestError::Api(error));
+        assert!(is_lost_channel_rights(&api(ApiError::NotEnoughRightsToPostMessages)));
+        assert!(is_lost_channel_rights(&api(ApiError::Unknown(String::from(
+            "Forbidden: bot is not a member of the channel chat"
+        )))));
+        assert!(!is_lost_channel_rights(&api(ApiError::MessageIsTooLong)));
+        assert!(!is_lost_channel_rights(&BotError::TelegramError(RequestError::RetryAfter(
+            teloxide::types::Seconds::from_seconds(5)
+        ))));
+
+        let pool = setup_test_db().await?;
+        let options = TaskOptions { target_chat_id: Some(-100500), ..TaskOptions::default() };
+        create_task(&pool, "channel_digest", "q", 60, 7, &options).await?;
+        let task = fetch_active_tasks(&pool).await?.remove(0);
+        assert_eq!((task.chat_id, task.target()), (7, -100500));
+
+        // The bot can't reach Telegram in tests, so only the pause is checked
+        let state = test_state(pool.clone(), Config::default());
+        let bot = Bot::new("0:test").set_api_url(reqwest::Url::parse("http://127.0.0.1:9")?);
+        pause_lost_channel_task(&state, &bot, &task).await?;
+        assert!(fetch_active_tasks(&pool).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejected_answer() {
+        let api = |error: ApiError| BotError::TelegramError(RequestError::Api(error));
+        assert!(is_rejected_answer(&api(ApiError::MessageIsTooLong)));
+        assert!(is_rejected_answer(&api(ApiError::CantParseEntities(String::from(
+            "Bad Request: can't parse entities"
+        )))));
+        // Worth retrying on the next poll
+        assert!(!is_rejected_answer(&api(ApiError::BotKicked)));
+        assert!(!is_rejected_answer(&BotError::TelegramError(RequestError::RetryAfter(
+            teloxide::types::Seconds::from_seconds(5)
+        ))));
+
+        // A combined answer over the limit is sent in parts
+        let answer = |name: &str| XaiAnswer {
+            content: "BTC is up today\n\n".repeat(150),
+            total_tokens: None,
+            model: String::from(name),
+        };
+        let tasks = [scheduled("group_a", 1, Some("digest"), 60), scheduled("group_b", 1, Some("digest"), 60)];
+        let answers = vec![(&tasks[0], answer("grok")), (&tasks[1], answer("grok"))];
+        let message = format_group_response("digest", &answers, Utc::now());
+        assert!(message.chars().count() > TELEGRAM_MESSAGE_LIMIT);
+        for chunk in split_markdown_message(&message, TELEGRAM_MESSAGE_LIMIT) {
+            assert!(chunk.chars().count() <= TELEGRAM_MESSAGE_LIMIT);
+        }
+    }
+
+    #[test]
+    fn test_overdue_task_runs_once() {
+        let now = Utc::now();
+        // Missed about 2000 intervals while the host was asleep
+        let stale = scheduled("asleep", 1, None, 60_000);
+        assert!(stale.is_due(now));
+        assert!(!has_future_last_run(&stale, now));
+
+        let batches = plan_task_batches(vec![stale], now);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].len(), 1);
+
+        assert!(detect_clock_jump(chrono::Duration::hours(8), Duration::from_secs(60)).is_some());
+        assert!(detect_clock_jump(chrono::Duration::seconds(-3600), Duration::from_secs(60)).is_some());
+        assert!(detect_clock_jump(chrono::Duration::seconds(61), Duration::from_secs(60)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_future_last_run_is_reset() -> Result<()> {
+        let pool = setup_test_db().await?;
+        create_task(&pool, "time_traveller", "q", 30, 1, &TaskOptions::default()).await?;
+        create_task(&pool, "on_time", "q", 30, 1, &TaskOptions::default()).await?;
+        let now = Utc::now();
+        let future = now + chrono::Duration::days(365);
+        update_last_run(&pool, "time_traveller", future).await?;
+        // Slight drift is tolerated
+        update_last_run(&pool, "on_time", now + chrono::Duration::minutes(1)).await?;
+
+        let tasks = fetch_active_tasks(&pool).await?;
+        let remaining = reset_future_tasks(&pool, tasks, now).await?;
+        let names: Vec<&str> = remaining.iter().map(|task| task.name.as_str()).collect();
+        assert_eq!(names, vec!["on_time"]);
+
+        let reset = fetch_active_tasks(&pool)
+            .await?
+            .into_iter()
+            .find(|task| task.name == "time_traveller")
+            .unwrap();
+        assert_eq!(reset.last_run, now);
+        assert!(!reset.is_due(now));
+        assert!(reset.is_due(now + chrono::Duration::minutes(30)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scale_interval() {
+        assert_eq!(scale_interval(60, 2.0, true), 120);
+        assert_eq!(scale_interval(60, 2.0, false), 30);
+        assert_eq!(scale_interval(45, 1.5, true), 68);
+        assert_eq!(scale_interval(45, 1.5, false), 30);
+        // Small intervals still move by a minute
+        assert_eq!(scale_interval(3, 1.1, true), 4);
+        assert_eq!(scale_interval(3, 1.1, false), 2);
+
+        assert_eq!(scale_interval(1, 2.0, false), MIN_TASK_INTERVAL);
+        assert_eq!(scale_interval(MAX_TASK_INTERVAL - 10, 2.0, true), MAX_TASK_INTERVAL);
+        assert_eq!(scale_interval(MAX_TASK_INTERVAL, 2.0, true), MAX_TASK_INTERVAL);
+
+        // Intervals from before the limits never move the wrong way
+        let legacy = MAX_TASK_INTERVAL + 5000;
+        assert_eq!(scale_interval(legacy, 2.0, true), legacy);
+        assert_eq!(scale_interval(legacy, 2.0, false), legacy / 2);
+        assert_eq!(scale_interval(0, 2.0, false), 0);
+        assert_eq!(scale_interval(0, 2.0, true), 1);
+        assert!(format_interval_change("t", legacy, legacy).contains("outside the allowed 1 to 43200 minutes"));
+    }
+
+    #[tokio::test]
+    async fn test_adjust_task_interval() -> Result<()> {
+        let pool = setup_test_db().await?;
+        create_task(&pool, "nudged", "q", 60, 1, &TaskOptions::default()).await?;
+
+        assert_eq!(adjust_task_interval(&pool, "nudged", 1, 2.0, true).await?, Some((60, 120)));
+        assert_eq!(adjust_task_interval(&pool, "nudged", 1, 2.0, false).await?, Some((120, 60)));
+        assert_eq!(adjust_task_interval(&pool, "nudged", 2, 2.0, false).await?, None);
+        assert!(format_interval_change("nudged", 120, 60).contains("every 60 minutes"));
+        assert!(format_interval_change("nudged", 1, 1).contains("shortest"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_swap_pinned_message() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let pinned = TaskOptions { pin: true, ..TaskOptions::default() };
+        create_task(&pool, "pin_agenda", "q", 60, 1, &pinned).await?;
+
+        assert_eq!(swap_pinned_message(&pool, "pin_agenda", 1, Some(MessageId(10))).await?, None);
+        assert_eq!(
+            swap_pinned_message(&pool, "pin_agenda", 1, Some(MessageId(11))).await?,
+            Some(MessageId(10))
+        );
+        // Re-pinning the same message leaves nothing to unpin
+        assert_eq!(swap_pinned_message(&pool, "pin_agenda", 1, Some(MessageId(11))).await?, None);
+        // Another chat has no pinned message for this task
+        assert_eq!(swap_pinned_message(&pool, "pin_agenda", 2, Some(MessageId(12))).await?, None);
+
+        assert_eq!(swap_pinned_message(&pool, "pin_agenda", 1, None).await?, Some(MessageId(11)));
+        assert_eq!(swap_pinned_message(&pool, "pin_agenda", 1, None).await?, None);
+
+        let task = fetch_active_tasks(&pool).await?.remove(0);
+        assert!(task.pin);
+        assert!(set_task_pin(&pool, "pin_agenda", 1, false).await?);
+        assert!(!fetch_active_tasks(&pool).await?[0].pin);
+        assert!(!set_task_pin(&pool, "pin_agenda", 2, true).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_tagged_tasks_paused() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let tagged = |tags: &[&str]| TaskOptions {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..TaskOptions::default()
+        };
+        create_task(&pool, "tag_headlines", "q", 60, 1, &tagged(&["news"])).await?;
+        create_task(&pool, "tag_markets", "q", 60, 1, &tagged(&["finance", "news"])).await?;
+        create_task(&pool, "tag_newsletter", "q", 60, 1, &tagged(&["newsletter"])).await?;
+        create_task(&pool, "tag_untagged", "q", 60, 1, &TaskOptions::default()).await?;
+        create_task(&pool, "tag_elsewhere", "q", 60, 2, &tagged(&["news"])).await?;
+
+        assert_eq!(set_tagged_tasks_paused(&pool, "news", 1, true).await?, 2);
+        // Already paused tasks are not counted again
+        assert_eq!(set_tagged_tasks_paused(&pool, "news", 1, true).await?, 0);
+        assert_eq!(set_tagged_tasks_paused(&pool, "sports", 1, true).await?, 0);
+
+        let mut active: Vec<String> = fetch_active_tasks(&pool)
+            .await?
+            .into_iter()
+            .map(|task| task.name)
+            .collect();
+        active.sort();
+        assert_eq!(active, vec!["tag_elsewhere", "tag_newsletter", "tag_untagged"]);
+
+        assert_eq!(set_tagged_tasks_paused(&pool, "finance", 1, false).await?, 1);
+        assert_eq!(set_tagged_tasks_paused(&pool, "news", 1, false).await?, 1);
+
+        assert_eq!(parse_pause_target("--tag=News")?, PauseTarget::Tag(String::from("news")));
+        assert_eq!(parse_pause_target(" prices ")?, PauseTarget::Task(String::from("prices")));
+        assert!(parse_pause_target("--tag=a,b").is_err());
+        assert!(parse_pause_target("--group=news").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_paused_tasks_are_not_scheduled() -> Result<()> {
+        let pool = setup_test_db().await?;
+        create_task(&pool, "running", "question", 1, 1, &TaskOptions::default()).await?;
+        create_task(&pool, "stopped", "question", 1, 2, &TaskOptions::default()).await?;
+
+        assert!(set_task_paused(&pool, "stopped", 2, true).await?);
+        // Pausing is scoped to the owning chat
+        assert!(!set_task_paused(&pool, "running", 2, true).await?);
+
+        let names: Vec<String> = fetch_active_tasks(&pool)
+            .await?
+            .into_iter()
+            .map(|task| task.name)
+            .collect();
+        assert_eq!(names, vec!["running"]);
+
+        assert!(set_task_paused(&pool, "stopped", 2, false).await?);
+        assert_eq!(fetch_active_tasks(&pool).await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_welcome_message_storage() -> Result<()> {
+        let pool = setup_test_db().await?;
+        assert_eq!(get_chat_settings(&pool, 1).await?.welcome_message, None);
+
+        set_welcome_message(&pool, 1, "Hi there!").await?;
+        set_welcome_message(&pool, 1, "Welcome to *Rust* fans (v2.0)!").await?;
+        set_welcome_message(&pool, 2, "Other chat").await?;
+
+        let message = get_chat_settings(&pool, 1).await?.welcome_message;
+        assert_eq!(message.as_deref(), Some("Welcome to *Rust* fans (v2.0)!"));
+        assert_eq!(
+            get_chat_settings(&pool, 2).await?.welcome_message.as_deref(),
+            Some("Other chat")
+        );
+
+        assert_eq!(
+            format_welcome_message(message.as_deref()),
+            r"👋 Welcome to \*Rust\* fans \(v2\.0\)\!"
+        );
+        assert!(format_welcome_message(None).contains("/help"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_cache_evicts_least_recently_used() {
+        let mut cache = ResponseCache::new(Duration::from_secs(300), 2);
+        let now = Instant::now();
+
+        cache.insert("a", "answer a", now);
+        cache.insert("b", "answer b", now);
+        // Touch "a" so that "b" becomes the least recently used entry
+        assert_eq!(cache.get("a", now).as_deref(), Some("answer a"));
+
+        cache.insert("c", "answer c", now);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.get("b", now), None);
+        assert_eq!(cache.get("a", now).as_deref(), Some("answer a"));
+        assert_eq!(cache.get("c", now).as_deref(), Some("answer c"));
+
+        // Without any reads the oldest insert goes first
+        cache.insert("d", "answer d", now);
+        assert_eq!(cache.get("a", now), None);
+        assert_eq!(cache.get("d", now).as_deref(), Some("answer d"));
+    }
+
+    #[test]
+    fn test_response_cache_expiry_and_limits() {
+        let mut cache = ResponseCache::new(Duration::from_secs(60), 10);
+        let now = Instant::now();
+
+        cache.insert("q", "first", now);
+        cache.insert("q", "second", now);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.get("q", now + Duration::from_secs(30)).as_deref(), Some("second"));
+        assert_eq!(cache.get("q", now + Duration::from_secs(60)), None);
+        assert!(cache.entries.is_empty());
+
+        let mut disabled = ResponseCache::new(Duration::from_secs(60), 0);
+        disabled.insert("q", "answer", now);
+        assert_eq!(disabled.get("q", now), None);
+    }
+
+    #[test]
+    fn test_rate_limiter_window() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(limiter.check(1, now).is_ok());
+        assert!(limiter.check(1, now + Duration::from_secs(10)).is_ok());
+        assert_eq!(
+            limiter.check(1, now + Duration::from_secs(20)),
+            Err(Duration::from_secs(40))
+        );
+
+        // The first request leaves the window after 60 seconds
+        assert!(limiter.check(1, now + Duration::from_secs(60)).is_ok());
+        assert_eq!(limiter.usage(1, now + Duration::from_secs(60)).0, 2);
+    }
+
+    #[test]
+    fn test_rate_limiter_reset_is_scoped_to_user() {
+        let mut limiter = RateLimiter::new(1, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(limiter.check(1, now).is_ok());
+        assert!(limiter.check(2, now).is_ok());
+        assert!(limiter.check(1, now).is_err());
+        assert!(limiter.check(2, now).is_err());
+
+        assert!(limiter.reset(1));
+        assert!(!limiter.reset(3));
+        assert_eq!(limiter.usage(1, now), (0, None));
+        assert_eq!(limiter.usage(2, now), (1, Some(Duration::from_secs(60))));
+
+        assert!(limiter.check(1, now).is_ok());
+        assert!(limiter.check(2, now).is_err());
+    }
+
+    #[test]
+    fn test_parse_rate_limit_command() {
+        assert_eq!(parse_rate_limit_command("12345"), Some((12345, false)));
+        assert_eq!(parse_rate_limit_command("12345 reset"), Some((12345, true)));
+        assert_eq!(parse_rate_limit_command("abc"), None);
+        assert_eq!(parse_rate_limit_command("12345 clear"), None);
+        assert_eq!(parse_rate_limit_command(""), None);
+    }
+
+    #[test]
+    fn test_validate_task_name() {
+        assert!(validate_task_name("crypto_watch", 64).is_ok());
+        assert!(validate_task_name(&"a".repeat(64), 64).is_ok());
+        assert!(matches!(
+            validate_task_name(&"a".repeat(65), 64),
+            Err(BotError::InvalidParameters)
+        ));
+        // Length is counted in characters, not bytes
+        assert!(validate_task_name(&"é".repeat(64), 64).is_ok());
+
+        assert!(validate_task_name("", 64).is_err());
+        assert!(validate_task_name("bad\nname", 64).is_err());
+        assert!(validate_task_name("tab\tname", 64).is_err());
+        assert!(validate_task_name("null\u{0}name", 64).is_err());
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let previous = "BTC: 50000\nETH: 3000\nSOL: 100";
+        let latest = "BTC: 51000\nETH: 3000\nSOL: 100\nDOGE: 0.1";
+
+        assert_eq!(
+            diff_lines(previous, latest),
+            vec![
+                DiffLine::Removed("BTC: 50000"),
+                DiffLine::Added("BTC: 51000"),
+                DiffLine::Same("ETH: 3000"),
+                DiffLine::Same("SOL: 100"),
+                DiffLine::Added("DOGE: 0.1"),
+            ]
+        );
+
+        assert_eq!(diff_lines("a\nb", ""), vec![DiffLine::Removed("a"), DiffLine::Removed("b")]);
+        assert_eq!(diff_lines("same", "same"), vec![DiffLine::Same("same")]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_selects_history_entry() -> Result<()> {
+        let pool = setup_test_db().await?;
+        assert!(matches!(
+            get_task_run(&pool, "prices", 1, 1).await,
+            Err(BotError::RunIndexOutOfRange(0))
+        ));
+
+        record_task_run(&pool, "prices", 1, &test_answer("BTC: 1")).await?;
+        record_task_run(&pool, "prices", 1, &test_answer("BTC: 2")).await?;
+        record_task_run(&pool, "prices", 1, &test_answer("BTC: 3")).await?;
+        record_task_run(&pool, "prices", 2, &test_answer("other chat")).await?;
+
+        assert_eq!(get_task_run(&pool, "prices", 1, 1).await?.response, "BTC: 3");
+        assert_eq!(get_task_run(&pool, "prices", 1, 3).await?.response, "BTC: 1");
+        assert_eq!(get_task_run(&pool, "prices", 2, 1).await?.response, "other chat");
+        for index in [0, 4, -1] {
+            assert!(matches!(
+                get_task_run(&pool, "prices", 1, index).await,
+                Err(BotError::RunIndexOutOfRange(3))
+            ));
+        }
+        assert!(BotError::RunIndexOutOfRange(3).user_message().contains("from 1 to 3"));
+
+        assert_eq!(parse_replay_command("prices"), Some((String::from("prices"), 1)));
+        assert_eq!(parse_replay_command(" prices 2 "), Some((String::from("prices"), 2)));
+        assert_eq!(parse_replay_command("prices two"), None);
+        assert_eq!(parse_replay_command("prices 2 3"), None);
+        assert_eq!(parse_replay_command(""), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_task_diff_uses_latest_runs() -> Result<()> {
+        let pool = setup_test_db().await?;
+        record_task_run(&pool, "prices", 1, &test_answer("BTC: 1")).await?;
+        record_task_run(&pool, "prices", 1, &test_answer("BTC: 2")).await?;
+        record_task_run(&pool, "prices", 1, &test_answer("BTC: 3 `up`")).await?;
+        record_task_run(&pool, "prices", 2, &test_answer("other chat")).await?;
+
+        let runs = get_recent_runs(&pool, "prices", 1, 2).await?;
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].response, "BTC: 3 `up`");
+        assert_eq!(runs[1].response, "BTC: 2");
+
+        let message = format_task_diff("prices", &runs[1], &runs[0]);
+        assert!(message.contains("```\n- BTC: 2\n+ BTC: 3 \\`up\\`\n```"));
+
+        let unchanged = TaskRun {
+            run_at: runs[0].run_at.clone(),
+            response: runs[0].response.clone(),
+        };
+        assert!(format_task_diff("prices", &runs[0], &unchanged).contains("No changes"));
+
+        // Long answers are split into messages that each hold a closed code block
+        let long_run = |prefix: &str| TaskRun {
+            run_at: runs[0].run_at.clone(),
+            response: (0..300).map(|i| format!("{} line {}", prefix, i)).collect::<Vec<_>>().join("\n"),
+        };
+        let message = format_task_diff("prices", &long_run("old"), &long_run("new"));
+        assert!(message.chars().count() > TELEGRAM_MESSAGE_LIMIT);
+        let chunks = split_markdown_message(&message, TELEGRAM_MESSAGE_LIMIT);
+        assert!(chunks.len() > 1);
+        assert!(chunks[1].starts_with(CODE_FENCE));
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= TELEGRAM_MESSAGE_LIMIT);
+            assert_eq!(chunk.matches(CODE_FENCE).count() % 2, 0, "unclosed code block: {}", chunk);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_task_queue_status() {
+        let now: DateTime<Utc> = "2024-02-20T12:00:00Z".parse().unwrap();
+        let schedule = |last_run: &str, interval: i64, paused: bool| TaskSchedule {
+            name: String::from("task"),
+            chat_id: 1,
+            interval,
+            last_run: last_run.to_string(),
+            paused,
+        };
+
+        let due = schedule("2024-02-20T11:00:00Z", 60, false);
+        assert_eq!(due.status(now), TaskQueueStatus::Due);
+        assert_eq!(due.next_due(), Some("2024-02-20T12:00:00Z".parse().unwrap()));
+
+        let waiting = schedule("2024-02-20T11:30:00Z", 60, false);
+        assert_eq!(waiting.status(now), TaskQueueStatus::Waiting);
+        assert_eq!(waiting.next_due(), Some("2024-02-20T12:30:00Z".parse().unwrap()));
+
+        // Paused wins over being due
+        assert_eq!(schedule("2024-02-20T10:00:00Z", 60, true).status(now), TaskQueueStatus::Paused);
+
+        let invalid = schedule("yesterday", 60, false);
+        assert_eq!(invalid.status(now), TaskQueueStatus::Invalid);
+        assert_eq!(invalid.next_due(), None);
+
+        let formatted = format_task_queue(&[waiting, due, invalid], now);
+        let due_position = formatted.find("Due").unwrap();
+        let waiting_position = formatted.find("Waiting").unwrap();
+        assert!(due_position < waiting_position);
+        assert!(formatted.contains("Invalid last run"));
+
+        let many: Vec<_> = (0..100).map(|_| schedule("2024-02-20T11:00:00Z", 60, false)).collect();
+        let chunks = split_markdown_message(&format_task_queue(&many, now), TELEGRAM_MESSAGE_LIMIT);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= TELEGRAM_MESSAGE_LIMIT);
+            assert!(has_balanced_markdown(chunk));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_logging_can_be_disabled() -> Result<()> {
+        let count_logs = |pool: SqlitePool| async move {
+            sqlx::query("SELECT COUNT(*) as count FROM bot_logs")
+                .fetch_one(&pool)
+                .await
+                .map(|row| row.get::<i64, _>("count"))
+        };
+
+        let disabled = test_state(
+            setup_test_db().await?,
+            Config {
+                logging_enabled: false,
+                ..Config::default()
+            },
+        );
+        log_interaction(&disabled, 1, Some(42), None, "Ask", None, None, None, None, Duration::from_millis(5)).await?;
+        assert_eq!(count_logs(disabled.pool.clone()).await?, 0);
+        assert!(matches!(ensure_logging_enabled(&disabled), Err(BotError::StatisticsDisabled)));
+
+        let enabled = test_state(setup_test_db().await?, Config::default());
+        log_interaction(&enabled, 1, Some(42), None, "Ask", None, None, None, None, Duration::from_millis(5)).await?;
+        assert_eq!(count_logs(enabled.pool.clone()).await?, 1);
+        assert!(ensure_logging_enabled(&enabled).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_redact_sensitive() {
+        let patterns = default_redaction_patterns();
+
+        let redacted = redact_sensitive(
+            "use key sk-abcdefghijklmnop1234567890 and mail me at jane.doe@example.com",
+            &patterns,
+        );
+        assert_eq!(redacted, "use key [REDACTED] and mail me at [REDACTED]");
+
+        assert_eq!(
+            redact_sensitive("my password: hunter2 please", &patterns),
+            "my [REDACTED] please"
+        );
+        assert_eq!(
+            redact_sensitive("123456789:AAHdqTcvCH1vGWJxfSeofSAs0K5PALDsaw0", &patterns),
+            "[REDACTED]"
+        );
+
+        // Ordinary questions are left alone
+        let question = "What's the price of BTC and ETH today?";
+        assert_eq!(redact_sensitive(question, &patterns), question);
+    }
+
+    #[tokio::test]
+    async fn test_logged_args_are_redacted() -> Result<()> {
+        let mut config = Config::default();
+        config.redaction_patterns.push(Regex::new(r"ACME-\d+")?);
+        let state = test_state(setup_test_db().await?, config);
+
+        log_interaction(
+            &state,
+            1,
+            Some(42),
+            None,
+            "Ask",
+            Some("Why does xai-Zx81kLmNoPqRsTuVwXyZ0123 fail for ACME-1234?"),
+            Some("Key xai-Zx81kLmNoPqRsTuVwXyZ0123 is revoked"),
+            None,
+            None,
+            Duration::from_millis(5),
+        )
+        .await?;
+
+        let row = sqlx::query("SELECT command, args, response FROM bot_logs")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(row.get::<String, _>("command"), "Ask");
+        assert_eq!(
+            row.get::<String, _>("args"),
+            "Why does [REDACTED] fail for [REDACTED]?"
+        );
+        assert_eq!(row.get::<String, _>("response"), "Key [REDACTED] is revoked");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_name_and_args() {
+        let ask = Command::Ask(String::from("What is Rust?"));
+        assert_eq!(ask.name(), "Ask");
+        assert_eq!(ask.args(), Some("What is Rust?"));
+        assert_eq!(Command::BotStats.name(), "BotStats");
+        assert_eq!(Command::BotStats.args(), None);
+    }
+
+    #[test]
+    fn test_temperature_precedence() -> Result<()> {
+        let config = Config {
+            default_temperature: 0.0,
+            ..Config::default()
+        };
+        let chat = ChatSettings {
+            temperature: Some(0.9),
+            ..ChatSettings::default()
+        };
+
+        let (inline, question) = parse_inline_options("--temp=1.5 Write a poem")?;
+        assert_eq!(question, "Write a poem");
+        assert_eq!(resolve_xai_options(&inline, &UserPrefs::default(), &chat, &config).temperature, 1.5);
+
+        let (inline, question) = parse_inline_options("Write a poem")?;
+        assert_eq!(question, "Write a poem");
+        assert_eq!(resolve_xai_options(&inline, &UserPrefs::default(), &chat, &config).temperature, 0.9);
+        assert_eq!(
+            resolve_xai_options(&inline, &UserPrefs::default(), &ChatSettings::default(), &config).temperature,
+            0.0
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_xai_payload_preview() -> Result<()> {
+        let state = test_state(setup_test_db().await?, Config::default());
+        let (inline, question) = parse_inline_options("--temp=0.7 --lang=de What's new?")?;
+        let options = resolve_xai_options(&inline, &UserPrefs::default(), &ChatSettings::default(), &state.config);
+
+        let request = build_xai_request(&state, &question, &options)?;
+        let sent: Value = serde_json::from_slice(request.body().and_then(|body| body.as_bytes()).unwrap())?;
+        assert_eq!(sent, build_xai_request_body("What's new?", &options));
+        assert_eq!(sent["temperature"], 0.7);
+        assert_eq!(request.url().as_str(), XAI_API_URL);
+
+        let preview = format_xai_payload(&request);
+        assert!(!preview.contains("test-token"));
+        assert!(preview.contains("Bearer <omitted>"));
+        let json_start = preview.find("```json\n").unwrap() + "```json\n".len();
+        let json_end = preview.rfind("\n```").unwrap();
+        let shown: Value = serde_json::from_str(&preview[json_start..json_end].replace("\\\\", "\\"))?;
+        assert_eq!(shown["body"], sent);
+        assert_eq!(shown["method"], "POST");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_xai_response() {
+        use reqwest::StatusCode;
+
+        let completion = r#"{"model": "grok-2", "choices": [{"message": {"role": "assistant", "content": "BTC is at 50k"}}], "usage": {"total_tokens": 42}}"#;
+        assert_eq!(
+            parse_xai_response(StatusCode::OK, completion, "grok-beta"),
+            Ok(XaiAnswer {
+                content: String::from("BTC is at 50k"),
+                total_tokens: Some(42),
+                model: String::from("grok-2"),
+            })
+        );
+        let minimal = r#"{"choices": [{"message": {"content": "hi"}}]}"#;
+        let answer = parse_xai_response(StatusCode::OK, minimal, "grok-beta").unwrap();
+        assert_eq!((answer.total_tokens, answer.model.as_str()), (None, "grok-beta"));
+
+        assert_eq!(
+            parse_xai_response(
+                StatusCode::UNAUTHORIZED,
+                r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error"}}"#,
+                "grok-beta"
+            ),
+            Err(XaiResponseError::Api {
+                status: 401,
+                message: String::from("Incorrect API key provided (invalid_request_error)"),
+            })
+        );
+        assert_eq!(
+            parse_xai_response(StatusCode::TOO_MANY_REQUESTS, r#"{"code": "rate_limited", "error": "Too many requests"}"#, "grok-beta"),
+            Err(XaiResponseError::Api { status: 429, message: String::from("Too many requests") })
+        );
+
+        let unexpected = |body: &str| match parse_xai_response(StatusCode::OK, body, "grok-beta") {
+            Err(XaiResponseError::UnexpectedShape { detail, .. }) => detail,
+            other => panic!("expected an unexpected shape, got {:?}", other),
+        };
+        assert!(unexpected(r#"{"choices": [{"message": {"content": "trunc"#).starts_with("invalid JSON"));
+        assert!(unexpected(r#"{"choices": []}"#).starts_with("completion without content"));
+        assert!(unexpected(r#"{"status": "ok"}"#).contains(r#"{"status": "ok"}"#));
+        assert!(unexpected("<html>Bad Gateway</html>").ends_with("<html>Bad Gateway</html>"));
+    }
+
+    #[test]
+    fn test_check_json_answer() -> Result<()> {
+        assert_eq!(
+            check_json_answer(r#"{"btc": 50000, "eth": 3000}"#, 0),
+            JsonAnswerCheck::Valid(String::from("{\n  \"btc\": 50000,\n  \"eth\": 3000\n}"))
+        );
+        assert_eq!(
+            check_json_answer("```json\n[1, 2]\n```", 0),
+            JsonAnswerCheck::Valid(String::from("[\n  1,\n  2\n]"))
+        );
+
+        assert_eq!(check_json_answer("Sure! Here's the JSON: {\"btc\": 1}", 0), JsonAnswerCheck::Retry);
+        assert_eq!(check_json_answer(r#"{"btc": 50000,"#, 0), JsonAnswerCheck::Retry);
+        assert_eq!(check_json_answer(r#"{"btc": 50000,"#, 1), JsonAnswerCheck::GiveUp);
+        assert!(matches!(check_json_answer(r#"{"btc": 1}"#, 1), JsonAnswerCheck::Valid(_)));
+
+        let (inline, question) = parse_inline_options("--json List three coins")?;
+        assert_eq!(question, "List three coins");
+        let options = resolve_xai_options(&inline, &UserPrefs::default(), &ChatSettings::default(), &Config::default());
+        let body = build_xai_request_body(&question, &options);
+        assert_eq!(body["response_format"]["type"], "json_object");
+        let prompt = body["messages"][0]["content"].as_str().unwrap();
+        assert!(prompt.ends_with(JSON_INSTRUCTION));
+        assert!(build_xai_request_body("q", &XaiOptions { json: false, ..options.clone() })
+            .get("response_format")
+            .is_none());
+
+        // Language and style still reach the model in JSON mode
+        let (inline, question) = parse_inline_options("--json --lang=es List three coins")?;
+        let prefs = UserPrefs { style: Some(String::from("terse")), ..UserPrefs::default() };
+        let options = resolve_xai_options(&inline, &prefs, &ChatSettings::default(), &Config::default());
+        let body = build_xai_request_body(&question, &options);
+        let prompt = body["messages"][0]["content"].as_str().unwrap();
+        assert!(prompt.contains("Always respond in Spanish"));
+        assert!(prompt.contains("this style: terse"));
+        assert!(prompt.ends_with(JSON_INSTRUCTION));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_pref_precedence() -> Result<()> {
+        let config = Config {
+            xai_model: String::from("grok-beta"),
+            default_temperature: 0.1,
+            ..Config::default()
+        };
+        let user = UserPrefs {
+            model: Some(String::from("grok-2")),
+            temperature: Some(0.5),
+            style: Some(String::from("short bullet points")),
+        };
+        let chat = ChatSettings {
+            temperature: Some(0.9),
+            ..ChatSettings::default()
+        };
+
+        // inline > user pref
+        let (inline, _) = parse_inline_options("--model=grok-3 --temp=1.5 q")?;
+        let options = resolve_xai_options(&inline, &user, &chat, &config);
+        assert_eq!((options.model.as_str(), options.temperature), ("grok-3", 1.5));
+
+        // user pref > chat setting
+        let inline = InlineOptions::default();
+        let options = resolve_xai_options(&inline, &user, &chat, &config);
+        assert_eq!((options.model.as_str(), options.temperature), ("grok-2", 0.5));
+        let prompt = build_xai_request_body("q", &options)["messages"][0]["content"].clone();
+        assert!(prompt.as_str().unwrap().ends_with("this style: short bullet points"));
+
+        // chat setting > global
+        let options = resolve_xai_options(&inline, &UserPrefs::default(), &chat, &config);
+        assert_eq!((options.model.as_str(), options.temperature), ("grok-beta", 0.9));
+        assert_eq!(options.style, None);
+
+        let options = resolve_xai_options(&inline, &UserPrefs::default(), &ChatSettings::default(), &config);
+        assert_eq!((options.model.as_str(), options.temperature), ("grok-beta", 0.1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_user_prefs() -> Result<()> {
+        let pool = setup_test_db().await?;
+        assert_eq!(get_user_prefs(&pool, 7).await?, UserPrefs::default());
+
+        set_user_pref(&pool, 7, &parse_pref_command("model grok-2")?).await?;
+        set_user_pref(&pool, 7, &parse_pref_command("temp 0.3")?).await?;
+        set_user_pref(&pool, 7, &parse_pref_command("style Explain like I'm five")?).await?;
+        let prefs = get_user_prefs(&pool, 7).await?;
+        assert_eq!(prefs.model.as_deref(), Some("grok-2"));
+        assert_eq!(prefs.temperature, Some(0.3));
+        assert_eq!(prefs.style.as_deref(), Some("Explain like I'm five"));
+
+        set_user_pref(&pool, 7, &parse_pref_command("model default")?).await?;
+        assert_eq!(get_user_prefs(&pool, 7).await?.model, None);
+        assert_eq!(get_user_prefs(&pool, 8).await?, UserPrefs::default());
+
+        assert!(parse_pref_command("temp 3").is_err());
+        assert!(parse_pref_command("model grok 2").is_err());
+        assert!(parse_pref_command("colour blue").is_err());
+        assert!(parse_pref_command(&format!("style {}", "a".repeat(MAX_STYLE_LEN + 1))).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_answer_language() -> Result<()> {
+        let config = Config::default();
+        let chat = ChatSettings {
+            language: Some(String::from("fr")),
+            ..ChatSettings::default()
+        };
+
+        let (inline, question) = parse_inline_options("--lang=ES ¿Qué hora es?")?;
+        assert_eq!(question, "¿Qué hora es?");
+        let options = resolve_xai_options(&inline, &UserPrefs::default(), &chat, &config);
+        assert_eq!(options.language.as_deref(), Some("es"));
+        let body = build_xai_request_body(&question, &options);
+        let prompt = body["messages"][0]["content"].as_str().unwrap();
+        assert!(prompt.starts_with(SYSTEM_PROMPT));
+        assert!(prompt.ends_with("Always respond in Spanish, regardless of the language of the question."));
+
+        // The chat default applies without a flag
+        let (inline, _) = parse_inline_options("What time is it?")?;
+        let options = resolve_xai_options(&inline, &UserPrefs::default(), &chat, &config);
+        assert!(system_prompt(options.language.as_deref()).contains("respond in French"));
+        assert_eq!(system_prompt(None), SYSTEM_PROMPT);
+
+        assert!(parse_inline_options("--lang=xx question").is_err());
+        assert!(parse_language("klingon").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_temperature_validation() {
+        assert_eq!(parse_temperature("0").ok(), Some(0.0));
+        assert_eq!(parse_temperature("2").ok(), Some(2.0));
+        assert_eq!(parse_temperature(" 0.7 ").ok(), Some(0.7));
+        assert!(parse_temperature("-0.1").is_err());
+        assert!(parse_temperature("2.1").is_err());
+        assert!(parse_temperature("hot").is_err());
+        assert!(parse_temperature("NaN").is_err());
+
+        assert!(parse_inline_options("--temp=3 question").is_err());
+        assert!(parse_inline_options("--unknown=1 question").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chat_temperature_storage() -> Result<()> {
+        let pool = setup_test_db().await?;
+        set_welcome_message(&pool, 1, "Hello").await?;
+        set_chat_temperature(&pool, 1, Some(0.4)).await?;
+
+        let settings = get_chat_settings(&pool, 1).await?;
+        assert_eq!(settings.temperature, Some(0.4));
+        // Updating one setting keeps the others
+        assert_eq!(settings.welcome_message.as_deref(), Some("Hello"));
+
+        set_chat_temperature(&pool, 1, None).await?;
+        assert_eq!(get_chat_settings(&pool, 1).await?.temperature, None);
+
+        set_chat_language(&pool, 1, Some("de")).await?;
+        let updated = get_chat_settings(&pool, 1).await?;
+        assert_eq!(updated.language.as_deref(), Some("de"));
+        assert!(format_chat_settings(&updated, &Config::default()).contains("German \\(de\\)"));
+        assert!(format_chat_settings(&settings, &Config::default()).contains("0\\.4"));
+
+        Ok(())
+    }
+
+    fn scheduled(name: &str, chat_id: i64, group: Option<&str>, minutes_ago: i64) -> ScheduledTask {
+        ScheduledTask {
+            name: name.to_string(),
+            question: format!("{} question", name),
+            interval: 30,
+            last_run: Utc::now() - chrono::Duration::minutes(minutes_ago),
+            chat_id,
+            group: group.map(str::to_string),
+            prefix: None,
+            suffix: None,
+            priority: 0,
+            pin: false,
+            target_chat_id: None,
+            max_tokens: None,
+        }
+    }
+
+    #[test]
+    fn test_find_overdue_tasks() {
+        let now = Utc::now();
+        let task = |name: &str, interval: i64, minutes_ago: i64| ScheduledTask {
+            interval,
+            last_run: now - chrono::Duration::minutes(minutes_ago),
+            ..scheduled(name, 1, None, 0)
+        };
+        let tasks = vec![
+            task("fresh", 30, 10),
+            task("stuck", 30, 3000),
+            task("late_b", 30, 45),
+            task("late_a", 30, 45),
+            task("hourly", 60, 200),
+        ];
+
+        let overdue = find_overdue_tasks(tasks, now);
+        let summary: Vec<(&str, i64)> = overdue
+            .iter()
+            .map(|(task, late)| (task.name.as_str(), late.num_minutes()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("stuck", 2970), ("hourly", 140), ("late_a", 15), ("late_b", 15)]
+        );
+
+        assert_eq!(format_lateness(chrono::Duration::minutes(2970)), "2d 1h 30m");
+        assert_eq!(format_lateness(chrono::Duration::minutes(60)), "1h");
+        assert_eq!(format_lateness(chrono::Duration::seconds(20)), "<1m");
+
+        let formatted = format_overdue_tasks(&overdue, true);
+        assert!(formatted.find("stuck").unwrap() < formatted.find("hourly").unwrap());
+        assert!(formatted.contains("Overdue by: 2d 1h 30m"));
+        assert!(formatted.contains("\\(chat `1`\\)"));
+        assert!(has_balanced_markdown(&formatted));
+        assert_eq!(format_overdue_tasks(&[], false), "✅ *No overdue tasks*");
+
+        let many = find_overdue_tasks((0..200).map(|i| task(&format!("stuck_{}", i), 30, 3000)).collect(), now);
+        let chunks = split_markdown_message(&format_overdue_tasks(&many, true), TELEGRAM_MESSAGE_LIMIT);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= TELEGRAM_MESSAGE_LIMIT);
+            assert!(has_balanced_markdown(chunk));
+        }
+    }
+
+    #[test]
+    fn test_plan_task_batches() {
+        let now = Utc::now();
+        let tasks = vec![
+            scheduled("btc", 1, Some("crypto"), 31),
+            scheduled("eth", 1, Some("crypto"), 45),
+            scheduled("sol", 1, Some("crypto"), 60),
+            scheduled("weather", 1, None, 31),
+            scheduled("news", 1, None, 5),
+            // Same group name in another chat is a different group
+            scheduled("btc_other", 2, Some("crypto"), 40),
+            // Only part of this group is due
+            scheduled("a", 1, Some("partial"), 40),
+            scheduled("b", 1, Some("partial"), 10),
+        ];
+
+        let batches: Vec<Vec<String>> = plan_task_batches(tasks, now)
+            .into_iter()
+            .map(|batch| batch.into_iter().map(|task| task.name).collect())
+            .collect();
+
+        assert_eq!(
+            batches,
+            vec![
+                vec!["weather"],
+                vec!["btc", "eth", "sol"],
+                vec!["a"],
+                vec!["btc_other"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_group_response() {
+        let btc = scheduled("btc", 1, Some("crypto"), 31);
+        let eth = scheduled("eth", 1, Some("crypto"), 31);
+        let answer = |content: &str| XaiAnswer {
+            content: content.to_string(),
+            total_tokens: None,
+            model: String::from("grok-beta"),
+        };
+        let answers = vec![
+            (&btc, answer("BTC is at $50,000")),
+            (&eth, answer("ETH is at $3,000")),
+        ];
+
+        let message = format_group_response("crypto", &answers, Utc::now());
+        assert_eq!(message.matches("📌 *Task:*").count(), 2);
+        assert!(message.contains("*Group:* crypto"));
+        assert!(message.find("BTC is at \\$50\\,000").unwrap() < message.find("ETH is at \\$3\\,000").unwrap());
+    }
+
+    #[test]
+    fn test_batch_dispatch_order() {
+        let now = Utc::now();
+        let prioritized = |name: &str, priority: i64, minutes_ago: i64| ScheduledTask {
+            priority,
+            ..scheduled(name, 1, None, minutes_ago)
+        };
+        let mut batches = [
+            vec![prioritized("low_late", 0, 300)],
+            vec![prioritized("high_recent", 5, 31)],
+            vec![prioritized("mid", 1, 40)],
+            vec![prioritized("high_late", 5, 90)],
+            vec![prioritized("low_recent", 0, 31)],
+            // A group is as urgent as its most important member
+            vec![prioritized("group_low", 0, 31), prioritized("group_high", 3, 31)],
+        ];
+
+        batches.sort_by(|a, b| compare_batch_urgency(a, b, now));
+        let order: Vec<&str> = batches.iter().map(|batch| batch[0].name.as_str()).collect();
+        assert_eq!(
+            order,
+            vec!["high_late", "high_recent", "group_low", "mid", "low_late", "low_recent"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_task_priority_storage() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let (inline, rest) = parse_inline_options("--priority=7 btc 30 BTC?")?;
+        assert_eq!(inline.priority, Some(7));
+        assert!(parse_inline_options("--priority=high btc 30 BTC?").is_err());
+
+        let (name, interval, question) = parse_create_command(rest).await.unwrap();
+        let options = TaskOptions {
+            priority: 7,
+            ..TaskOptions::default()
+        };
+        create_task(&pool, &name, &question, interval as i64, 1, &options).await?;
+        create_task(&pool, "weather", "Rain?", 30, 1, &TaskOptions::default()).await?;
+
+        let tasks = fetch_active_tasks(&pool).await?;
+        assert_eq!(tasks[0].priority, 7);
+        assert_eq!(tasks[1].priority, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_startup_tasks() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let (inline, rest) = parse_inline_options("--startup status 1440 Any outages?")?;
+        assert_eq!(inline.run_on_startup, Some(true));
+        assert_eq!(parse_inline_options("--startup=no x")?.0.run_on_startup, Some(false));
+        assert!(parse_inline_options("--startup=maybe x").is_err());
+
+        let (name, interval, question) = parse_create_command(rest).await.unwrap();
+        let startup = TaskOptions {
+            run_on_startup: true,
+            ..TaskOptions::default()
+        };
+        create_task(&pool, &name, &question, interval as i64, 1, &startup).await?;
+        create_task(&pool, "paused_status", "q", 60, 1, &startup).await?;
+        create_task(&pool, "weather", "Rain?", 60, 1, &TaskOptions::default()).await?;
+        set_task_paused(&pool, "paused_status", 1, true).await?;
+
+        let tasks = fetch_startup_tasks(&pool).await?;
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].name, "status");
+        assert!(!tasks[0].is_due(Utc::now()));
+
+        // A startup run moves last_run forward like a scheduled run
+        let started = Utc::now() + chrono::Duration::minutes(5);
+        update_last_run(&pool, "status", started).await?;
+        let task = &fetch_startup_tasks(&pool).await?[0];
+        assert_eq!(task.last_run, started);
+        assert!(!task.is_due(started + chrono::Duration::minutes(1439)));
+        assert!(task.is_due(started + chrono::Duration::minutes(1440)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_task_templates() {
+        let now = "2024-02-20T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut task = scheduled("btc", 1, None, 31);
+        assert_eq!(apply_task_templates(&task, String::from("answer"), now), "answer");
+
+        task.prefix = Some(String::from("Daily Report ({task}, {date}):"));
+        task.suffix = Some(String::from("— automated"));
+        assert_eq!(
+            apply_task_templates(&task, String::from("answer"), now),
+            "Daily Report \\(btc\\, 2024\\-02\\-20\\)\\:\n\nanswer\n\n— automated"
+        );
+
+        assert_eq!(
+            parse_template_command("btc prefix Report for {date}"),
+            Some((String::from("btc"), TemplatePart::Prefix, Some(String::from("Report for {date}"))))
+        );
+        assert_eq!(
+            parse_template_command("btc suffix"),
+            Some((String::from("btc"), TemplatePart::Suffix, None))
+        );
+        assert_eq!(parse_template_command("btc middle text"), None);
+    }
+
+    #[tokio::test]
+    async fn test_task_template_storage() -> Result<()> {
+        let pool = setup_test_db().await?;
+        create_task(&pool, "btc", "BTC?", 30, 1, &TaskOptions::default()).await?;
+
+        assert!(set_task_template(&pool, "btc", 1, TemplatePart::Prefix, Some("Report:")).await?);
+        assert!(!set_task_template(&pool, "btc", 2, TemplatePart::Prefix, Some("Report:")).await?);
+        let task = &fetch_active_tasks(&pool).await?[0];
+        assert_eq!(task.prefix.as_deref(), Some("Report:"));
+        assert_eq!(task.suffix, None);
+
+        set_task_template(&pool, "btc", 1, TemplatePart::Prefix, None).await?;
+        assert_eq!(fetch_active_tasks(&pool).await?[0].prefix, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_group_task_selection() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let crypto = TaskOptions {
+            group: Some(String::from("crypto")),
+            ..TaskOptions::default()
+        };
+        create_task(&pool, "btc", "BTC?", 30, 1, &crypto).await?;
+        create_task(&pool, "eth", "ETH?", 30, 1, &crypto).await?;
+        create_task(&pool, "other_chat", "BTC?", 30, 2, &crypto).await?;
+        create_task(&pool, "weather", "Rain?", 30, 1, &TaskOptions::default()).await?;
+        set_task_paused(&pool, "eth", 1, true).await?;
+
+        let names: Vec<String> = fetch_group_tasks(&pool, 1, "crypto")
+            .await?
+            .into_iter()
+            .map(|task| task.name)
+            .collect();
+        assert_eq!(names, vec!["btc"]);
+
+        let (inline, rest) = parse_inline_options("--group=crypto sol 30 SOL price?")?;
+        assert_eq!(inline.group.as_deref(), Some("crypto"));
+        assert_eq!(rest, "sol 30 SOL price?");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chat_stats_permissions() {
+        // Flag off: only the owner
+        assert!(can_view_chat_stats(true, false, false));
+        assert!(!can_view_chat_stats(false, true, false));
+        assert!(!can_view_chat_stats(false, false, false));
+
+        // Flag on: owner and chat admins, never regular members
+        assert!(can_view_chat_stats(true, false, true));
+        assert!(can_view_chat_stats(false, true, true));
+        assert!(!can_view_chat_stats(false, false, true));
+    }
+
+    #[tokio::test]
+    async fn test_command_stats_scoped_to_chat() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let now = Utc::now();
+        insert_log_at(&pool, 1, now).await?;
+        insert_log_at(&pool, 1, now).await?;
+        insert_log_at(&pool, 2, now).await?;
+
+        let state = test_state(pool, Config::default());
+        let global = get_command_stats(&state, None).await?;
+        assert_eq!(global["commands"][0]["usage_count"], 3);
+
+        let chat = get_command_stats(&state, Some(1)).await?;
+        assert_eq!(chat["commands"][0]["usage_count"], 2);
+        let formatted = format_chat_stats(&chat);
+        assert!(formatted.contains("Chat Usage Statistics"));
+        assert!(formatted.contains("Avg Response: 10\\.00ms"));
+
+        let empty = get_command_stats(&state, Some(3)).await?;
+        assert_eq!(empty["commands"].as_array().map(Vec::len), Some(0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_and_undelete() -> Result<()> {
+        let pool = setup_test_db().await?;
+        create_task(&pool, "prices", "q", 60, 1, &TaskOptions::default()).await?;
+        record_task_run(&pool, "prices", 1, &test_answer("BTC: 1")).await?;
+
+        assert!(!delete_task(&pool, "prices", 2).await?);
+        assert!(delete_task(&pool, "prices", 1).await?);
+        assert!(!delete_task(&pool, "prices", 1).await?);
+        assert!(fetch_active_tasks(&pool).await?.is_empty());
+        assert!(fetch_task_schedules(&pool).await?.is_empty());
+        assert!(!set_task_paused(&pool, "prices", 1, false).await?);
+
+        let long_ago = Utc::now() - chrono::Duration::days(1);
+        assert!(!undelete_task(&pool, "prices", 2, long_ago).await?);
+        assert!(undelete_task(&pool, "prices", 1, long_ago).await?);
+        assert_eq!(fetch_active_tasks(&pool).await?.len(), 1);
+        assert_eq!(get_recent_runs(&pool, "prices", 1, 5).await?.len(), 1);
+
+        // Past the grace period the task can no longer be restored
+        delete_task(&pool, "prices", 1).await?;
+        assert!(!undelete_task(&pool, "prices", 1, Utc::now() + chrono::Duration::seconds(1)).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_multiple_tasks() -> Result<()> {
+        let pool = setup_test_db().await?;
+        create_task(&pool, "btc", "q", 60, 1, &TaskOptions::default()).await?;
+        create_task(&pool, "eth", "q", 60, 1, &TaskOptions::default()).await?;
+        create_task(&pool, "other", "q", 60, 2, &TaskOptions::default()).await?;
+
+        let names = parse_task_names(" btc missing  eth btc other ");
+        assert_eq!(names, vec!["btc", "missing", "eth", "other"]);
+
+        let results = delete_tasks(&pool, &names, 1).await?;
+        assert_eq!(
+            results,
+            vec![
+                (String::from("btc"), true),
+                (String::from("missing"), false),
+                (String::from("eth"), true),
+                (String::from("other"), false),
+            ]
+        );
+        let remaining: Vec<String> = fetch_active_tasks(&pool)
+            .await?
+            .into_iter()
+            .map(|task| task.name)
+            .collect();
+        assert_eq!(remaining, vec!["other"]);
+
+        let summary = format_delete_summary(&results, "1 day(s)");
+        assert!(summary.contains("✅ deleted *btc*"));
+        assert!(summary.contains("❌ not found *missing*"));
+
+        Ok(())
     }
 
-    match result {
-        Ok(_) => Ok(()),
-        Err(err) => {
-            let _ = try_send_message(&bot, msg.chat.id, err.user_message()).await;
-            log::error!("Command error: {:?}", err);
-            Ok(())
-        }
-    }
-}
+    #[tokio::test]
+    async fn test_purge_deleted_tasks() -> Result<()> {
+        let pool = setup_test_db().await?;
+        create_task(&pool, "old", "q", 60, 1, &TaskOptions::default()).await?;
+        create_task(&pool, "kept", "q", 60, 1, &TaskOptions::default()).await?;
+        record_task_run(&pool, "old", 1, &test_answer("BTC: 1")).await?;
+        delete_task(&pool, "old", 1).await?;
+
+        // Still inside the grace period
+        assert_eq!(purge_deleted_tasks(&pool, Utc::now() - chrono::Duration::hours(1)).await?, 0);
+
+        let purged = purge_deleted_tasks(&pool, Utc::now() + chrono::Duration::seconds(1)).await?;
+        assert_eq!(purged, 1);
+        assert!(get_recent_runs(&pool, "old", 1, 5).await?.is_empty());
+        assert_eq!(fetch_active_tasks(&pool).await?.len(), 1);
+
+        // A deleted name can be reused before it is purged
+        delete_task(&pool, "kept", 1).await?;
+        create_task(&pool, "kept", "new", 60, 1, &TaskOptions::default()).await?;
+        assert_eq!(fetch_active_tasks(&pool).await?[0].question, "new");
 
+        Ok(())
+    }
 
-async fn check_and_run_tasks(state: State) -> Result<(), BotError> {
-    let now = Utc::now();
-    let tasks =
-        sqlx::query("SELECT name, description as question, interval, last_run, chat_id FROM tasks")
-            .fetch_all(&state.pool)
-            .await?;
+    #[tokio::test]
+    async fn test_deleted_name_reserved_for_other_chats() -> Result<()> {
+        let pool = setup_test_db().await?;
+        create_task(&pool, "reserved", "q", 60, 1, &TaskOptions::default()).await?;
+        record_task_run(&pool, "reserved", 1, &test_answer("BTC: 1")).await?;
+        delete_task(&pool, "reserved", 1).await?;
+
+        // Another chat can't take the name while chat 1 may still restore it
+        assert!(matches!(
+            create_task(&pool, "reserved", "q", 60, 2, &TaskOptions::default()).await,
+            Err(BotError::TaskNameReserved)
+        ));
+        assert_eq!(get_recent_runs(&pool, "reserved", 1, 5).await?.len(), 1);
+        assert!(undelete_task(&pool, "reserved", 1, Utc::now() - chrono::Duration::hours(1)).await?);
 
-    for task in tasks {
-        let last_run: DateTime<Utc> = task.get::<String, _>("last_run").parse()?;
-        let interval: i64 = task.get("interval");
-        let duration_since_last = now.signed_duration_since(last_run);
+        // A live task's name is simply taken
+        assert!(matches!(
+            create_task(&pool, "reserved", "q", 60, 2, &TaskOptions::default()).await,
+            Err(BotError::TaskExists)
+        ));
 
-        if duration_since_last.num_minutes() >= interval {
-            let name: String = task.get("name");
-            let question: String = task.get("question");
-            let chat_id: i64 = task.get("chat_id");
-
-            log::info!("Running task '{}' with question: {}", name, question);
-
-            match call_xai_api(&state, &question).await {
-                Ok(response) => {
-                    let formatted_response = format_xai_response(Some(&name), &question, &response);
-                    let bot = Bot::new(&env::var("TELEGRAM_BOT_TOKEN").unwrap());
-                    if let Err(e) =
-                        try_send_message(&bot, ChatId(chat_id), formatted_response).await
-                    {
-                        log::error!("Failed to send task response: {:?}", e);
-                        continue;
-                    }
-                }
-                Err(e) => {
-                    log::error!("Failed to get X.AI response for task {}: {:?}", name, e);
-                    continue;
-                }
-            }
+        Ok(())
+    }
 
-            sqlx::query("UPDATE tasks SET last_run = ? WHERE name = ?")
-                .bind(now.to_rfc3339())
-                .bind(&name)
-                .execute(&state.pool)
-                .await?;
-        }
+    #[test]
+    fn test_validate_question() {
+        assert!(matches!(validate_question(""), Err(BotError::InvalidParameters)));
+        assert!(matches!(validate_question(" \t\n "), Err(BotError::InvalidParameters)));
+        assert_eq!(validate_question("  What is BTC at? ").unwrap(), "What is BTC at?");
     }
-    Ok(())
-}
 
-async fn try_connect_bot(token: &str, retries: u32, delay: Duration) -> Result<Bot, BotError> {
-    let mut attempt = 0;
-    loop {
-        match Bot::new(token).get_me().await {
-            Ok(_) => {
-                log::info!("Successfully connected to Telegram API");
-                return Ok(Bot::new(token));
-            }
-            Err(e) => {
-                attempt += 1;
-                if attempt >= retries {
-                    return Err(BotError::TelegramError(e));
-                }
-                log::warn!(
-                    "Failed to connect to Telegram API (attempt {}/{}): {:?}",
-                    attempt,
-                    retries,
-                    e
-                );
-                sleep(delay).await;
+    #[tokio::test]
+    async fn test_all_tasks_across_chats() -> Result<()> {
+        let pool = setup_test_db().await?;
+        for chat_id in [3, 1, 2] {
+            for i in 0..5 {
+                let name = format!("task{}-{}", chat_id, i);
+                create_task(&pool, &name, "q", 60, chat_id, &TaskOptions::default()).await?;
             }
         }
-    }
-}
 
-async fn run_bot(bot: Bot, state: State) -> Result<(), BotError> {
-    let handler = move |bot: Bot, msg: Message, cmd: Command| {
-        handle_command(bot, msg, cmd, Arc::clone(&state))
-    };
+        let (first, total) = fetch_all_tasks_page(&pool, 1).await?;
+        assert_eq!(total, 15);
+        assert_eq!(first.len(), ALL_TASKS_PAGE_SIZE as usize);
+        assert_eq!(first[0].chat_id, 1);
+        assert_eq!(first[5].chat_id, 2);
 
-    // Remove the ? operator since Command::repl returns ()
-    Command::repl(bot, handler).await;
-    Ok(())
-}
+        let (last, _) = fetch_all_tasks_page(&pool, 2).await?;
+        assert_eq!(last.len(), 5);
+        assert!(last.iter().all(|task| task.chat_id == 3));
 
-async fn run_with_retry(state: State, telegram_token: String) {
-    let retry_delay = Duration::from_secs(5);
-    let max_retries = 5;
+        let titles = HashMap::from([(1, String::from("Traders"))]);
+        let formatted = format_all_tasks(&first, &titles, 1, total);
+        assert!(formatted.contains("💬 *Traders* `1`"));
+        assert!(formatted.contains("💬 `2`"));
+        assert!(formatted.contains("/alltasks 2"));
+        assert!(!format_all_tasks(&last, &titles, 2, total).contains("Next page"));
 
-    loop {
-        log::info!("Attempting to start bot...");
+        Ok(())
+    }
 
-        match try_connect_bot(&telegram_token, max_retries, retry_delay).await {
-            Ok(bot) => match run_bot(bot, Arc::clone(&state)).await {
-                Ok(_) => {
-                    log::info!("Bot stopped gracefully");
-                    break;
-                }
-                Err(e) => {
-                    log::error!(
-                        "Bot crashed: {:?}. Restarting in {} seconds...",
-                        e,
-                        retry_delay.as_secs()
-                    );
-                    sleep(retry_delay).await;
-                }
-            },
-            Err(e) => {
-                log::error!(
-                    "Failed to connect to Telegram API after {} attempts: {:?}",
-                    max_retries,
-                    e
-                );
-                log::info!("Retrying in {} seconds...", retry_delay.as_secs());
-                sleep(retry_delay).await;
-            }
+    #[test]
+    fn test_parse_owner_id() {
+        assert_eq!(parse_owner_id("123456789").unwrap(), 123456789);
+        assert_eq!(parse_owner_id(" 42\n").unwrap(), 42);
+        assert_eq!(parse_owner_id(&MAX_TELEGRAM_USER_ID.to_string()).unwrap(), MAX_TELEGRAM_USER_ID);
+
+        for invalid in ["0", "-5", "-1001234567890", "4503599627370496", "abc", ""] {
+            assert!(parse_owner_id(invalid).is_err(), "{:?} should be rejected", invalid);
         }
     }
-}
 
-fn format_bot_stats(stats: &Value) -> String {
-    let mut formatted = String::from("*📊 Bot Usage Statistics*\n\n");
-    
-    if let Some(commands) = stats["commands"].as_array() {
-        for cmd in commands {
-            formatted.push_str(&format!(
-                "🔷 *{}*\n\
-                  ├ Usage Count: {}\n\
-                  ├ Avg Response: {:.2}ms\n\
-                  └ Error Rate: {:.2}%\n\n",
-                escape_markdown_v2(cmd["command"].as_str().unwrap_or("unknown")),
-                cmd["usage_count"].as_i64().unwrap_or(0),
-                escape_markdown_v2(&format!("{:.2}", cmd["avg_execution_time_ms"].as_f64().unwrap_or(0.0))),
-                escape_markdown_v2(&format!("{:.2}", cmd["error_rate"].as_f64().unwrap_or(0.0)))
-            ));
+    #[test]
+    fn test_config_validation() {
+        assert!(Config::default().validate().is_ok());
+        assert!(Config {
+            rate_limit_max_requests: 0,
+            rate_limit_window: Duration::ZERO,
+            fallback_chat_id: Some(-100123),
+            default_temperature: 2.0,
+            ..Config::default()
+        }
+        .validate()
+        .is_ok());
+
+        let invalid = [
+            Config { fallback_chat_id: Some(0), ..Config::default() },
+            Config { rate_limit_window: Duration::ZERO, ..Config::default() },
+            Config { max_task_name_len: 0, ..Config::default() },
+            Config { max_concurrent_tasks: 0, ..Config::default() },
+            Config { xai_model: String::from(" "), ..Config::default() },
+            Config { default_temperature: 2.5, ..Config::default() },
+            Config { xai_price_per_1k_tokens: -0.01, ..Config::default() },
+            Config { xai_tokens_per_run: 0, ..Config::default() },
+            Config { interval_step_factor: 1.0, ..Config::default() },
+            Config { xai_latency_alpha: 0.0, ..Config::default() },
+            Config { command_cooldown: MAX_COMMAND_COOLDOWN + Duration::from_secs(1), ..Config::default() },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err());
         }
     }
 
-    formatted
-}
-
-fn format_user_stats(stats: &Value) -> String {
-    format!(
-        "*📊 Your Usage Statistics*\n\n\
-        📈 *Total Commands:* {}\n\
-        📅 *Active Days:* {}\n\
-        ⚡ *Average Response Time:* {}\n\
-        ❌ *Error Rate:* {}",
-        stats["total_commands"].as_i64().unwrap_or(0),
-        stats["active_days"].as_i64().unwrap_or(0),
-        escape_markdown_v2(&format!("{:.2}ms", stats["avg_execution_time_ms"].as_f64().unwrap_or(0.0))),
-        escape_markdown_v2(&format!("{:.2}%", stats["error_rate"].as_f64().unwrap_or(0.0)))
-    )
-}
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenv().ok();
-
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "info");
+    #[test]
+    fn test_config_report_masks_secrets() {
+        let config = Config {
+            xai_model: String::from("grok-2"),
+            fallback_chat_id: Some(-100),
+            ..Config::default()
+        };
+        let xai_token = "xai-0123456789abcdefghij";
+        let telegram_token = "123456789:AAE_secret_telegram_token_value_0000";
+        let report = format_config_report(&config, xai_token, telegram_token);
+
+        assert!(!report.contains(xai_token));
+        assert!(!report.contains(telegram_token));
+        assert!(!report.contains("secret_telegram"));
+        assert!(report.contains("xai_api_token") && report.contains("********"));
+        assert!(format_config_report(&config, "", telegram_token).contains("<not set>"));
+
+        assert!(report.contains("grok-2"));
+        assert!(report.contains("api.x.ai"));
+        assert!(report.contains("MarkdownV2"));
+        assert!(report.contains("60s"));
+        assert!(report.contains("-100"));
+        assert!(report.starts_with("*⚙️ Bot Configuration*\n\n```\n"));
     }
-    pretty_env_logger::init();
 
-    log::info!("Starting task bot...");
-
-    let telegram_token = env::var("TELEGRAM_BOT_TOKEN")
-        .context("TELEGRAM_BOT_TOKEN not found in environment variables or .env file")?;
-    let xai_token = env::var("XAI_API_TOKEN")
-        .context("XAI_API_TOKEN not found in environment variables or .env file")?;
-    
-    // Add owner ID initialization
-    let owner_id = env::var("BOT_OWNER_ID")
-        .context("BOT_OWNER_ID not found in environment variables or .env file")?
-        .parse::<i64>()
-        .context("BOT_OWNER_ID must be a valid integer")?;
+    fn test_message(from_bot: bool) -> Message {
+        serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 1708430400,
+            "chat": { "id": -100, "type": "group", "title": "Traders" },
+            "from": { "id": 42, "is_bot": from_bot, "first_name": "Sender" },
+            "text": "/help"
+        }))
+        .unwrap()
+    }
 
-    initialize_database().await?;
+    #[test]
+    fn test_messages_from_bots_are_ignored() {
+        assert!(is_from_bot(&test_message(true)));
+        assert!(!is_from_bot(&test_message(false)));
+    }
 
-    let db_path = Path::new("data").join("tasks.db");
-    let database_url = format!("sqlite:{}", db_path.to_string_lossy());
+    #[tokio::test]
+    async fn test_rating_vote_upsert() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let target = RatingTarget::Run(7);
+        let (parsed, vote) = parse_rating_callback(&target.callback_data(1)).unwrap();
+        assert_eq!((parsed, vote), (target, 1));
+        assert_eq!(parse_rating_callback("rate:log:3:down"), Some((RatingTarget::Log(3), -1)));
+        assert_eq!(parse_rating_callback("rate:run:x:up"), None);
+        assert_eq!(parse_rating_callback("other"), None);
+
+        record_rating(&pool, target, 42, 1, "Task", "grok-beta").await?;
+        record_rating(&pool, target, 42, -1, "Task", "grok-beta").await?;
+        record_rating(&pool, target, 43, 1, "Task", "grok-beta").await?;
+
+        let votes: Vec<(i64, i64)> =
+            sqlx::query_as("SELECT user_id, vote FROM ratings ORDER BY user_id")
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(votes, vec![(42, -1), (43, 1)]);
 
-    let pool = SqlitePool::connect(&database_url)
-        .await
-        .context("Failed to connect to SQLite database")?;
+        Ok(())
+    }
 
-    let state = Arc::new(AppState {
-        pool,
-        http_client: Client::new(),
-        xai_token,
-        owner_id,
-    });
+    #[tokio::test]
+    async fn test_rating_uses_logged_model() -> Result<()> {
+        let state = test_state(setup_test_db().await?, Config::default());
+        let log = |model| log_interaction(&state, 1, Some(42), None, "Ask", Some("q"), Some("a"), model, None, Duration::from_millis(5));
+        let overridden = log(Some("grok-custom")).await?.unwrap();
+        let legacy = log(None).await?.unwrap();
+
+        assert_eq!(
+            rating_target_info(&state.pool, RatingTarget::Log(overridden), &state.config).await?,
+            Some((String::from("Ask"), String::from("grok-custom")))
+        );
+        assert_eq!(
+            rating_target_info(&state.pool, RatingTarget::Log(legacy), &state.config).await?,
+            Some((String::from("Ask"), state.config.xai_model.clone()))
+        );
 
-    let state_clone = Arc::clone(&state);
+        Ok(())
+    }
 
-    tokio::spawn(async move {
-        loop {
-            if let Err(e) = check_and_run_tasks(Arc::clone(&state_clone)).await {
-                log::error!("Error checking tasks: {}", e);
-            }
-            sleep(Duration::from_secs(60)).await;
+    #[tokio::test]
+    async fn test_rating_aggregates() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let config = Config::default();
+        let answer = XaiAnswer {
+            content: String::from("BTC: 1"),
+            total_tokens: None,
+            model: String::from("grok-2"),
+        };
+        let run_id = record_task_run(&pool, "prices", 1, &answer).await?;
+        assert_eq!(
+            rating_target_info(&pool, RatingTarget::Run(run_id), &config).await?,
+            Some((String::from("Task"), String::from("grok-2")))
+        );
+        assert_eq!(rating_target_info(&pool, RatingTarget::Log(99), &config).await?, None);
+
+        for (id, user, vote, command, model) in [
+            (1, 1, 1, "Ask", "grok-beta"),
+            (1, 2, 1, "Ask", "grok-beta"),
+            (2, 1, -1, "Ask", "grok-2"),
+            (3, 1, 1, "Task", "grok-2"),
+        ] {
+            record_rating(&pool, RatingTarget::Log(id), user, vote, command, model).await?;
         }
-    });
 
-    log::info!("Bot started successfully!");
+        let state = test_state(pool, config);
+        let by_command = get_rating_stats(&state, false).await?;
+        assert_eq!(
+            by_command,
+            vec![
+                RatingStat { key: String::from("Ask"), up: 2, down: 1 },
+                RatingStat { key: String::from("Task"), up: 1, down: 0 },
+            ]
+        );
+        assert!((by_command[0].up_rate() - 66.666).abs() < 0.01);
+        assert!(format_rating_stats(&by_command, false).contains("*Ask*: 2 👍 / 1 👎 \\(66\\.7%\\)"));
 
-    run_with_retry(state, telegram_token).await;
+        let by_model = get_rating_stats(&state, true).await?;
+        assert_eq!(by_model[0], RatingStat { key: String::from("grok-2"), up: 1, down: 1 });
+        assert_eq!(by_model[0].up_rate(), 50.0);
 
-    Ok(())
-}
+        Ok(())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use sqlx::Row;
+    #[tokio::test]
+    async fn test_create_validation_retries_transient_errors() {
+        let backoff = [Duration::ZERO; 3];
+        let unavailable = || {
+            anyhow::Error::from(XaiResponseError::Api {
+                status: 503,
+                message: String::from("Service Unavailable"),
+            })
+        };
 
-    #[test]
-    fn test_escape_markdown_v2() {
-        let input = "Hello *world* with [link] and (parens)";
-        let escaped = escape_markdown_v2(input);
-        assert_eq!(escaped, r"Hello \*world\* with \[link\] and \(parens\)");
-    }
+        let mut calls = 0;
+        let answer = retry_transient_xai(&backoff, || {
+            calls += 1;
+            let attempt = calls;
+            async move {
+                if attempt < 3 {
+                    Err(unavailable())
+                } else {
+                    Ok(String::from("ok"))
+                }
+            }
+        })
+        .await;
+        assert_eq!(answer.unwrap(), "ok");
+        assert_eq!(calls, 3);
 
-    #[test]
-    fn test_format_response_content() {
-        // Test list formatting with debug output
-        let list_input = "Items:\n- First item\n- *Second* item";
-        let formatted = format_response_content(list_input);
-        println!("Formatted output: {}", formatted);
-        
-        // Test list items - asterisks are preserved for formatting
-        assert!(formatted.contains("• First item")); 
-        assert!(formatted.contains("• *Second* item")); // Markdown formatting is preserved
-    
-        // Test paragraph formatting
-        let text_with_formatting = "Here is *bold* and `code` text";
-        let formatted_text = format_response_content(text_with_formatting);
-        assert!(formatted_text.contains("Here is *bold* and `code` text")); // Markdown formatting is preserved
-    
-        // Test multiple paragraphs with lists
-        let multi_paragraph = "First paragraph\n\nList:\n- Item 1\n- *Item* 2\n\nLast paragraph";
-        let formatted_multi = format_response_content(multi_paragraph);
-        assert!(formatted_multi.contains("First paragraph"));
-        assert!(formatted_multi.contains("• Item 1"));
-        assert!(formatted_multi.contains("• *Item* 2")); // Markdown formatting is preserved
-        assert!(formatted_multi.contains("Last paragraph"));
-    
-        // Test special characters are escaped but formatting is preserved
-        let mixed_content = "Here's a *bold* statement with some (parentheses)";
-        let formatted_mixed = format_response_content(mixed_content);
-        assert!(formatted_mixed.contains("Here\\'s a *bold* statement with some \\(parentheses\\)")); // Special chars escaped, formatting preserved
+        let mut calls = 0;
+        let exhausted = retry_transient_xai(&backoff, || {
+            calls += 1;
+            async { Err::<String, _>(unavailable()) }
+        })
+        .await;
+        assert!(matches!(
+            exhausted.unwrap_err().downcast_ref::<XaiResponseError>(),
+            Some(XaiResponseError::Api { status: 503, .. })
+        ));
+        assert_eq!(calls, 4);
+
+        // Errors that would repeat are not retried
+        let mut calls = 0;
+        let rejected = retry_transient_xai(&backoff, || {
+            calls += 1;
+            async {
+                Err::<String, _>(anyhow::Error::from(XaiResponseError::Api {
+                    status: 400,
+                    message: String::from("Model not found"),
+                }))
+            }
+        })
+        .await;
+        assert!(rejected.is_err());
+        assert_eq!(calls, 1);
     }
 
     #[test]
-    fn test_format_xai_response() {
-        let question = "What's the price?";
-        let response = "Bitcoin is at $50,000";
+    fn test_inline_answer_result() {
+        let article = inline_answer_result("What is Rust?", "Rust is a   systems\nprogramming language.");
+        assert_eq!(article.id, "answer");
+        assert_eq!(article.title, "What is Rust?");
+        assert_eq!(article.description.as_deref(), Some("Rust is a systems programming language."));
+        let InputMessageContent::Text(content) = &article.input_message_content else {
+            panic!("expected a text message");
+        };
+        assert_eq!(content.parse_mode, Some(ParseMode::MarkdownV2));
+        assert_eq!(
+            content.message_text,
+            format_xai_response(None, "What is Rust?", "Rust is a   systems\nprogramming language.")
+        );
 
-        // Test with task name
-        let with_task = format_xai_response(Some("price_check"), question, response);
-        assert!(with_task.contains("price\\_check"));
-        assert!(with_task.contains("What\\'s the price\\?"));
-        assert!(with_task.contains("Bitcoin is at \\$50\\,000"));
+        let long_answer = "word ".repeat(2000);
+        let article = inline_answer_result("Long?", &long_answer);
+        let description = article.description.unwrap();
+        assert_eq!(description.chars().count(), INLINE_DESCRIPTION_LEN);
+        assert!(description.ends_with('…'));
+        let InputMessageContent::Text(content) = &article.input_message_content else {
+            panic!("expected a text message");
+        };
+        assert!(content.message_text.chars().count() <= TELEGRAM_MESSAGE_LIMIT);
+        assert!(content.message_text.starts_with("🤖 *X\\.AI Response*"));
 
-        // Test without task name
-        let without_task = format_xai_response(None, question, response);
-        assert!(!without_task.contains("Task:"));
-        assert!(without_task.contains("Question:"));
-        assert!(without_task.contains("Answer:"));
+        let limited = inline_error_button(&BotError::RateLimited(Duration::from_secs(42)));
+        assert_eq!(limited.text, "⏳ Rate limit reached, try again in 42s");
     }
 
     #[test]
-    fn test_help_message() {
-        let help = format_help_message();
-        assert!(help.contains("/help"));
-        assert!(help.contains("/create"));
-        assert!(help.contains("/list"));
-        assert!(help.contains("/delete"));
-        assert!(help.contains("/ask"));
+    fn test_truncate_markdown() {
+        assert_eq!(truncate_markdown("*Hello world*", 20), "*Hello world*");
+        assert_eq!(truncate_markdown("*Hello world*", 8), "*Hello…*");
+        // Escape sequences are kept whole
+        assert_eq!(truncate_markdown("Price 1\\.5\\.0 today", 9), "Price 1…");
+        // Links are dropped rather than cut
+        assert_eq!(truncate_markdown("See [docs](https://example.com/page) now", 20), "See …");
+        assert_eq!(
+            truncate_markdown("Intro\n```\nlet x = 1;\nlet y = 2;\n```", 20),
+            "Intro\n```\nlet x…\n```"
+        );
+
+        let formatted = format_response_content(
+            "**Bitcoin** is at _50k_ (up 2.5%)\n\n- `btc` rallies\n- *ETH* follows __closely__ today",
+        );
+        for max_chars in 1..formatted.chars().count() {
+            let truncated = truncate_markdown(&formatted, max_chars);
+            assert!(truncated.chars().count() <= max_chars, "too long: {}", truncated);
+            assert!(has_balanced_markdown(&truncated), "unbalanced: {}", truncated);
+            assert!(!truncated.contains("\\…"), "cut escape: {}", truncated);
+        }
     }
 
-    #[tokio::test]
-    async fn test_database_operations() -> Result<()> {
-        // Setup in-memory database for testing
-        let pool = SqlitePool::connect("sqlite::memory:").await?;
+    #[test]
+    fn test_task_max_tokens() -> Result<()> {
+        let (inline, rest) = parse_inline_options("--maxlen=150 brief 60 News?")?;
+        assert_eq!(inline.max_tokens, Some(150));
+        assert_eq!(rest, "brief 60 News?");
+        assert!(parse_inline_options("--maxlen=-1 brief 60 News?").is_err());
+        assert!(parse_inline_options("--maxlen=5000 brief 60 News?").is_err());
+        assert!(parse_inline_options("--maxlen brief 60 News?").is_err());
+
+        let chat_options =
+            resolve_xai_options(&InlineOptions::default(), &UserPrefs::default(), &ChatSettings::default(), &Config::default());
+        assert!(build_xai_request_body("q", &chat_options).get("max_tokens").is_none());
+
+        let task = ScheduledTask {
+            max_tokens: Some(150),
+            ..scheduled("brief", 1, None, 0)
+        };
+        let options = task.xai_options(&chat_options);
+        assert_eq!(build_xai_request_body("q", &options)["max_tokens"], 150);
+        assert_ne!(options.cache_key("q"), chat_options.cache_key("q"));
 
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS tasks (
-                name TEXT PRIMARY KEY,
-                description TEXT NOT NULL,
-                interval INTEGER NOT NULL,
-                last_run TEXT NOT NULL,
-                chat_id INTEGER NOT NULL
-            )
-            "#,
-        )
-        .execute(&pool)
-        .await?;
+        let answer = "word ".repeat(400);
+        let content = format_task_content(&answer, task.max_tokens);
+        assert_eq!(content.chars().count(), 150 * CHARS_PER_TOKEN);
+        assert!(content.ends_with('…'));
+        assert_eq!(format_task_content(&answer, None), format_response_content(&answer));
 
-        // Test task creation
-        let result = sqlx::query(
-            "INSERT INTO tasks (name, description, interval, last_run, chat_id) VALUES (?, ?, ?, ?, ?)"
-        )
-        .bind("test_task")
-        .bind("test description")
-        .bind(60)
-        .bind(Utc::now().to_rfc3339())
-        .bind(123456789)
-        .execute(&pool)
-        .await;
+        Ok(())
+    }
 
-        assert!(result.is_ok());
+    #[test]
+    fn test_repeated_errors() {
+        let start = Instant::now();
+        let window = Duration::from_secs(300);
+        let mut errors = RepeatedErrors::new(window);
+
+        assert_eq!(errors.record("X.AI down", start), vec!["X.AI down"]);
+        for minute in 1..=3 {
+            assert!(errors.record("X.AI down", start + Duration::from_secs(minute * 60)).is_empty());
+        }
+        assert_eq!(errors.record("DB locked", start + Duration::from_secs(60)), vec!["DB locked"]);
+        assert!(errors.flush(start + Duration::from_secs(299)).is_empty());
 
-        // Test task retrieval
-        let task = sqlx::query("SELECT * FROM tasks WHERE name = ?")
-            .bind("test_task")
-            .fetch_one(&pool)
-            .await?;
+        // The window ends: one summary for the repeats, nothing for the single error
+        assert_eq!(
+            errors.record("X.AI down", start + window),
+            vec!["X.AI down (repeated 3 times in the last 300s)", "X.AI down"]
+        );
+        assert!(errors.flush(start + window + Duration::from_secs(60)).is_empty());
 
-        assert_eq!(task.get::<String, _>("name"), "test_task");
-        assert_eq!(task.get::<i64, _>("interval"), 60);
+        errors.record("X.AI down", start + window + Duration::from_secs(1));
+        assert_eq!(
+            errors.flush(start + window * 2),
+            vec!["X.AI down (repeated 1 times in the last 300s)"]
+        );
+        assert_eq!(errors.record("X.AI down", start + window * 2), vec!["X.AI down"]);
+    }
 
-        // Test task deletion
-        let delete_result = sqlx::query("DELETE FROM tasks WHERE name = ?")
-            .bind("test_task")
-            .execute(&pool)
-            .await?;
+    #[test]
+    fn test_parse_as_command() {
+        let (chat_id, cmd) = parse_as_command("-1001234 /list", "wibot").unwrap();
+        assert_eq!(chat_id, -1001234);
+        assert!(matches!(cmd, Command::List));
+
+        let (_, cmd) = parse_as_command(" 42  cost ", "wibot").unwrap();
+        assert!(matches!(cmd, Command::Cost(scope) if scope.is_empty()));
+        let (_, cmd) = parse_as_command("42 /overdue@wibot", "wibot").unwrap();
+        assert!(matches!(cmd, Command::Overdue(_)));
+        let (_, cmd) = parse_as_command("42 /delete btc eth", "wibot").unwrap();
+        assert!(matches!(cmd, Command::Delete(names) if names == "btc eth"));
+
+        for invalid in ["", "42", "0 /list", "chat /list", "42 /nosuchcommand", "42 /list@otherbot"] {
+            assert!(parse_as_command(invalid, "wibot").is_err(), "{:?} should be rejected", invalid);
+        }
+    }
 
-        assert_eq!(delete_result.rows_affected(), 1);
+    #[tokio::test]
+    async fn test_run_as_chat() -> Result<()> {
+        let pool = setup_test_db().await?;
+        create_task(&pool, "as_theirs", "q", 30, 500, &TaskOptions::default()).await?;
+        create_task(&pool, "as_mine", "q", 30, 600, &TaskOptions::default()).await?;
+        set_welcome_message(&pool, 500, "Hi from 500").await?;
+        let state = test_state(pool, Config::default());
+
+        let listed = run_as_chat(&state, 500, &Command::List).await?;
+        assert!(listed.starts_with("👤 *As chat* `500`"));
+        assert!(listed.contains("as\\_theirs"));
+        assert!(!listed.contains("as\\_mine"));
+
+        let welcome = run_as_chat(&state, 500, &Command::Welcome).await?;
+        assert!(welcome.contains("Hi from 500"));
+
+        // Nothing that writes, posts or reaches other chats
+        for cmd in [
+            Command::Delete(String::from("as_theirs")),
+            Command::Ask(String::from("question")),
+            Command::Cost(String::from("all")),
+            Command::As(String::from("600 /list")),
+        ] {
+            assert!(matches!(run_as_chat(&state, 500, &cmd).await, Err(BotError::NotReadOnly)));
+        }
+        assert_eq!(fetch_task_list(&state.pool, 500).await?.len(), 1);
 
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_task_scheduling() -> Result<()> {
-        let pool = SqlitePool::connect("sqlite::memory:").await?;
+    #[test]
+    fn test_trim_history_to_token_budget() {
+        let turn = |role, tokens: usize| ChatTurn {
+            role,
+            content: "x".repeat(tokens * CHARS_PER_TOKEN),
+        };
+        let turns = vec![
+            turn(ChatRole::User, 300),
+            turn(ChatRole::Assistant, 400),
+            turn(ChatRole::User, 100),
+            turn(ChatRole::Assistant, 200),
+            turn(ChatRole::User, 50),
+        ];
+        assert_eq!(estimate_tokens("abcde"), 2);
+
+        assert_eq!(trim_history(&turns, 2000), &turns[..]);
+        assert_eq!(trim_history(&turns, 350), &turns[2..]);
+        // The oldest kept turn would be an orphaned answer
+        assert_eq!(trim_history(&turns, 300), &turns[4..]);
+        // The latest question stays even when it alone is over budget
+        assert_eq!(trim_history(&turns, 10), &turns[4..]);
+        assert!(trim_history(&[], 10).is_empty());
+
+        let system = "y".repeat(100 * CHARS_PER_TOKEN);
+        let messages = build_xai_messages(&system, &turns, 450);
+        let roles: Vec<&str> = messages.iter().map(|message| message["role"].as_str().unwrap()).collect();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+        assert_eq!(messages[3]["content"], turns[4].content);
+
+        let options =
+            resolve_xai_options(&InlineOptions::default(), &UserPrefs::default(), &ChatSettings::default(), &Config::default());
+        let body = build_xai_request_body("What's new?", &options);
+        assert_eq!(body["messages"][1], json!({ "role": "user", "content": "What's new?" }));
+    }
 
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS tasks (
-                name TEXT PRIMARY KEY,
-                description TEXT NOT NULL,
-                interval INTEGER NOT NULL,
-                last_run TEXT NOT NULL,
-                chat_id INTEGER NOT NULL
-            )
-            "#,
-        )
-        .execute(&pool)
-        .await?;
+    #[test]
+    fn test_parse_reschedule_delay() {
+        assert_eq!(parse_reschedule_delay("now").unwrap(), chrono::Duration::zero());
+        assert_eq!(parse_reschedule_delay("+2h").unwrap(), chrono::Duration::hours(2));
+        assert_eq!(parse_reschedule_delay("45m").unwrap(), chrono::Duration::minutes(45));
+        assert_eq!(parse_reschedule_delay("+1D").unwrap(), chrono::Duration::days(1));
+        assert_eq!(parse_reschedule_delay("+30d").unwrap(), chrono::Duration::days(30));
+
+        for invalid in [
+            "", "+", "+h", "+-2h", "-2h", "+2", "+2w", "+31d", "+1.5h", "+9999999999999999d", "tomorrow", "2é", "é", "+2hé", "+２h",
+        ] {
+            assert!(parse_reschedule_delay(invalid).is_err(), "{:?} should be rejected", invalid);
+        }
+    }
 
-        let now = Utc::now();
+    #[tokio::test]
+    async fn test_rescheduled_last_run() -> Result<()> {
+        let now = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z")?.with_timezone(&Utc);
 
-        // Create a task that should run
-        sqlx::query(
-            "INSERT INTO tasks (name, description, interval, last_run, chat_id) VALUES (?, ?, ?, ?, ?)"
-        )
-        .bind("schedule_test")
-        .bind("test description")
-        .bind(1) // 1 minute interval
-        .bind(now.checked_sub_signed(chrono::Duration::minutes(2)).unwrap().to_rfc3339())
-        .bind(123456789)
-        .execute(&pool)
-        .await?;
+        // A daily task deferred by two hours ran "22 hours ago"
+        let (last_run, clamped) = rescheduled_last_run(now, 1440, chrono::Duration::hours(2));
+        assert_eq!(last_run, now - chrono::Duration::hours(22));
+        assert_eq!(task_next_due(last_run, 1440), now + chrono::Duration::hours(2));
+        assert!(!clamped);
 
-        // Check if task should run
-        let task = sqlx::query("SELECT * FROM tasks WHERE name = ?")
-            .bind("schedule_test")
-            .fetch_one(&pool)
-            .await?;
+        let (last_run, clamped) = rescheduled_last_run(now, 1440, chrono::Duration::zero());
+        assert!(is_task_due(last_run, 1440, now));
+        assert!(!clamped);
 
-        let last_run: DateTime<Utc> = task.get::<String, _>("last_run").parse()?;
-        let interval: i64 = task.get("interval");
-        let duration_since_last = now.signed_duration_since(last_run);
+        // Deferring past one interval would need a last_run in the future
+        let (last_run, clamped) = rescheduled_last_run(now, 30, chrono::Duration::hours(2));
+        assert_eq!(last_run, now);
+        assert!(clamped);
 
-        assert!(duration_since_last.num_minutes() >= interval);
+        let formatted = format_reschedule("daily_news", now + chrono::Duration::hours(2), now, false);
+        assert!(formatted.contains("2024\\-03\\-01 14\\:00 UTC \\(in 2h\\)"));
+        assert!(has_balanced_markdown(&format_reschedule("a_b", now, now, true)));
+
+        let pool = setup_test_db().await?;
+        create_task(&pool, "resched", "q", 60, 1, &TaskOptions::default()).await?;
+        assert_eq!(get_task_interval(&pool, "resched", 1).await?, Some(60));
+        assert_eq!(get_task_interval(&pool, "resched", 2).await?, None);
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_parse_create_command() {
-        let valid_input = "test_task 30 What is the weather?".to_string();
-        let result = parse_create_command(valid_input).await;
-        assert!(result.is_some());
-
-        if let Some((name, interval, question)) = result {
-            assert_eq!(name, "test_task");
-            assert_eq!(interval, 30);
-            assert_eq!(question, "What is the weather?");
-        }
+    async fn test_unwritable_database_path() -> Result<()> {
+        let dir = env::temp_dir().join(format!("wibot-db-test-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+
+        let db_path = dir.join("nested").join("tasks.db");
+        let pool = initialize_database(&db_path, true).await?;
+        create_task(&pool, "on_disk", "q", 30, 1, &TaskOptions::default()).await?;
+        pool.close().await;
+        assert!(db_path.exists());
+
+        // A regular file where the data directory should be can't be written
+        // into, even by root, just like a read-only filesystem
+        let blocker = dir.join("blocker");
+        fs::write(&blocker, "not a directory")?;
+        let unwritable = blocker.join("data").join("tasks.db");
+
+        let error = prepare_database_file(&unwritable, false).unwrap_err();
+        assert!(matches!(error, DatabasePathError::DefaultNotWritable { .. }));
+        assert!(error.to_string().contains("set DATABASE_PATH"));
+        assert!(error.to_string().contains(&unwritable.display().to_string()));
+
+        let error = initialize_database(&unwritable, true).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<DatabasePathError>(),
+            Some(DatabasePathError::ConfiguredNotWritable { .. })
+        ));
 
-        let invalid_input = "invalid command".to_string();
-        let result = parse_create_command(invalid_input).await;
-        assert!(result.is_none());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
     }
 
     #[tokio::test]
-    async fn test_format_task_list() -> Result<()> {
-        let pool = SqlitePool::connect("sqlite::memory:").await?;
-
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS tasks (
-                name TEXT PRIMARY KEY,
-                description TEXT NOT NULL,
-                interval INTEGER NOT NULL,
-                last_run TEXT NOT NULL,
-                chat_id INTEGER NOT NULL
-            )
-            "#,
-        )
-        .execute(&pool)
-        .await?;
+    async fn test_stats_read_from_replica() -> Result<()> {
+        let primary = setup_test_db().await?;
+        let replica = setup_test_db().await?;
+        insert_log_at(&replica, 1, Utc::now()).await?;
+        insert_log_at(&replica, 2, Utc::now()).await?;
+        let state = test_state(primary, Config::default()).with_read_pool(replica);
+
+        log_interaction(&state, 1, Some(42), None, "Ask", Some("q"), Some("a"), None, None, Duration::from_millis(5)).await?;
+
+        // Writes land on the primary only
+        let primary_logs: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM bot_logs").fetch_one(&state.pool).await?;
+        let replica_logs: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM bot_logs").fetch_one(&state.read_pool).await?;
+        assert_eq!((primary_logs, replica_logs), (1, 2));
+
+        // The statistics and history queries the handlers run read the replica
+        let stats = get_command_stats(&state, None).await?;
+        assert_eq!(stats["commands"][0]["command"], "List");
+        assert_eq!(stats["commands"][0]["usage_count"], 2);
+        assert_eq!(get_user_stats(&state, 42).await?["total_commands"], 2);
+        assert!(fetch_transcript(&state, 1, None).await?.is_empty());
+        assert!(search_logs(&state, &parse_search_command("q")?).await?.is_empty());
+
+        // Without a replica both paths share the primary pool
+        let shared = test_state(setup_test_db().await?, Config::default());
+        log_interaction(&shared, 1, Some(42), None, "Ask", Some("q"), None, None, None, Duration::from_millis(5)).await?;
+        assert_eq!(get_command_stats(&shared, None).await?["commands"][0]["usage_count"], 1);
 
-        let timestamp = "2024-02-20T12:00:00Z";
-        
-        sqlx::query(
-            "INSERT INTO tasks (name, description, interval, last_run, chat_id) VALUES (?, ?, ?, ?, ?)"
-        )
-        .bind("test_task")
-        .bind("What is the weather?")
-        .bind(30)
-        .bind(timestamp)
-        .bind(123456789)
-        .execute(&pool)
-        .await?;
+        Ok(())
+    }
 
-        let tasks = sqlx::query("SELECT name, description as question, interval, last_run FROM tasks")
-            .fetch_all(&pool)
-            .await?;
+    #[tokio::test]
+    async fn test_read_replica_refuses_writes() -> Result<()> {
+        let dir = env::temp_dir().join(format!("wibot-replica-test-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let db_path = dir.join("replica.db");
+        initialize_database(&db_path, true).await?.close().await;
 
-        let formatted = format_task_list(&tasks);
+        let replica = connect_read_only(&format!("sqlite:{}", db_path.display())).await?;
+        let logs: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM bot_logs").fetch_one(&replica).await?;
+        assert_eq!(logs, 0);
+        assert!(insert_log_at(&replica, 1, Utc::now()).await.is_err());
+        replica.close().await;
 
-        assert!(formatted.contains("test\\_task"));
-        assert!(formatted.contains("30 minutes"));
-        assert!(formatted.contains("What is the weather\\?"));
-        assert!(formatted.contains(&escape_markdown_v2(timestamp)));
+        assert!(connect_read_only("postgres://localhost/wibot").await.is_err());
 
+        fs::remove_dir_all(&dir)?;
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_empty_task_list() -> Result<()> {
-        let pool = SqlitePool::connect("sqlite::memory:").await?;
+    #[test]
+    fn test_send_retry_delay() {
+        use teloxide::types::Seconds;
 
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS tasks (
-                name TEXT PRIMARY KEY,
-                description TEXT NOT NULL,
-                interval INTEGER NOT NULL,
-                last_run TEXT NOT NULL,
-                chat_id INTEGER NOT NULL
-            )
-            "#,
-        )
-        .execute(&pool)
-        .await?;
+        let flood = RequestError::RetryAfter(Seconds::from_seconds(5));
+        assert_eq!(send_retry_delay(&flood, 0), Some(Duration::from_secs(5)));
+        assert_eq!(send_retry_delay(&flood, MAX_SEND_RETRIES - 1), Some(Duration::from_secs(5)));
+        assert_eq!(send_retry_delay(&flood, MAX_SEND_RETRIES), None);
 
-        let tasks =
-            sqlx::query("SELECT name, description as question, interval, last_run FROM tasks")
-                .fetch_all(&pool)
-                .await?;
+        let too_long = RequestError::RetryAfter(Seconds::from_seconds(3600));
+        assert_eq!(send_retry_delay(&too_long, 0), None);
 
-        let formatted = format_task_list(&tasks);
-        assert!(formatted.contains("No tasks found"));
+        let other = RequestError::Api(ApiError::BotBlocked);
+        assert_eq!(send_retry_delay(&other, 0), None);
+    }
 
-        Ok(())
+    #[test]
+    fn test_version_info() {
+        let version = version_info();
+        assert!(!version.is_empty());
+        assert!(version.contains(env!("CARGO_PKG_VERSION")));
+        assert!(version.contains("commit "));
+        assert!(format_version_message(&version).contains(env!("CARGO_PKG_VERSION")));
     }
 
     #[test]
-    fn test_markdown_escaping() {
-        let special_chars = "._*[]()~`>#+-=|{}.!";
-        let escaped = escape_markdown_v2(special_chars);
-        assert_eq!(escaped, r"\.\_\*\[\]\(\)\~\`\>\#\+\-\=\|\{\}\.\!");
+    fn test_owner_only_commands() {
+        assert!(ensure_owner(Some(1), 1).is_ok());
+        assert!(matches!(ensure_owner(Some(2), 1), Err(BotError::PermissionDenied)));
+        assert!(matches!(ensure_owner(None, 1), Err(BotError::PermissionDenied)));
+
+        assert_eq!(parse_page("").unwrap(), 1);
+        assert_eq!(parse_page(" 3 ").unwrap(), 3);
+        assert!(parse_page("0").is_err());
+        assert!(parse_page("two").is_err());
+    }
 
-        // Test individual characters
-        assert_eq!(escape_markdown_v2("."), r"\.");
-        assert_eq!(escape_markdown_v2("*"), r"\*");
-        assert_eq!(escape_markdown_v2("_"), r"\_");
-        assert_eq!(escape_markdown_v2("["), r"\[");
-        assert_eq!(escape_markdown_v2("]"), r"\]");
-        assert_eq!(escape_markdown_v2("("), r"\(");
-        assert_eq!(escape_markdown_v2(")"), r"\)");
-        assert_eq!(escape_markdown_v2("~"), r"\~");
-        assert_eq!(escape_markdown_v2("`"), r"\`");
-        assert_eq!(escape_markdown_v2(">"), r"\>");
-        assert_eq!(escape_markdown_v2("#"), r"\#");
-        assert_eq!(escape_markdown_v2("+"), r"\+");
-        assert_eq!(escape_markdown_v2("-"), r"\-");
-        assert_eq!(escape_markdown_v2("="), r"\=");
-        assert_eq!(escape_markdown_v2("|"), r"\|");
-        assert_eq!(escape_markdown_v2("{"), r"\{");
-        assert_eq!(escape_markdown_v2("}"), r"\}");
-        assert_eq!(escape_markdown_v2("!"), r"\!");
+    #[test]
+    fn test_markdown_headers() {
+        assert_eq!(format_response_content("## Overview"), "*Overview*");
+        assert_eq!(
+            format_response_content("# Prices\nBTC is up\n\n### Outlook ###\n- Bullish"),
+            "*Prices*\nBTC is up\n\n*Outlook*\n• Bullish"
+        );
+        assert_eq!(format_response_content("Ranked #1 today"), "Ranked \\#1 today");
+        assert_eq!(format_response_content("#hashtag"), "\\#hashtag");
+    }
+
+    #[test]
+    fn test_runs_per_month() {
+        assert_eq!(runs_per_month(60), 720.0);
+        assert_eq!(runs_per_month(1), 43200.0);
+        assert_eq!(runs_per_month(1440), 30.0);
+        assert_eq!(runs_per_month(0), 0.0);
     }
 
     #[tokio::test]
-    async fn test_create_command_validation() {
-        // Valid command
-        let valid = parse_create_command("weather 60 What's the weather like?".to_string()).await;
-        assert!(valid.is_some());
-        if let Some((name, interval, question)) = valid {
-            assert_eq!(name, "weather");
-            assert_eq!(interval, 60);
-            assert_eq!(question, "What's the weather like?");
+    async fn test_monthly_cost_estimate() -> Result<()> {
+        let pool = setup_test_db().await?;
+        let config = Config {
+            xai_price_per_1k_tokens: 0.01,
+            xai_tokens_per_run: 1000,
+            ..Config::default()
+        };
+
+        create_task(&pool, "hourly", "q", 60, 1, &TaskOptions::default()).await?;
+        create_task(&pool, "daily", "q", 1440, 1, &TaskOptions::default()).await?;
+        create_task(&pool, "elsewhere", "q", 60, 2, &TaskOptions::default()).await?;
+        for tokens in [400, 600] {
+            let answer = XaiAnswer {
+                content: String::from("answer"),
+                total_tokens: Some(tokens),
+                model: String::from("grok-beta"),
+            };
+            record_task_run(&pool, "hourly", 1, &answer).await?;
         }
 
-        // Invalid commands
-        let invalid_cases = vec![
-            "weather".to_string(),
-            "weather 60".to_string(),
-            "weather invalid 60".to_string(),
-            "".to_string(),
-        ];
+        let costs = estimate_monthly_costs(&pool, Some(1), &config).await?;
+        assert_eq!(costs.len(), 2);
 
-        for case in invalid_cases {
-            assert!(parse_create_command(case).await.is_none());
-        }
-    }
+        let daily = &costs[0];
+        assert_eq!(daily.name, "daily");
+        assert!(!daily.measured);
+        // 30 runs * 1000 tokens * $0.01 / 1k tokens
+        assert!((daily.monthly_cost - 0.3).abs() < 1e-9);
 
-    #[test]
-    fn test_xai_response_formatting() {
-        let response = format_xai_response(
-            Some("crypto_check"),
-            "What's the BTC price?",
-            "Bitcoin is at $50,000"
-        );
+        let hourly = &costs[1];
+        assert!(hourly.measured);
+        assert_eq!(hourly.tokens_per_run, 500.0);
+        // 720 runs * 500 tokens * $0.01 / 1k tokens
+        assert!((hourly.monthly_cost - 3.6).abs() < 1e-9);
 
-        assert!(response.contains("crypto\\_check"));
-        assert!(response.contains("What\\'s the BTC price\\?"));
-        assert!(response.contains("Bitcoin is at \\$50\\,000"));
+        let formatted = format_cost_estimate(&costs, false);
+        assert!(formatted.contains("*Total:* $3\\.90/month"));
 
-        let without_task = format_xai_response(
-            None, 
-            "What's the BTC price?",
-            "Bitcoin is at $50,000"
-        );
+        let global = estimate_monthly_costs(&pool, None, &config).await?;
+        assert_eq!(global.len(), 3);
+        assert!(format_cost_estimate(&global, true).contains("chat `2`"));
 
-        assert!(!without_task.contains("Task:"));
-        assert!(without_task.contains("Question:"));
-        assert!(without_task.contains("Answer:"));
+        Ok(())
     }
 
     #[test]
-    fn test_special_character_escaping() {
-        let text = "What's this? It's a test!";
-        let escaped = escape_markdown_v2(text);
-        assert_eq!(escaped, r"What\'s this\? It\'s a test\!");
+    fn test_parse_purge_command() {
+        assert_eq!(parse_purge_command("30"), Some((30, false)));
+        assert_eq!(parse_purge_command("30 confirm"), Some((30, true)));
+        assert_eq!(parse_purge_command(""), None);
+        assert_eq!(parse_purge_command("0"), None);
+        assert_eq!(parse_purge_command("-5"), None);
+        assert_eq!(parse_purge_command("36500"), Some((MAX_PURGE_DAYS, false)));
+        assert_eq!(parse_purge_command("36501"), None);
+        assert_eq!(parse_purge_command("999999999"), None);
+        assert_eq!(parse_purge_command("30 yes"), None);
+        assert_eq!(parse_purge_command("30 confirm now"), None);
     }
 }